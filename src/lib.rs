@@ -0,0 +1,31 @@
+mod vm;
+
+pub use vm::{
+    bus::{parse_number, Bus, MapError},
+    clint::TIMEBASE_FREQUENCY,
+    device::{Device, DeviceNode, Exit},
+    finisher::{TestFinisher, FINISHER_BASE, FINISHER_SIZE},
+    framebuffer::{PixelFormat, FRAMEBUFFER_BASE},
+    misaligned::MisalignedPolicy,
+    mmu::AdPolicy,
+    registers::BaseIsa,
+    rtc::{GoldfishRtc, RTC_BASE, RTC_SIZE, RTC_SOURCE},
+    uart::{
+        BufferSerial, FileSerial, Serial, StdioSerial, Uart, UART_BASE, UART_SIZE, UART_SOURCE,
+    },
+    virtio::{
+        block::{DiskMode, VirtioBlock},
+        console::VirtioConsole,
+        net::{LinkEndpoint, NetBackend, PcapBackend, VirtioNet, DEFAULT_MAC},
+        queue::{BadChain, Chain, GuestMemory, Virtqueue, QUEUE_SIZE},
+        rng::VirtioRng,
+        VirtioDevice, VIRTIO_BASE, VIRTIO_F_VERSION_1, VIRTIO_SIZE, VIRTIO_SLOTS,
+    },
+    Vm,
+};
+
+#[cfg(target_os = "linux")]
+pub use vm::uart::PtySerial;
+
+#[cfg(unix)]
+pub use vm::virtio::p9::{ShareMode, Virtio9p};
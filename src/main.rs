@@ -1,5 +1,379 @@
-mod vm;
+use std::{env, fs, process};
+
+use riscv_vm::{
+    parse_number, BaseIsa, Bus, DiskMode, Exit, FileSerial, GoldfishRtc, MisalignedPolicy,
+    PcapBackend, PixelFormat, Serial, StdioSerial, TestFinisher, Uart, VirtioBlock, VirtioConsole,
+    VirtioNet, VirtioRng, Vm, DEFAULT_MAC, FINISHER_BASE, FINISHER_SIZE, FRAMEBUFFER_BASE,
+    RTC_BASE, RTC_SIZE, RTC_SOURCE, UART_BASE, UART_SIZE, UART_SOURCE,
+};
+#[cfg(unix)]
+use riscv_vm::{ShareMode, Virtio9p};
+
+const USAGE: &str = "usage: riscv_vm [--memory-map FILE] [--ram SIZE] [--steps N]
+                [--isa rv32i|rv32e] [--serial stdio|pty|none|file:INPUT,OUTPUT]
+                [--disk IMAGE[,ro|,rw|,overlay]]... [--console BACKEND]...
+                [--rng host|seed:N] [--net pcap:[REPLAY][,CAPTURE]] [--mac MAC]
+                [--share TAG=DIR[,ro|,rw]]... [--rtc host|virtual[:SECONDS]]
+                [--irq-sources N] [--misaligned trap|emulate]
+                [--framebuffer WIDTHxHEIGHT[,FORMAT]] [--dump-frames DIR[,EVERY]]
+                [--save-frame FILE] [--bootargs ARGS] [--dump-dtb FILE] PROGRAM";
+
+// default RAM of the QEMU virt map
+const RAM_SIZE: u64 = 128 << 20;
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+// Backend of a serial port named on the command line, None for none.
+// `port` labels the pty path.
+fn serial(name: &str, port: &str) -> Result<Option<Box<dyn Serial>>, String> {
+    let serial: Box<dyn Serial> = match name {
+        "stdio" => Box::new(StdioSerial::new()),
+        "none" => return Ok(None),
+        #[cfg(target_os = "linux")]
+        "pty" => {
+            let pty = riscv_vm::PtySerial::new().map_err(|err| format!("pty: {}", err))?;
+            eprintln!("{}: {}", port, pty.path());
+            Box::new(pty)
+        }
+        _ => {
+            let files = name.strip_prefix("file:").ok_or(USAGE)?;
+            let (input, output) = files.split_once(',').ok_or(USAGE)?;
+            let serial =
+                FileSerial::new(input, output).map_err(|err| format!("{}: {}", files, err))?;
+            Box::new(serial)
+        }
+    };
+    Ok(Some(serial))
+}
+
+// Image path and mode of a --disk, read-write unless told otherwise
+fn disk_mode(disk: &str) -> (&str, DiskMode) {
+    match disk.rsplit_once(',') {
+        Some((path, "ro")) => (path, DiskMode::ReadOnly),
+        Some((path, "rw")) => (path, DiskMode::ReadWrite),
+        Some((path, "overlay")) => (path, DiskMode::Overlay),
+        _ => (disk, DiskMode::ReadWrite),
+    }
+}
+
+// 640x480 or 640x480,r5g6b5, x8r8g8b8 unless told otherwise
+fn framebuffer(geometry: &str) -> Option<(u32, u32, PixelFormat)> {
+    let (size, format) = geometry.split_once(',').unwrap_or((geometry, "x8r8g8b8"));
+    let (width, height) = size.split_once('x')?;
+    let format = match format {
+        "r5g6b5" => PixelFormat::R5G6B5,
+        "x8r8g8b8" => PixelFormat::X8R8G8B8,
+        "a8r8g8b8" => PixelFormat::A8R8G8B8,
+        "a8b8g8r8" => PixelFormat::A8B8G8R8,
+        _ => return None,
+    };
+    Some((width.parse().ok()?, height.parse().ok()?, format))
+}
+
+// 52:54:00:12:34:56
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let bytes: Vec<u8> = mac
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<_>>()?;
+    bytes.try_into().ok()
+}
+
+struct Options {
+    isa: BaseIsa,
+    memory_map: Option<String>,
+    serial_name: String,
+    ram_size: u64,
+    steps: u64,
+    disks: Vec<String>,
+    consoles: Vec<String>,
+    rng: Option<String>,
+    net: Option<String>,
+    shares: Vec<String>,
+    rtc: GoldfishRtc,
+    bootargs: Option<String>,
+    dump_dtb: Option<String>,
+    sources: usize,
+    misaligned: MisalignedPolicy,
+    geometry: Option<(u32, u32, PixelFormat)>,
+    dump_frames: Option<String>,
+    save_frame: Option<String>,
+    mac: [u8; 6],
+    program: String,
+}
+
+// The command line, checked for syntax only
+fn options() -> Options {
+    let mut isa = BaseIsa::Rv32i;
+    let mut memory_map = None;
+    let mut serial_name = "stdio".to_string();
+    let mut ram_size = RAM_SIZE;
+    let mut steps = u64::MAX;
+    let mut disks = vec![];
+    let mut consoles = vec![];
+    let mut rng = None;
+    let mut net = None;
+    let mut shares = vec![];
+    let mut rtc = GoldfishRtc::host();
+    let mut bootargs = None;
+    let mut dump_dtb = None;
+    let mut sources = 32;
+    let mut misaligned = MisalignedPolicy::Trap;
+    let mut geometry = None;
+    let mut dump_frames = None;
+    let mut save_frame = None;
+    let mut mac = DEFAULT_MAC;
+    let mut program = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--isa" => {
+                isa = match args.next().as_deref() {
+                    Some("rv32i") => BaseIsa::Rv32i,
+                    Some("rv32e") => BaseIsa::Rv32e,
+                    _ => fail(USAGE),
+                }
+            }
+            "--memory-map" => memory_map = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--ram" => {
+                ram_size = args
+                    .next()
+                    .and_then(|size| parse_number(&size))
+                    .unwrap_or_else(|| fail(USAGE))
+            }
+            "--serial" => serial_name = args.next().unwrap_or_else(|| fail(USAGE)),
+            "--disk" => disks.push(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--console" => consoles.push(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--rng" => rng = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--net" => net = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--mac" => {
+                mac = args
+                    .next()
+                    .and_then(|mac| parse_mac(&mac))
+                    .unwrap_or_else(|| fail(USAGE))
+            }
+            "--share" => shares.push(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--rtc" => {
+                let clock = args.next().unwrap_or_else(|| fail(USAGE));
+                rtc = match clock.split_once(':') {
+                    _ if clock == "host" => GoldfishRtc::host(),
+                    _ if clock == "virtual" => GoldfishRtc::virtual_clock(0),
+                    Some(("virtual", seconds)) => seconds
+                        .parse::<u64>()
+                        .ok()
+                        .and_then(|seconds| seconds.checked_mul(1_000_000_000))
+                        .map(GoldfishRtc::virtual_clock)
+                        .unwrap_or_else(|| fail(USAGE)),
+                    _ => fail(USAGE),
+                };
+            }
+            "--irq-sources" => {
+                sources = args
+                    .next()
+                    .and_then(|sources| sources.parse().ok())
+                    .unwrap_or_else(|| fail(USAGE))
+            }
+            "--misaligned" => {
+                misaligned = match args.next().as_deref() {
+                    Some("trap") => MisalignedPolicy::Trap,
+                    Some("emulate") => MisalignedPolicy::Emulate,
+                    _ => fail(USAGE),
+                }
+            }
+            "--framebuffer" => {
+                geometry = Some(
+                    args.next()
+                        .and_then(|geometry| framebuffer(&geometry))
+                        .unwrap_or_else(|| fail(USAGE)),
+                )
+            }
+            "--dump-frames" => dump_frames = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--save-frame" => save_frame = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--bootargs" => bootargs = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--dump-dtb" => dump_dtb = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--steps" => {
+                steps = args
+                    .next()
+                    .and_then(|steps| steps.parse().ok())
+                    .unwrap_or_else(|| fail(USAGE))
+            }
+            _ if program.is_none() && !arg.starts_with('-') => program = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+    // stdin can only feed one port
+    let stdio = consoles.iter().chain([&serial_name]);
+    if stdio.filter(|name| *name == "stdio").count() > 1 {
+        fail("only one serial port can use stdio");
+    }
+    Options {
+        isa,
+        memory_map,
+        serial_name,
+        ram_size,
+        steps,
+        disks,
+        consoles,
+        rng,
+        net,
+        shares,
+        rtc,
+        bootargs,
+        dump_dtb,
+        sources,
+        misaligned,
+        geometry,
+        dump_frames,
+        save_frame,
+        mac,
+        program: program.unwrap_or_else(|| fail(USAGE)),
+    }
+}
+
+// Builds the machine the options describe and runs it
+fn run(options: Options) -> Result<Option<Exit>, String> {
+    let Options {
+        isa,
+        memory_map,
+        serial_name,
+        ram_size,
+        steps,
+        disks,
+        consoles,
+        rng,
+        net,
+        shares,
+        rtc,
+        bootargs,
+        dump_dtb,
+        sources,
+        misaligned,
+        geometry,
+        dump_frames,
+        save_frame,
+        mac,
+        program,
+    } = options;
+    let bus = match memory_map {
+        Some(path) => Bus::load(&path).map_err(|err| format!("{}: {}", path, err))?,
+        None => Bus::virt(ram_size).map_err(|err| err.to_string())?,
+    };
+    let mut vm = Vm::with_sources(isa, bus, sources).map_err(|err| err.to_string())?;
+    vm.set_misaligned_policy(misaligned);
+    vm.load_program_from_file(&program)
+        .map_err(|err| format!("{}: {}", program, err))?;
+    let finisher = Box::new(TestFinisher::new());
+    vm.attach("test", FINISHER_BASE, FINISHER_SIZE, finisher, None)
+        .and_then(|_| vm.attach("rtc", RTC_BASE, RTC_SIZE, Box::new(rtc), Some(RTC_SOURCE)))
+        .map_err(|err| err.to_string())?;
+    if let Some(serial) = serial(&serial_name, "uart")? {
+        // HTIF programs print through tohost, the serial port goes there
+        // instead of to a UART
+        if vm.has_htif() {
+            vm.set_htif_console(serial);
+        } else {
+            let uart = Box::new(Uart::new(serial));
+            vm.attach("uart", UART_BASE, UART_SIZE, uart, Some(UART_SOURCE))
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    for (index, disk) in disks.iter().enumerate() {
+        let (path, mode) = disk_mode(disk);
+        let block = VirtioBlock::open(path, mode).map_err(|err| format!("{}: {}", path, err))?;
+        vm.add_virtio(&format!("disk{}", index), Box::new(block))
+            .map_err(|err| err.to_string())?;
+    }
+    let mut ports = vec![];
+    for (index, name) in consoles.iter().enumerate() {
+        if let Some(serial) = serial(name, &format!("port{}", index))? {
+            ports.push((index, serial));
+        }
+    }
+    let mut ports = ports.into_iter();
+    if let Some((_, console)) = ports.next() {
+        let mut device = VirtioConsole::new(console);
+        for (index, serial) in ports {
+            device.add_port(&format!("port{}", index), serial);
+        }
+        vm.add_virtio("console", Box::new(device))
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some(source) = rng {
+        let device = match source.strip_prefix("seed:") {
+            Some(seed) => VirtioRng::seeded(parse_number(seed).ok_or(USAGE)?),
+            None if source == "host" => VirtioRng::host().map_err(|err| format!("rng: {}", err))?,
+            None => return Err(USAGE.to_string()),
+        };
+        vm.add_virtio("rng", Box::new(device))
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some(net) = net {
+        let files = net.strip_prefix("pcap:").ok_or(USAGE)?;
+        let (replay, capture) = files.split_once(',').unwrap_or((files, ""));
+        let (replay, capture) = (Some(replay), Some(capture));
+        let empty = |path: &&str| !path.is_empty();
+        let backend = PcapBackend::new(replay.filter(empty), capture.filter(empty))
+            .map_err(|err| format!("{}: {}", files, err))?;
+        vm.add_virtio("net", Box::new(VirtioNet::new(mac, Box::new(backend))))
+            .map_err(|err| err.to_string())?;
+    }
+    #[cfg(unix)]
+    for share in &shares {
+        // read only unless asked
+        let (tag, directory) = share.split_once('=').ok_or(USAGE)?;
+        let (directory, mode) = match directory.rsplit_once(',') {
+            Some((directory, "rw")) => (directory, ShareMode::ReadWrite),
+            Some((directory, "ro")) => (directory, ShareMode::ReadOnly),
+            _ => (directory, ShareMode::ReadOnly),
+        };
+        let device =
+            Virtio9p::new(directory, tag, mode).map_err(|err| format!("{}: {}", directory, err))?;
+        vm.add_virtio(tag, Box::new(device))
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some((width, height, format)) = geometry {
+        vm.attach_framebuffer(FRAMEBUFFER_BASE, width, height, format)
+            .map_err(|err| err.to_string())?;
+    }
+    if let Some(frames) = &dump_frames {
+        // every frame unless told otherwise
+        let (directory, every) = match frames.rsplit_once(',') {
+            Some((directory, every)) => (directory, every.parse().map_err(|_| USAGE)?),
+            None => (frames.as_str(), 1),
+        };
+        fs::create_dir_all(directory).map_err(|err| format!("{}: {}", directory, err))?;
+        vm.dump_frames(directory, every);
+    }
+    eprint!("{}", vm.bus());
+
+    vm.load_device_tree(bootargs.as_deref())
+        .map_err(|err| format!("device tree: {}", err))?;
+    if let Some(path) = dump_dtb {
+        let blob = vm.device_tree(bootargs.as_deref());
+        fs::write(&path, blob).map_err(|err| format!("{}: {}", path, err))?;
+    }
+    let exit = vm.run(steps);
+    if let Some(dpc) = vm.halted() {
+        eprintln!("halted in debug mode at {:#x}", dpc);
+    }
+    if let Some(path) = save_frame {
+        vm.save_frame(&path)
+            .map_err(|err| format!("{}: {}", path, err))?;
+    }
+    Ok(exit)
+}
 
 fn main() {
-    println!("Hello, world!");
+    // run() has dropped the VM by the time it returns, so the serial
+    // backends have put the terminal back before the process exits
+    match run(options()) {
+        // the exit status the finisher or HTIF was given, like QEMU and Spike
+        Ok(Some(Exit::Fail(code))) => process::exit(code as i32),
+        Ok(Some(Exit::Pass | Exit::Reset) | None) => {}
+        Err(message) => fail(&message),
+    }
 }
@@ -0,0 +1,507 @@
+// Advanced platform-level interrupt controller. A single root interrupt
+// domain for hart 0 at the address QEMU virt gives its S-level domain. In
+// direct mode the domain drives mip.MEIP or mip.SEIP through its interrupt
+// delivery control (IDC), in MSI mode pending sources are forwarded to the
+// hart's IMSIC interrupt file of the domain's privilege level.
+use super::csr::Privilege;
+
+pub(crate) const APLIC_BASE: u64 = 0x0D00_0000;
+pub(crate) const APLIC_SIZE: u64 = 0x8000;
+
+// register layout
+const DOMAINCFG: u64 = 0x0;
+const SOURCECFG: u64 = 0x4;
+const MMSIADDRCFG: u64 = 0x1BC0;
+const SMSIADDRCFGH: u64 = 0x1BCC;
+const SETIP: u64 = 0x1C00;
+const SETIPNUM: u64 = 0x1CDC;
+const IN_CLRIP: u64 = 0x1D00;
+const CLRIPNUM: u64 = 0x1DDC;
+const SETIE: u64 = 0x1E00;
+const SETIENUM: u64 = 0x1EDC;
+const CLRIE: u64 = 0x1F00;
+const CLRIENUM: u64 = 0x1FDC;
+const SETIPNUM_LE: u64 = 0x2000;
+const SETIPNUM_BE: u64 = 0x2004;
+const GENMSI: u64 = 0x3000;
+const TARGET: u64 = 0x3004;
+const IDC: u64 = 0x4000;
+
+// registers of the IDC, only hart 0's exists
+const IDELIVERY: u64 = 0x00;
+const IFORCE: u64 = 0x04;
+const ITHRESHOLD: u64 = 0x08;
+const TOPI: u64 = 0x18;
+const CLAIMI: u64 = 0x1C;
+const IDC_SIZE: u64 = 0x20;
+
+// domaincfg: bits 31:24 read as 0x80, interrupt enable and delivery mode
+const DOMAINCFG_FIXED: u32 = 0x80 << 24;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+// target: hart index in both modes, then either a priority or an MSI identity
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_IPRIO: u32 = 0xFF;
+const TARGET_EIID: u32 = 0x7FF;
+
+pub(crate) const MAX_SOURCES: usize = 1023;
+
+// sourcecfg.SM
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum SourceMode {
+    Inactive = 0,
+    Detached = 1,
+    Edge1 = 4,
+    Edge0 = 5,
+    Level1 = 6,
+    Level0 = 7,
+}
+
+impl SourceMode {
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(SourceMode::Inactive),
+            1 => Some(SourceMode::Detached),
+            4 => Some(SourceMode::Edge1),
+            5 => Some(SourceMode::Edge0),
+            6 => Some(SourceMode::Level1),
+            7 => Some(SourceMode::Level0),
+            _ => None,
+        }
+    }
+
+    fn is_level(self) -> bool {
+        matches!(self, SourceMode::Level1 | SourceMode::Level0)
+    }
+
+    // active low modes invert the wire
+    fn rectify(self, input: bool) -> bool {
+        match self {
+            SourceMode::Inactive | SourceMode::Detached => false,
+            SourceMode::Edge0 | SourceMode::Level0 => !input,
+            SourceMode::Edge1 | SourceMode::Level1 => input,
+        }
+    }
+}
+
+pub(crate) struct Aplic {
+    pub(crate) level: Privilege, // M or S, selects mip bit and interrupt file
+    sources: usize,              // source ids are 1..=sources
+    domaincfg: u32,
+    mode: Vec<SourceMode>,
+    input: Vec<bool>, // device line as wired, before rectification
+    pending: Vec<bool>,
+    enabled: Vec<bool>,
+    target: Vec<u32>,
+    msiaddrcfg: [u32; 4], // kept for software, MSIs go straight to the hart
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+    msis: Vec<u32>, // identities forwarded to hart 0, not yet delivered
+}
+
+impl Aplic {
+    pub(crate) fn new(sources: usize, level: Privilege) -> Self {
+        assert!(
+            (1..=MAX_SOURCES).contains(&sources),
+            "the APLIC supports 1 to {} sources",
+            MAX_SOURCES
+        );
+        let slots = sources + 1;
+        Self {
+            level,
+            sources,
+            domaincfg: 0,
+            mode: vec![SourceMode::Inactive; slots],
+            input: vec![false; slots],
+            pending: vec![false; slots],
+            enabled: vec![false; slots],
+            target: vec![0; slots],
+            msiaddrcfg: [0; 4],
+            idelivery: 0,
+            iforce: 0,
+            ithreshold: 0,
+            msis: vec![],
+        }
+    }
+
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    fn valid(&self, source: usize) -> bool {
+        source != 0 && source <= self.sources
+    }
+
+    fn active(&self, source: usize) -> bool {
+        self.valid(source) && self.mode[source] != SourceMode::Inactive
+    }
+
+    fn rectified(&self, source: usize) -> bool {
+        self.mode[source].rectify(self.input[source])
+    }
+
+    // Device interrupt line. Edge sources latch on the rectified rising
+    // edge, level sources are pending while the rectified input is high
+    // (in MSI mode only once per assertion).
+    pub(crate) fn set_input(&mut self, source: usize, level: bool) {
+        if !self.valid(source) {
+            return;
+        }
+        let was = self.rectified(source);
+        self.input[source] = level;
+        let now = self.rectified(source);
+        let mode = self.mode[source];
+        if mode.is_level() {
+            if !now {
+                self.pending[source] = false;
+            } else if !was || !self.msi_mode() {
+                self.pending[source] = true;
+            }
+        } else if now && !was {
+            self.pending[source] = true;
+        }
+        self.forward();
+    }
+
+    // setip and friends. Level sources can only be set while their input is
+    // high and only in MSI mode, in direct mode they follow the wire.
+    fn set_pending(&mut self, source: usize, pending: bool) {
+        if !self.active(source) {
+            return;
+        }
+        if self.mode[source].is_level()
+            && (!self.msi_mode() || (pending && !self.rectified(source)))
+        {
+            return;
+        }
+        self.pending[source] = pending;
+    }
+
+    fn set_enabled(&mut self, source: usize, enabled: bool) {
+        if self.active(source) {
+            self.enabled[source] = enabled;
+        }
+    }
+
+    fn write_sourcecfg(&mut self, source: usize, value: u32) {
+        // bit 10 delegates to a child domain, there are none
+        let Some(mode) = SourceMode::from_bits(value & 0x7).filter(|_| value & 1 << 10 == 0) else {
+            return;
+        };
+        self.mode[source] = mode;
+        if mode == SourceMode::Inactive {
+            self.pending[source] = false;
+            self.enabled[source] = false;
+            self.target[source] = 0;
+        } else if mode.is_level() {
+            self.pending[source] = self.rectified(source);
+        }
+    }
+
+    fn write_target(&mut self, source: usize, value: u32) {
+        if !self.active(source) {
+            return;
+        }
+        // hart indexes are kept, only hart 0 ever receives anything
+        let hart = value & !((1 << TARGET_HART_SHIFT) - 1);
+        self.target[source] = if self.msi_mode() {
+            // there are no guest interrupt files, the guest index is zero
+            hart | value & TARGET_EIID
+        } else {
+            // priority 0 is not allowed and becomes 1
+            hart | (value & TARGET_IPRIO).max(1)
+        };
+    }
+
+    // Direct mode: the pending and enabled source for hart 0 with the lowest
+    // priority number (ties to the lowest id) under a nonzero ithreshold
+    fn best(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for source in 1..=self.sources {
+            if !self.pending[source]
+                || !self.enabled[source]
+                || self.target[source] >> TARGET_HART_SHIFT != 0
+            {
+                continue;
+            }
+            let priority = self.target[source] & TARGET_IPRIO;
+            if self.ithreshold != 0 && priority >= self.ithreshold {
+                continue;
+            }
+            if best.is_none_or(|best| priority < self.target[best] & TARGET_IPRIO) {
+                best = Some(source);
+            }
+        }
+        best
+    }
+
+    fn topi(&self) -> u32 {
+        self.best().map_or(0, |source| {
+            (source as u32) << 16 | self.target[source] & TARGET_IPRIO
+        })
+    }
+
+    fn claimi(&mut self) -> u32 {
+        let topi = self.topi();
+        match self.best() {
+            Some(source) => {
+                // a level source with its line still high stays pending
+                self.pending[source] = self.mode[source].is_level() && self.rectified(source);
+            }
+            None => self.iforce = 0, // spurious interrupt acknowledged
+        }
+        topi
+    }
+
+    // Interrupt output of hart 0's IDC in direct mode
+    pub(crate) fn interrupt(&self) -> bool {
+        !self.msi_mode()
+            && self.domaincfg & DOMAINCFG_IE != 0
+            && self.idelivery == 1
+            && (self.iforce == 1 || self.best().is_some())
+    }
+
+    // MSI mode: every pending and enabled source is sent and stops pending
+    fn forward(&mut self) {
+        if !self.msi_mode() || self.domaincfg & DOMAINCFG_IE == 0 {
+            return;
+        }
+        for source in 1..=self.sources {
+            if self.pending[source] && self.enabled[source] {
+                self.pending[source] = false;
+                self.send(self.target[source]);
+            }
+        }
+    }
+
+    fn send(&mut self, target: u32) {
+        if target >> TARGET_HART_SHIFT == 0 {
+            self.msis.push(target & TARGET_EIID);
+        }
+    }
+
+    // Identities forwarded since the last call, for the interrupt file of
+    // `level`
+    pub(crate) fn take_msis(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.msis)
+    }
+
+    // 32 sources of a per-source flag array packed into a register
+    fn bits(&self, flags: &[bool], word: u64) -> u32 {
+        (0..32).fold(0, |value, bit| {
+            let source = word as usize * 32 + bit;
+            if flags.get(source).copied().unwrap_or(false) {
+                value | 1 << bit
+            } else {
+                value
+            }
+        })
+    }
+
+    fn rectified_bits(&self, word: u64) -> u32 {
+        let rectified: Vec<bool> = (0..=self.sources)
+            .map(|source| self.active(source) && self.rectified(source))
+            .collect();
+        self.bits(&rectified, word)
+    }
+
+    // 32 bit registers only, None for anything else. claimi has a side
+    // effect so reads take &mut self.
+    pub(crate) fn read(&mut self, offset: u64, size: usize) -> Option<u32> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let source = |base: u64| ((offset - base) / 4 + 1) as usize;
+        let value = match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            SOURCECFG..MMSIADDRCFG => {
+                let source = source(SOURCECFG);
+                if !self.valid(source) {
+                    return Some(0);
+                }
+                self.mode[source] as u32
+            }
+            MMSIADDRCFG..=SMSIADDRCFGH => self.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize],
+            SETIP..SETIPNUM => self.bits(&self.pending, (offset - SETIP) / 4),
+            IN_CLRIP..CLRIPNUM => self.rectified_bits((offset - IN_CLRIP) / 4),
+            SETIE..SETIENUM => self.bits(&self.enabled, (offset - SETIE) / 4),
+            GENMSI => 0, // never busy, MSIs are sent on the spot
+            TARGET..IDC => {
+                let source = source(TARGET);
+                if !self.valid(source) {
+                    return Some(0);
+                }
+                self.target[source]
+            }
+            IDC..=0x7FFF => match offset - IDC {
+                IDELIVERY => self.idelivery,
+                IFORCE => self.iforce,
+                ITHRESHOLD => self.ithreshold,
+                TOPI => self.topi(),
+                CLAIMI => self.claimi(),
+                offset if offset < IDC_SIZE => 0,
+                _ => return None, // no IDCs for other harts
+            },
+            _ => 0,
+        };
+        Some(value)
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, size: usize, value: u32) -> Option<()> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let source = |base: u64| ((offset - base) / 4 + 1) as usize;
+        match offset {
+            DOMAINCFG => self.domaincfg = value & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG..MMSIADDRCFG => {
+                let source = source(SOURCECFG);
+                if self.valid(source) {
+                    self.write_sourcecfg(source, value);
+                }
+            }
+            MMSIADDRCFG..=SMSIADDRCFGH => {
+                self.msiaddrcfg[((offset - MMSIADDRCFG) / 4) as usize] = value
+            }
+            SETIP..SETIPNUM => self.write_bits(offset - SETIP, value, Self::set_pending, true),
+            SETIPNUM | SETIPNUM_LE => self.set_pending(value as usize, true),
+            SETIPNUM_BE => self.set_pending(value.swap_bytes() as usize, true),
+            IN_CLRIP..CLRIPNUM => {
+                self.write_bits(offset - IN_CLRIP, value, Self::set_pending, false)
+            }
+            CLRIPNUM => self.set_pending(value as usize, false),
+            SETIE..SETIENUM => self.write_bits(offset - SETIE, value, Self::set_enabled, true),
+            SETIENUM => self.set_enabled(value as usize, true),
+            CLRIE..CLRIENUM => self.write_bits(offset - CLRIE, value, Self::set_enabled, false),
+            CLRIENUM => self.set_enabled(value as usize, false),
+            GENMSI if self.msi_mode() => {
+                self.send(value & !((1 << TARGET_HART_SHIFT) - 1) | value & TARGET_EIID)
+            }
+            TARGET..IDC => {
+                let source = source(TARGET);
+                if self.valid(source) {
+                    self.write_target(source, value);
+                }
+            }
+            IDC..=0x7FFF => match offset - IDC {
+                IDELIVERY => self.idelivery = value & 1,
+                IFORCE => self.iforce = value & 1,
+                ITHRESHOLD => self.ithreshold = value & TARGET_IPRIO,
+                offset if offset < IDC_SIZE => {}
+                _ => return None,
+            },
+            _ => {}
+        }
+        self.forward();
+        Some(())
+    }
+
+    // setip/in_clrip/setie/clrie: each set bit of register `word` applies
+    // `update` to its source
+    fn write_bits(
+        &mut self,
+        offset: u64,
+        value: u32,
+        update: fn(&mut Self, usize, bool),
+        on: bool,
+    ) {
+        let word = (offset / 4) as usize;
+        for bit in 0..32 {
+            if value & (1 << bit) != 0 {
+                update(self, word * 32 + bit, on);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        tests::vm_with_program,
+        WORD_SIZE,
+    };
+
+    use super::{Aplic, APLIC_BASE};
+
+    #[test]
+    fn test_direct_delivery() {
+        let mut aplic = Aplic::new(8, Privilege::Machine);
+        aplic.write(0x0, 4, 1 << 8).unwrap(); // domaincfg.IE, direct mode
+        aplic.write(0x4 * 2, 4, 6).unwrap(); // source 2 level high
+        aplic.write(0x4 * 3, 4, 4).unwrap(); // source 3 rising edge
+        aplic.write(0x1EDC, 4, 2).unwrap(); // setienum
+        aplic.write(0x1EDC, 4, 3).unwrap();
+        aplic.write(0x3004 + 4, 4, 0).unwrap(); // target[2], priority 0 -> 1
+        aplic.write(0x3004 + 8, 4, 1).unwrap(); // target[3], priority 1
+        assert_eq!(aplic.read(0x3004 + 4, 4), Some(1));
+
+        aplic.set_input(3, true);
+        aplic.set_input(2, true);
+        assert!(!aplic.interrupt()); // idelivery still off
+        aplic.write(0x4000, 4, 1).unwrap();
+        assert!(aplic.interrupt());
+        assert_eq!(aplic.read(0x1C00, 4), Some(1 << 2 | 1 << 3));
+        assert_eq!(aplic.read(0x1D00, 4), Some(1 << 2 | 1 << 3));
+
+        // equal priorities go to the lower id, a level source stays pending
+        // while its line is high
+        assert_eq!(aplic.read(0x4018, 4), Some(2 << 16 | 1));
+        assert_eq!(aplic.read(0x401C, 4), Some(2 << 16 | 1));
+        assert_eq!(aplic.read(0x401C, 4), Some(2 << 16 | 1));
+        aplic.set_input(2, false);
+        assert_eq!(aplic.read(0x401C, 4), Some(3 << 16 | 1));
+        assert!(!aplic.interrupt());
+
+        // edges latch once, software can pend them
+        aplic.write(0x1CDC, 4, 3).unwrap();
+        aplic.write(0x4008, 4, 1).unwrap(); // ithreshold masks priority 1
+        assert!(!aplic.interrupt());
+        aplic.write(0x4008, 4, 0).unwrap();
+        aplic.write(0x1DDC, 4, 3).unwrap(); // clripnum
+        assert!(!aplic.interrupt());
+
+        // iforce raises a spurious interrupt claimed as 0
+        aplic.write(0x4004, 4, 1).unwrap();
+        assert!(aplic.interrupt());
+        assert_eq!(aplic.read(0x401C, 4), Some(0));
+        assert!(!aplic.interrupt());
+    }
+
+    #[test]
+    fn test_msi_delivery_to_the_supervisor_file() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        let aplic = APLIC_BASE as u32;
+        let s = Privilege::Supervisor;
+        vm.csr.write(csr::SISELECT, 0x70, s).unwrap();
+        vm.csr.write(csr::SIREG, 1, s).unwrap(); // eidelivery
+        vm.csr.write(csr::SISELECT, 0xC0, s).unwrap();
+        vm.csr.write(csr::SIREG, 1 << 9, s).unwrap(); // eie for identity 9
+
+        vm.store(WORD_SIZE, aplic, 1 << 8 | 1 << 2).unwrap(); // IE, MSI mode
+        vm.store(WORD_SIZE, aplic + 4 * 4, 6).unwrap(); // source 4 level high
+        vm.store(WORD_SIZE, aplic + 0x3004 + 3 * 4, 9).unwrap(); // EIID 9
+        vm.store(WORD_SIZE, aplic + 0x1EDC, 4).unwrap(); // setienum
+
+        vm.set_interrupt_line(4, true);
+        vm.step();
+        assert_eq!(
+            vm.csr.read(csr::MIP, Privilege::Machine),
+            Some(csr::MIP_SEIP)
+        );
+        assert_eq!(vm.csr.read(csr::STOPEI, s), Some(9 << 16 | 9));
+
+        // forwarded once per assertion of the line
+        vm.csr.write(csr::STOPEI, 0, s).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.read(csr::MIP, Privilege::Machine), Some(0));
+        assert_eq!(vm.load(WORD_SIZE, aplic + 0x1C00), Ok(0));
+
+        // genmsi to hart 0
+        vm.store(WORD_SIZE, aplic + 0x3000, 9).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.read(csr::STOPEI, s), Some(9 << 16 | 9));
+    }
+}
@@ -0,0 +1,511 @@
+// System bus: the physical address space as a list of regions. RAM and ROM
+// own their bytes, MMIO regions forward to a device at an offset from their
+// base, holes and anything unmapped raise access faults.
+use std::{fmt, fs};
+
+use super::{
+    aplic::{APLIC_BASE, APLIC_SIZE},
+    clint::{CLINT_BASE, CLINT_SIZE},
+    imsic::{IMSIC_FILE_SIZE, IMSIC_M_BASE, IMSIC_S_BASE},
+    plic::{PLIC_BASE, PLIC_SIZE},
+    Vm,
+};
+
+// QEMU virt layout
+pub(crate) const BOOT_ROM_BASE: u64 = 0x1000;
+pub(crate) const BOOT_ROM_SIZE: u64 = 0x1000;
+pub(crate) const DRAM_BASE: u64 = 0x8000_0000;
+
+// Devices an MMIO region can forward to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Mmio {
+    Clint,
+    Plic,
+    Aplic,
+    ImsicMachine,
+    ImsicSupervisor,
+    Device(usize), // attached with Vm::attach, index into Vm.devices
+    Virtio(usize), // virtio-mmio transport, index into Vm.virtio
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum RegionKind {
+    Ram,
+    Rom,  // read only to the guest, written by loaders through the backdoor
+    Vram, // RAM to the guest, but not memory it is given to run in
+    Mmio(Mmio),
+    Hole, // reserved, every access faults
+}
+
+pub(crate) struct Region {
+    pub(crate) name: String,
+    pub(crate) base: u64,
+    pub(crate) size: u64,
+    pub(crate) kind: RegionKind,
+    data: Vec<u8>, // contents of RAM, ROM and VRAM
+}
+
+impl Region {
+    pub(crate) fn new(name: &str, base: u64, size: u64, kind: RegionKind) -> Self {
+        let data = match kind {
+            RegionKind::Ram | RegionKind::Rom | RegionKind::Vram => vec![0; size as usize],
+            RegionKind::Mmio(_) | RegionKind::Hole => Vec::new(),
+        };
+        Self {
+            name: name.to_string(),
+            base,
+            size,
+            kind,
+            data,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    fn is_memory(&self) -> bool {
+        matches!(
+            self.kind,
+            RegionKind::Ram | RegionKind::Rom | RegionKind::Vram
+        )
+    }
+
+    // contents of a ROM, whatever does not fit is dropped
+    pub(crate) fn with_contents(mut self, contents: &[u8]) -> Self {
+        let length = contents.len().min(self.data.len());
+        self.data[..length].copy_from_slice(&contents[..length]);
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MapError {
+    Empty(String),           // region with size 0
+    Overflow(String),        // region running past the end of the address space
+    Overlap(String, String), // names of the two regions
+    Source(String),          // device wired to an interrupt source that does not exist
+    Sources(usize),          // interrupt controllers sized beyond what they support
+    VirtioSlots(String),     // device added when every virtio-mmio slot is taken
+    Parse { line: usize, message: String },
+    Io(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Empty(name) => write!(f, "region {} is empty", name),
+            MapError::Overflow(name) => write!(f, "region {} ends past 2^64", name),
+            MapError::Overlap(first, second) => {
+                write!(f, "regions {} and {} overlap", first, second)
+            }
+            MapError::Source(name) => write!(f, "device {} has no such interrupt source", name),
+            MapError::Sources(count) => write!(
+                f,
+                "{} interrupt sources, the PLIC takes 1 to {}",
+                count,
+                super::plic::MAX_SOURCES
+            ),
+            MapError::VirtioSlots(name) => write!(
+                f,
+                "no virtio slot left for {}, there are {}",
+                name,
+                super::virtio::VIRTIO_SLOTS
+            ),
+            MapError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MapError::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+pub struct Bus {
+    regions: Vec<Region>, // sorted by base, never overlapping
+}
+
+impl Bus {
+    pub(crate) fn new(regions: Vec<Region>) -> Result<Self, MapError> {
+        let mut bus = Self { regions: vec![] };
+        for region in regions {
+            bus.add(region)?;
+        }
+        Ok(bus)
+    }
+
+    pub(crate) fn add(&mut self, region: Region) -> Result<(), MapError> {
+        if region.size == 0 {
+            return Err(MapError::Empty(region.name));
+        }
+        if region.base.checked_add(region.size).is_none() {
+            return Err(MapError::Overflow(region.name));
+        }
+        let index = self
+            .regions
+            .partition_point(|other| other.base < region.base);
+        if let Some(next) = self.regions.get(index) {
+            if region.end() > next.base {
+                return Err(MapError::Overlap(region.name, next.name.clone()));
+            }
+        }
+        if let Some(previous) = index.checked_sub(1).map(|index| &self.regions[index]) {
+            if previous.end() > region.base {
+                return Err(MapError::Overlap(previous.name.clone(), region.name));
+            }
+        }
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    // The interrupt controllers at their QEMU virt addresses
+    fn devices() -> Vec<Region> {
+        vec![
+            Region::new(
+                "clint",
+                CLINT_BASE,
+                CLINT_SIZE,
+                RegionKind::Mmio(Mmio::Clint),
+            ),
+            Region::new("plic", PLIC_BASE, PLIC_SIZE, RegionKind::Mmio(Mmio::Plic)),
+            Region::new(
+                "aplic",
+                APLIC_BASE,
+                APLIC_SIZE,
+                RegionKind::Mmio(Mmio::Aplic),
+            ),
+            Region::new(
+                "imsic-m",
+                IMSIC_M_BASE,
+                IMSIC_FILE_SIZE,
+                RegionKind::Mmio(Mmio::ImsicMachine),
+            ),
+            Region::new(
+                "imsic-s",
+                IMSIC_S_BASE,
+                IMSIC_FILE_SIZE,
+                RegionKind::Mmio(Mmio::ImsicSupervisor),
+            ),
+        ]
+    }
+
+    // The devices with RAM in every gap of the 32 bit address space, so any
+    // address a test picks is memory
+    #[cfg(test)]
+    pub(crate) fn flat() -> Self {
+        let devices = Self::devices();
+        let mut regions = Vec::new();
+        let mut next = 0;
+        for device in &devices {
+            if device.base > next {
+                regions.push(Region::new(
+                    "ram",
+                    next,
+                    device.base - next,
+                    RegionKind::Ram,
+                ));
+            }
+            next = device.end();
+        }
+        let end = super::MAX_ADDRESSABLE_MEMORY as u64;
+        regions.push(Region::new("ram", next, end - next, RegionKind::Ram));
+        regions.extend(devices);
+        Self::new(regions).unwrap()
+    }
+
+    // QEMU virt: boot ROM, the devices and `ram_size` bytes of RAM at DRAM_BASE
+    pub fn virt(ram_size: u64) -> Result<Self, MapError> {
+        let mut regions = Self::devices();
+        regions.push(Region::new(
+            "boot-rom",
+            BOOT_ROM_BASE,
+            BOOT_ROM_SIZE,
+            RegionKind::Rom,
+        ));
+        regions.push(Region::new("ram", DRAM_BASE, ram_size, RegionKind::Ram));
+        Self::new(regions)
+    }
+
+    // One region per line, `name kind base size [rom contents]`, # starts a
+    // comment. Kinds are ram, rom, hole and the devices clint, plic, aplic,
+    // imsic-m and imsic-s. Numbers are decimal or 0x hex with an optional
+    // K, M or G suffix.
+    pub fn from_config(text: &str) -> Result<Self, MapError> {
+        let mut regions = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| MapError::Parse {
+                line: index + 1,
+                message,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if !(4..=5).contains(&fields.len()) {
+                return Err(error("expected name, kind, base and size".to_string()));
+            }
+            let kind = match fields[1] {
+                "ram" => RegionKind::Ram,
+                "rom" => RegionKind::Rom,
+                "hole" => RegionKind::Hole,
+                "clint" => RegionKind::Mmio(Mmio::Clint),
+                "plic" => RegionKind::Mmio(Mmio::Plic),
+                "aplic" => RegionKind::Mmio(Mmio::Aplic),
+                "imsic-m" => RegionKind::Mmio(Mmio::ImsicMachine),
+                "imsic-s" => RegionKind::Mmio(Mmio::ImsicSupervisor),
+                kind => return Err(error(format!("unknown region kind {}", kind))),
+            };
+            let base = parse_number(fields[2]).ok_or_else(|| error("bad base".to_string()))?;
+            let size = parse_number(fields[3]).ok_or_else(|| error("bad size".to_string()))?;
+            let mut region = Region::new(fields[0], base, size, kind);
+            if let Some(path) = fields.get(4) {
+                if kind != RegionKind::Rom {
+                    return Err(error("only a rom has contents".to_string()));
+                }
+                let contents =
+                    fs::read(path).map_err(|err| MapError::Io(format!("{}: {}", path, err)))?;
+                region = region.with_contents(&contents);
+            }
+            regions.push(region);
+        }
+        Self::new(regions)
+    }
+
+    pub fn load(path: &str) -> Result<Self, MapError> {
+        let text =
+            fs::read_to_string(path).map_err(|err| MapError::Io(format!("{}: {}", path, err)))?;
+        Self::from_config(&text)
+    }
+
+    pub(crate) fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // The region holding all `size` bytes at `address`
+    pub(crate) fn region(&self, address: u64, size: usize) -> Option<&Region> {
+        let index = self
+            .regions
+            .partition_point(|region| region.base <= address)
+            .checked_sub(1)?;
+        let region = &self.regions[index];
+        let end = address.checked_add(size as u64)?;
+        (end <= region.end()).then_some(region)
+    }
+
+    fn region_mut(&mut self, address: u64, size: usize) -> Option<&mut Region> {
+        let index = self
+            .regions
+            .partition_point(|region| region.base <= address)
+            .checked_sub(1)?;
+        let region = &mut self.regions[index];
+        let end = address.checked_add(size as u64)?;
+        (end <= region.end()).then_some(region)
+    }
+
+    // RAM or ROM, what can be fetched from and walked
+    pub(crate) fn is_memory(&self, address: u64, size: usize) -> bool {
+        self.region(address, size)
+            .is_some_and(|region| region.is_memory())
+    }
+
+    pub(crate) fn is_ram(&self, address: u64, size: usize) -> bool {
+        self.region(address, size)
+            .is_some_and(|region| region.kind == RegionKind::Ram)
+    }
+
+    // Bytes of RAM or ROM in memory order
+    pub(crate) fn read(&self, address: u64, size: usize) -> Option<&[u8]> {
+        let region = self
+            .region(address, size)
+            .filter(|region| region.is_memory())?;
+        let start = (address - region.base) as usize;
+        Some(&region.data[start..start + size])
+    }
+
+    // Backdoor write into RAM or ROM, the guest's stores check is_ram first
+    pub(crate) fn write(&mut self, address: u64, bytes: &[u8]) -> Option<()> {
+        let region = self
+            .region_mut(address, bytes.len())
+            .filter(|region| region.is_memory())?;
+        let start = (address - region.base) as usize;
+        region.data[start..start + bytes.len()].copy_from_slice(bytes);
+        Some(())
+    }
+}
+
+// The memory map printed at startup
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in &self.regions {
+            let kind = match region.kind {
+                RegionKind::Ram => "ram",
+                RegionKind::Rom => "rom",
+                RegionKind::Vram => "vram",
+                RegionKind::Hole => "hole",
+                RegionKind::Mmio(_) => "mmio",
+            };
+            writeln!(
+                f,
+                "{:#011x}-{:#011x} {:<5} {}",
+                region.base,
+                region.end() - 1,
+                kind,
+                region.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Decimal or 0x hex, with an optional K, M or G suffix
+pub fn parse_number(text: &str) -> Option<u64> {
+    let (digits, scale) = match text.chars().last()? {
+        'K' | 'k' => (&text[..text.len() - 1], 1 << 10),
+        'M' | 'm' => (&text[..text.len() - 1], 1 << 20),
+        'G' | 'g' => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok()?,
+        None => digits.replace('_', "").parse().ok()?,
+    };
+    value.checked_mul(scale)
+}
+
+impl Vm {
+    pub(crate) fn mmio_read(&mut self, device: Mmio, offset: u64, size: usize) -> Option<u32> {
+        match device {
+            Mmio::Clint => self.clint.read(offset, size),
+            Mmio::Plic => self.plic.read(offset, size),
+            Mmio::Aplic => self.aplic.read(offset, size),
+            Mmio::ImsicMachine => self.csr.imsic.machine.mmio_read(offset, size),
+            Mmio::ImsicSupervisor => self.csr.imsic.supervisor.mmio_read(offset, size),
+            Mmio::Device(index) => self.device_read(index, offset, size),
+            Mmio::Virtio(index) => self.virtio_read(index, offset, size),
+        }
+    }
+
+    pub(crate) fn mmio_write(
+        &mut self,
+        device: Mmio,
+        offset: u64,
+        size: usize,
+        value: u32,
+    ) -> Option<()> {
+        match device {
+            Mmio::Clint => self.clint.write(offset, size, value),
+            Mmio::Plic => self.plic.write(offset, size, value),
+            Mmio::Aplic => self.aplic.write(offset, size, value),
+            Mmio::ImsicMachine => self.csr.imsic.machine.mmio_write(offset, size, value),
+            Mmio::ImsicSupervisor => self.csr.imsic.supervisor.mmio_write(offset, size, value),
+            Mmio::Device(index) => self.device_write(index, offset, size, value),
+            Mmio::Virtio(index) => self.virtio_write(index, offset, size, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        instruction::into_byte,
+        registers::{BaseIsa, Registers},
+        tests::allow_all_memory,
+        trap::Exception,
+        Vm, WORD_SIZE,
+    };
+
+    use super::{Bus, MapError, Region, RegionKind, DRAM_BASE};
+
+    const MAP: &str = "
+        # name  kind   base        size
+        rom     rom    0x1000      4K
+        clint   clint  0x2000000   0x10000
+        guard   hole   0x7fff0000  64K
+        ram     ram    0x80000000  1M
+    ";
+
+    #[test]
+    fn test_config_and_validation() {
+        let bus = Bus::from_config(MAP).unwrap();
+        assert_eq!(
+            bus.to_string().lines().collect::<Vec<_>>(),
+            [
+                "0x000001000-0x000001fff rom   rom",
+                "0x002000000-0x00200ffff mmio  clint",
+                "0x07fff0000-0x07fffffff hole  guard",
+                "0x080000000-0x0800fffff ram   ram",
+            ]
+        );
+        assert!(bus.is_ram(DRAM_BASE + 0xFFFFC, WORD_SIZE));
+        assert!(!bus.is_ram(DRAM_BASE + 0xFFFFE, WORD_SIZE));
+        assert!(bus.is_memory(0x1000, WORD_SIZE));
+        assert!(!bus.is_memory(0x2000, WORD_SIZE));
+
+        assert!(matches!(
+            Bus::from_config("ram ram 0x0 1M\nrom rom 0x80000 4K"),
+            Err(MapError::Overlap(first, second)) if first == "ram" && second == "rom"
+        ));
+        assert_eq!(
+            Bus::from_config("ram flash 0x0 1M").err(),
+            Some(MapError::Parse {
+                line: 1,
+                message: "unknown region kind flash".to_string()
+            })
+        );
+        assert_eq!(
+            Bus::new(vec![Region::new("x", 0, 0, RegionKind::Ram)]).err(),
+            Some(MapError::Empty("x".to_string()))
+        );
+        assert_eq!(Bus::virt(0).err(), Some(MapError::Empty("ram".to_string())));
+    }
+
+    #[test]
+    fn test_guest_accesses() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::from_config(MAP).unwrap());
+        allow_all_memory(&mut vm);
+        vm.bus.write(0x1000, &[0x13, 0, 0, 0]).unwrap(); // nop in the rom
+
+        assert_eq!(vm.load(WORD_SIZE, 0x1000), Ok(0x13));
+        assert_eq!(
+            vm.store(WORD_SIZE, 0x1000, 0),
+            Err(Exception::StoreAccessFault(0x1000))
+        );
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x7fff_0000),
+            Err(Exception::LoadAccessFault(0x7fff_0000))
+        );
+        // unmapped
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x4000_0000),
+            Err(Exception::LoadAccessFault(0x4000_0000))
+        );
+        vm.store(WORD_SIZE, 0x8000_0000, 0x1234).unwrap();
+        assert_eq!(vm.load(WORD_SIZE, 0x8000_0000), Ok(0x1234));
+        vm.store(WORD_SIZE, 0x0200_4000, 5).unwrap();
+        assert_eq!(vm.clint.mtimecmp & 0xFFFF_FFFF, 5);
+
+        // executes from the rom, then faults fetching the unmapped page after it
+        vm.set_register(Registers::Pc as u32, 0x1000);
+        vm.csr.mtvec = 0x8000_0000;
+        vm.step();
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x1004);
+        vm.set_register(Registers::Pc as u32, 0x2000);
+        vm.step();
+        assert_eq!(vm.csr.mcause, 1);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_memory_above_4g() {
+        let map = "low ram 0x0 4K\nhigh ram 0x100000000 4K";
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::from_config(map).unwrap());
+        vm.mem_write(WORD_SIZE, 0x1_0000_0000, &into_byte(0x1234))
+            .unwrap();
+        assert_eq!(vm.bus.read(0, WORD_SIZE), Some(&[0, 0, 0, 0][..]));
+        assert_eq!(vm.read_physical(WORD_SIZE, 0x1_0000_0000, 0), Ok(0x1234));
+        // past the end of the map is a fault, not a panic
+        assert_eq!(vm.mem_read(WORD_SIZE, 0x1_0000_1000), None);
+        assert_eq!(
+            vm.read_physical(WORD_SIZE, 0x1_0000_1000, 0),
+            Err(Exception::LoadAccessFault(0))
+        );
+    }
+}
@@ -0,0 +1,130 @@
+// Core-local interruptor, the SiFive layout used by QEMU virt and Spike
+pub(crate) const CLINT_BASE: u64 = 0x0200_0000;
+pub(crate) const CLINT_SIZE: u64 = 0x1_0000;
+
+// mtime ticks per second, as on QEMU virt. One tick is one step here, so this
+// is only what guests are told.
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// register offsets for hart 0
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIMECMPH: u64 = 0x4004;
+const MTIME: u64 = 0xBFF8;
+const MTIMEH: u64 = 0xBFFC;
+
+pub(crate) struct Clint {
+    pub(crate) msip: bool,
+    pub(crate) mtimecmp: u64,
+    pub(crate) mtime: u64,
+}
+
+impl Clint {
+    pub(crate) fn new() -> Self {
+        Self {
+            msip: false,
+            // no timer interrupt until software programs a deadline
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+
+    // Registers are 32 bits wide on RV32, None for any other access
+    pub(crate) fn read(&self, offset: u64, size: usize) -> Option<u32> {
+        if size != 4 {
+            return None;
+        }
+        let value = match offset {
+            MSIP => self.msip as u32,
+            MTIMECMP => self.mtimecmp as u32,
+            MTIMECMPH => (self.mtimecmp >> 32) as u32,
+            MTIME => self.mtime as u32,
+            MTIMEH => (self.mtime >> 32) as u32,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, size: usize, value: u32) -> Option<()> {
+        if size != 4 {
+            return None;
+        }
+        match offset {
+            MSIP => self.msip = value & 1 != 0,
+            MTIMECMP => self.mtimecmp = (self.mtimecmp & !0xFFFF_FFFF) | value as u64,
+            MTIMECMPH => self.mtimecmp = (self.mtimecmp & 0xFFFF_FFFF) | (value as u64) << 32,
+            MTIME => self.mtime = (self.mtime & !0xFFFF_FFFF) | value as u64,
+            MTIMEH => self.mtime = (self.mtime & 0xFFFF_FFFF) | (value as u64) << 32,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    pub(crate) fn timer_pending(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr, instruction::into_byte, registers::Registers, tests::vm_with_program, WORD_SIZE,
+    };
+
+    use super::{Clint, CLINT_BASE};
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::new();
+        clint.write(0x4000, 4, 0x10).unwrap();
+        clint.write(0x4004, 4, 0x1).unwrap();
+        assert_eq!(clint.mtimecmp, 0x1_0000_0010);
+        assert_eq!(clint.read(0x4004, 4), Some(1));
+        assert_eq!(clint.read(0x4000, 2), None);
+        assert_eq!(clint.read(0x8, 4), None);
+
+        clint.mtime = 0x1_0000_0010;
+        assert!(clint.timer_pending());
+    }
+
+    #[test]
+    fn test_wfi_fast_forwards_to_timer() {
+        let mut program = vec![0; 0x104 / WORD_SIZE];
+        program[..4].copy_from_slice(&[
+            0x020002b7, // lui x5, 0x2000 (CLINT)
+            0x00004337, // lui x6, 0x4
+            0x006282b3, // add x5, x5, x6 (mtimecmp)
+            0x10500073, // wfi
+        ]);
+        program[0x100 / WORD_SIZE] = 0x00100413; // addi x8, x0, 1
+        let mut vm = vm_with_program(&program);
+        vm.csr.mtvec = 0x100;
+        vm.csr.mie = csr::MIP_MTIP;
+        vm.csr.mstatus |= csr::MSTATUS_MIE;
+        vm.clint.mtimecmp = 1000;
+
+        vm.run(4);
+        assert!(vm.waiting);
+        // the next step jumps time to the deadline, the one after takes it
+        vm.run(2);
+        assert!(!vm.waiting);
+        assert_eq!(vm.csr.mcause, 1 << 31 | 7);
+        assert_eq!(vm.csr.mepc, 0x10);
+        assert!(vm.clint.mtime >= 1000);
+        vm.run(1);
+        assert_eq!(vm.get_register(Registers::S0 as u32), 1);
+    }
+
+    #[test]
+    fn test_software_interrupt_over_mmio() {
+        let mut vm = vm_with_program(&[]);
+        vm.store(WORD_SIZE, CLINT_BASE as u32, 1).unwrap();
+        assert_eq!(vm.load(WORD_SIZE, CLINT_BASE as u32), Ok(1));
+
+        vm.csr.mie = csr::MIP_MSIP;
+        vm.csr.mstatus |= csr::MSTATUS_MIE;
+        vm.mem_write(WORD_SIZE, 0, &into_byte(0x00000013)).unwrap(); // nop
+        vm.step();
+        assert_eq!(vm.csr.mcause, 1 << 31 | 3);
+    }
+}
@@ -0,0 +1,698 @@
+use super::{
+    imsic::Imsic,
+    pmp::{self, Pmp},
+    registers::BaseIsa,
+    trap::INTERRUPT_PRIORITY,
+    trigger::{self, Triggers},
+};
+
+// Privilege levels, encoded as in xstatus.xPP and csr address bits 9:8
+#[derive(Debug, PartialEq, Clone, Copy, PartialOrd)]
+pub(crate) enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    // None for encodings of unsupported levels
+    pub(crate) fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+// Supervisor trap setup
+pub(crate) const SSTATUS: u32 = 0x100;
+pub(crate) const SIE: u32 = 0x104;
+pub(crate) const STVEC: u32 = 0x105;
+pub(crate) const SCOUNTEREN: u32 = 0x106;
+
+// Supervisor trap handling
+pub(crate) const SSCRATCH: u32 = 0x140;
+pub(crate) const SEPC: u32 = 0x141;
+pub(crate) const SCAUSE: u32 = 0x142;
+pub(crate) const STVAL: u32 = 0x143;
+pub(crate) const SIP: u32 = 0x144;
+
+// Supervisor timer compare (Sstc)
+pub(crate) const STIMECMP: u32 = 0x14D;
+pub(crate) const STIMECMPH: u32 = 0x15D;
+
+// Supervisor advanced interrupts (Ssaia)
+pub(crate) const SISELECT: u32 = 0x150;
+pub(crate) const SIREG: u32 = 0x151;
+pub(crate) const STOPEI: u32 = 0x15C;
+pub(crate) const STOPI: u32 = 0xDB0;
+pub(crate) const SIEH: u32 = 0x114;
+pub(crate) const SIPH: u32 = 0x154;
+
+// Supervisor protection and translation
+pub(crate) const SATP: u32 = 0x180;
+
+// Virtual supervisor registers, what VS mode sees as its s* csrs
+pub(crate) const VSSTATUS: u32 = 0x200;
+pub(crate) const VSIE: u32 = 0x204;
+pub(crate) const VSTVEC: u32 = 0x205;
+pub(crate) const VSSCRATCH: u32 = 0x240;
+pub(crate) const VSEPC: u32 = 0x241;
+pub(crate) const VSCAUSE: u32 = 0x242;
+pub(crate) const VSTVAL: u32 = 0x243;
+pub(crate) const VSIP: u32 = 0x244;
+pub(crate) const VSATP: u32 = 0x280;
+
+// Hypervisor trap setup, handling and guest translation
+pub(crate) const HSTATUS: u32 = 0x600;
+pub(crate) const HEDELEG: u32 = 0x602;
+pub(crate) const HIDELEG: u32 = 0x603;
+pub(crate) const HIE: u32 = 0x604;
+pub(crate) const HTIMEDELTA: u32 = 0x605;
+pub(crate) const HCOUNTEREN: u32 = 0x606;
+pub(crate) const HGEIE: u32 = 0x607;
+pub(crate) const HENVCFG: u32 = 0x60A;
+pub(crate) const HTIMEDELTAH: u32 = 0x615;
+pub(crate) const HENVCFGH: u32 = 0x61A;
+pub(crate) const HTVAL: u32 = 0x643;
+pub(crate) const HIP: u32 = 0x644;
+pub(crate) const HVIP: u32 = 0x645;
+pub(crate) const HTINST: u32 = 0x64A;
+pub(crate) const HGATP: u32 = 0x680;
+pub(crate) const HGEIP: u32 = 0xE12;
+
+// Machine information registers
+pub(crate) const MVENDORID: u32 = 0xF11;
+pub(crate) const MARCHID: u32 = 0xF12;
+pub(crate) const MIMPID: u32 = 0xF13;
+pub(crate) const MHARTID: u32 = 0xF14;
+
+// Machine trap setup
+pub(crate) const MSTATUS: u32 = 0x300;
+pub(crate) const MISA: u32 = 0x301;
+pub(crate) const MEDELEG: u32 = 0x302;
+pub(crate) const MIDELEG: u32 = 0x303;
+pub(crate) const MIE: u32 = 0x304;
+pub(crate) const MTVEC: u32 = 0x305;
+pub(crate) const MCOUNTEREN: u32 = 0x306;
+pub(crate) const MSTATUSH: u32 = 0x310;
+
+// Machine configuration
+pub(crate) const MENVCFG: u32 = 0x30A;
+pub(crate) const MENVCFGH: u32 = 0x31A;
+
+// Machine advanced interrupts (Smaia)
+pub(crate) const MVIEN: u32 = 0x308;
+pub(crate) const MVIP: u32 = 0x309;
+pub(crate) const MIDELEGH: u32 = 0x313;
+pub(crate) const MIEH: u32 = 0x314;
+pub(crate) const MVIENH: u32 = 0x318;
+pub(crate) const MVIPH: u32 = 0x319;
+pub(crate) const MISELECT: u32 = 0x350;
+pub(crate) const MIREG: u32 = 0x351;
+pub(crate) const MIPH: u32 = 0x354;
+pub(crate) const MTOPEI: u32 = 0x35C;
+pub(crate) const MTOPI: u32 = 0xFB0;
+
+// Unprivileged counters
+pub(crate) const TIME: u32 = 0xC01;
+pub(crate) const TIMEH: u32 = 0xC81;
+
+// Machine trap handling
+pub(crate) const MSCRATCH: u32 = 0x340;
+pub(crate) const MEPC: u32 = 0x341;
+pub(crate) const MCAUSE: u32 = 0x342;
+pub(crate) const MTVAL: u32 = 0x343;
+pub(crate) const MIP: u32 = 0x344;
+pub(crate) const MTINST: u32 = 0x34A;
+pub(crate) const MTVAL2: u32 = 0x34B;
+
+// mstatus fields
+pub(crate) const MSTATUS_SIE: u32 = 1 << 1;
+pub(crate) const MSTATUS_MIE: u32 = 1 << 3;
+pub(crate) const MSTATUS_SPIE: u32 = 1 << 5;
+pub(crate) const MSTATUS_MPIE: u32 = 1 << 7;
+pub(crate) const MSTATUS_SPP: u32 = 1 << 8;
+pub(crate) const MSTATUS_MPP: u32 = 0b11 << 11;
+pub(crate) const MSTATUS_MPRV: u32 = 1 << 17;
+pub(crate) const MSTATUS_SUM: u32 = 1 << 18;
+pub(crate) const MSTATUS_MXR: u32 = 1 << 19;
+pub(crate) const MSTATUS_TVM: u32 = 1 << 20;
+pub(crate) const MSTATUS_TW: u32 = 1 << 21;
+pub(crate) const MSTATUS_TSR: u32 = 1 << 22;
+pub(crate) const MSTATUS_MPP_SHIFT: u32 = 11;
+
+// mstatush fields: the trap came from V = 1, xtval holds a guest virtual
+// address
+pub(crate) const MSTATUSH_GVA: u32 = 1 << 6;
+pub(crate) const MSTATUSH_MPV: u32 = 1 << 7;
+
+// hstatus fields
+pub(crate) const HSTATUS_GVA: u32 = 1 << 6;
+pub(crate) const HSTATUS_SPV: u32 = 1 << 7;
+pub(crate) const HSTATUS_SPVP: u32 = 1 << 8;
+pub(crate) const HSTATUS_HU: u32 = 1 << 9;
+pub(crate) const HSTATUS_VTVM: u32 = 1 << 20;
+pub(crate) const HSTATUS_VTW: u32 = 1 << 21;
+pub(crate) const HSTATUS_VTSR: u32 = 1 << 22;
+const HSTATUS_MASK: u32 = HSTATUS_GVA
+    | HSTATUS_SPV
+    | HSTATUS_SPVP
+    | HSTATUS_HU
+    | HSTATUS_VTVM
+    | HSTATUS_VTW
+    | HSTATUS_VTSR;
+
+// the part of mstatus visible through sstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// mip / mie bits, the bit index is the interrupt cause
+pub(crate) const MIP_SSIP: u32 = 1 << 1;
+pub(crate) const MIP_MSIP: u32 = 1 << 3;
+pub(crate) const MIP_STIP: u32 = 1 << 5;
+pub(crate) const MIP_MTIP: u32 = 1 << 7;
+pub(crate) const MIP_SEIP: u32 = 1 << 9;
+pub(crate) const MIP_MEIP: u32 = 1 << 11;
+pub(crate) const MIP_VSSIP: u32 = 1 << 2;
+pub(crate) const MIP_VSTIP: u32 = 1 << 6;
+pub(crate) const MIP_VSEIP: u32 = 1 << 10;
+pub(crate) const MIP_SGEIP: u32 = 1 << 12;
+const SUPERVISOR_INTERRUPTS: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+pub(crate) const VS_INTERRUPTS: u32 = MIP_VSSIP | MIP_VSTIP | MIP_VSEIP;
+const ALL_INTERRUPTS: u32 = SUPERVISOR_INTERRUPTS | VS_INTERRUPTS | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+
+// menvcfgh.STCE (bit 63 of menvcfg) enables stimecmp
+pub(crate) const MENVCFGH_STCE: u32 = 1 << 31;
+
+// xcounteren.TM gates the time csr (and stimecmp) for lower privileges
+pub(crate) const COUNTEREN_TM: u32 = 1 << 1;
+
+// exception codes 0 - 10, 12, 13, 15 and 20 - 23, ecall from M mode is
+// never delegated
+const DELEGABLE_EXCEPTIONS: u32 = 0xF0_B7FF;
+// 20 - 23, the guest page faults and virtual instruction, need H
+const HYPERVISOR_EXCEPTIONS: u32 = 0xF0_0000;
+
+// HS mode can pass 0 - 8, 12, 13 and 15 on to VS mode
+const HYPERVISOR_DELEGABLE_EXCEPTIONS: u32 = 0xB1FF;
+
+// hgatp on RV32: MODE (Sv32x4), VMID and a 16 KiB aligned root
+const HGATP_MASK: u32 = 1 << 31 | 0x7F << 22 | 0x3F_FFFC;
+
+// misa
+const MISA_MXL_32: u32 = 1 << 30;
+const MISA_E: u32 = 1 << 4;
+const MISA_H: u32 = 1 << 7;
+const MISA_I: u32 = 1 << 8;
+const MISA_S: u32 = 1 << 18;
+const MISA_U: u32 = 1 << 20;
+
+pub(crate) struct Csr {
+    pub(crate) misa: u32,
+    pub(crate) mstatus: u32,
+    pub(crate) mstatush: u32,
+    pub(crate) mtvec: u32,
+    pub(crate) mscratch: u32,
+    pub(crate) mepc: u32,
+    pub(crate) mcause: u32,
+    pub(crate) mtval: u32,
+    pub(crate) mtval2: u32,
+    pub(crate) mtinst: u32,
+    pub(crate) medeleg: u32,
+    pub(crate) mideleg: u32,
+    pub(crate) mie: u32,
+    pub(crate) mip: u32,
+    pub(crate) stvec: u32,
+    pub(crate) sscratch: u32,
+    pub(crate) sepc: u32,
+    pub(crate) scause: u32,
+    pub(crate) stval: u32,
+    pub(crate) satp: u32,
+    pub(crate) mcounteren: u32,
+    pub(crate) scounteren: u32,
+    pub(crate) menvcfgh: u32,
+    pub(crate) stimecmp: u64,
+    pub(crate) hstatus: u32,
+    pub(crate) hedeleg: u32,
+    pub(crate) hideleg: u32,
+    pub(crate) hcounteren: u32,
+    pub(crate) htimedelta: u64,
+    pub(crate) htval: u32,
+    pub(crate) htinst: u32,
+    pub(crate) hgatp: u32,
+    pub(crate) vsstatus: u32,
+    pub(crate) vstvec: u32,
+    pub(crate) vsscratch: u32,
+    pub(crate) vsepc: u32,
+    pub(crate) vscause: u32,
+    pub(crate) vstval: u32,
+    pub(crate) vsatp: u32,
+    pub(crate) time: u64, // mirror of the platform's mtime
+    pub(crate) pmp: Pmp,
+    pub(crate) imsic: Imsic,
+    pub(crate) triggers: Triggers,
+    pub(crate) miselect: u32,
+    pub(crate) siselect: u32,
+    // external interrupt line into SEIP, reads of mip OR it with the
+    // software writable bit
+    pub(crate) seip_line: bool,
+}
+
+impl Csr {
+    pub(crate) fn new(base: BaseIsa) -> Self {
+        let misa = match base {
+            BaseIsa::Rv32i => MISA_MXL_32 | MISA_I | MISA_S | MISA_U | MISA_H,
+            // H needs the full base ISA
+            BaseIsa::Rv32e => MISA_MXL_32 | MISA_E | MISA_S | MISA_U,
+        };
+
+        Self {
+            misa,
+            mstatus: MSTATUS_MPP,
+            mstatush: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mtval2: 0,
+            mtinst: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            stvec: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            mcounteren: 0,
+            scounteren: 0,
+            menvcfgh: 0,
+            stimecmp: u64::MAX,
+            hstatus: 0,
+            hedeleg: 0,
+            hideleg: 0,
+            hcounteren: 0,
+            htimedelta: 0,
+            htval: 0,
+            htinst: 0,
+            hgatp: 0,
+            vsstatus: 0,
+            vstvec: 0,
+            vsscratch: 0,
+            vsepc: 0,
+            vscause: 0,
+            vstval: 0,
+            vsatp: 0,
+            time: 0,
+            pmp: Pmp::new(),
+            imsic: Imsic::new(),
+            triggers: Triggers::new(),
+            miselect: 0,
+            siselect: 0,
+            seip_line: false,
+        }
+    }
+
+    // None if the csr does not exist or may not be accessed from `privilege`
+    pub(crate) fn read(&self, address: u32, privilege: Privilege) -> Option<u32> {
+        if !self.accessible(address, privilege) {
+            return None;
+        }
+
+        let value = match address {
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            MSTATUS => self.mstatus,
+            MISA => self.misa,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg(),
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSTATUSH => self.mstatush,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MTVAL2 => self.mtval2,
+            MTINST => self.mtinst,
+            MIP => self.pending(),
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.pending() & self.mideleg,
+            SATP => self.satp,
+            MCOUNTEREN => self.mcounteren,
+            SCOUNTEREN => self.scounteren,
+            MENVCFG => 0,
+            MENVCFGH => self.menvcfgh,
+            STIMECMP => self.stimecmp as u32,
+            STIMECMPH => (self.stimecmp >> 32) as u32,
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            // interrupts 32 - 63 and virtual supervisor interrupts are not
+            // implemented
+            MVIEN | MVIP | MIDELEGH | MIEH | MVIENH | MVIPH | MIPH | SIEH | SIPH => 0,
+            MISELECT => self.miselect,
+            MIREG => self.imsic.machine.read_indirect(self.miselect)?,
+            MTOPEI => self.imsic.machine.topei(),
+            MTOPI => self.topi(self.pending() & self.mie & !self.mideleg()),
+            SISELECT => self.siselect,
+            SIREG => self.imsic.supervisor.read_indirect(self.siselect)?,
+            STOPEI => self.imsic.supervisor.topei(),
+            STOPI => self.topi(self.pending() & self.mie & self.mideleg),
+            HSTATUS => self.hstatus,
+            HEDELEG => self.hedeleg,
+            HIDELEG => self.hideleg,
+            HIE => self.mie & VS_INTERRUPTS,
+            HIP => self.pending() & VS_INTERRUPTS,
+            HVIP => self.mip & VS_INTERRUPTS,
+            HCOUNTEREN => self.hcounteren,
+            HTIMEDELTA => self.htimedelta as u32,
+            HTIMEDELTAH => (self.htimedelta >> 32) as u32,
+            // no guest external interrupt files (GEILEN = 0)
+            HGEIE | HGEIP | HENVCFG | HENVCFGH => 0,
+            HTVAL => self.htval,
+            HTINST => self.htinst,
+            HGATP => self.hgatp,
+            VSSTATUS => self.vsstatus,
+            // VS interrupts appear one bit lower, at the S positions
+            VSIE => (self.mie & self.hideleg) >> 1,
+            VSIP => (self.pending() & self.hideleg) >> 1,
+            VSTVEC => self.vstvec,
+            VSSCRATCH => self.vsscratch,
+            VSEPC => self.vsepc,
+            VSCAUSE => self.vscause,
+            VSTVAL => self.vstval,
+            VSATP => self.vsatp,
+            trigger::TSELECT..=trigger::TCONTROL => self.triggers.read(address)?,
+            pmp::PMPCFG0..=pmp::PMPCFG15 => self.pmp.read_cfg((address - pmp::PMPCFG0) as usize),
+            pmp::PMPADDR0..=pmp::PMPADDR63 => {
+                self.pmp.read_addr((address - pmp::PMPADDR0) as usize)
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Fields are WARL, unsupported values are silently dropped.
+    // None if the csr does not exist, is read only or may not be accessed
+    // from `privilege`.
+    pub(crate) fn write(&mut self, address: u32, value: u32, privilege: Privilege) -> Option<()> {
+        if is_read_only(address) || !self.accessible(address, privilege) {
+            return None;
+        }
+
+        match address {
+            MSTATUS => {
+                let writable = MSTATUS_SIE
+                    | MSTATUS_MIE
+                    | MSTATUS_SPIE
+                    | MSTATUS_MPIE
+                    | MSTATUS_SPP
+                    | MSTATUS_MPRV
+                    | MSTATUS_SUM
+                    | MSTATUS_MXR
+                    | MSTATUS_TVM
+                    | MSTATUS_TW
+                    | MSTATUS_TSR;
+                let mut mstatus = (self.mstatus & !writable) | (value & writable);
+                if Privilege::from_bits((value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT).is_some() {
+                    mstatus = (mstatus & !MSTATUS_MPP) | (value & MSTATUS_MPP);
+                }
+                self.mstatus = mstatus;
+            }
+            MISA => {}
+            MSTATUSH if self.hypervisor() => self.mstatush = value & (MSTATUSH_GVA | MSTATUSH_MPV),
+            MSTATUSH => {}
+            MEDELEG if self.hypervisor() => self.medeleg = value & DELEGABLE_EXCEPTIONS,
+            MEDELEG => self.medeleg = value & DELEGABLE_EXCEPTIONS & !HYPERVISOR_EXCEPTIONS,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & ALL_INTERRUPTS,
+            MTVEC => self.mtvec = write_tvec(self.mtvec, value),
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0x3, // IALIGN is 32
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MTVAL2 => self.mtval2 = value,
+            MTINST => self.mtinst = value,
+            MIP => {
+                // the machine level bits are driven by the platform, and so is
+                // STIP once stimecmp is enabled
+                let mut writable = SUPERVISOR_INTERRUPTS | MIP_VSSIP;
+                if self.stimecmp_enabled() {
+                    writable &= !MIP_STIP;
+                }
+                self.mip = (self.mip & !writable) | (value & writable)
+            }
+            SSTATUS => {
+                self.mstatus = (self.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK);
+            }
+            SIE => self.mie = (self.mie & !self.mideleg) | (value & self.mideleg),
+            STVEC => self.stvec = write_tvec(self.stvec, value),
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !0x3,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SIP => {
+                // only the software interrupt can be raised from S mode
+                let writable = self.mideleg & MIP_SSIP;
+                self.mip = (self.mip & !writable) | (value & writable);
+            }
+            SATP => self.satp = value, // Bare and Sv32 are the only modes on RV32
+            MCOUNTEREN => self.mcounteren = value & COUNTEREN_TM,
+            SCOUNTEREN => self.scounteren = value & COUNTEREN_TM,
+            MENVCFG => {}
+            MENVCFGH => self.menvcfgh = value & MENVCFGH_STCE,
+            STIMECMP => self.stimecmp = (self.stimecmp & !0xFFFF_FFFF) | value as u64,
+            STIMECMPH => self.stimecmp = (self.stimecmp & 0xFFFF_FFFF) | (value as u64) << 32,
+            MVIEN | MVIP | MIDELEGH | MIEH | MVIENH | MVIPH | MIPH | SIEH | SIPH => {}
+            MISELECT => self.miselect = value & 0xFFF,
+            MIREG => self.imsic.machine.write_indirect(self.miselect, value)?,
+            // any write claims the top interrupt, the value is ignored
+            MTOPEI => self.imsic.machine.claim(),
+            SISELECT => self.siselect = value & 0xFFF,
+            SIREG => self.imsic.supervisor.write_indirect(self.siselect, value)?,
+            STOPEI => self.imsic.supervisor.claim(),
+            HSTATUS => self.hstatus = value & HSTATUS_MASK,
+            HEDELEG => self.hedeleg = value & HYPERVISOR_DELEGABLE_EXCEPTIONS,
+            HIDELEG => self.hideleg = value & VS_INTERRUPTS,
+            HIE => self.mie = (self.mie & !VS_INTERRUPTS) | (value & VS_INTERRUPTS),
+            HIP => self.mip = (self.mip & !MIP_VSSIP) | (value & MIP_VSSIP),
+            HVIP => self.mip = (self.mip & !VS_INTERRUPTS) | (value & VS_INTERRUPTS),
+            HCOUNTEREN => self.hcounteren = value & COUNTEREN_TM,
+            HTIMEDELTA => self.htimedelta = (self.htimedelta & !0xFFFF_FFFF) | value as u64,
+            HTIMEDELTAH => self.htimedelta = (self.htimedelta & 0xFFFF_FFFF) | (value as u64) << 32,
+            HGEIE | HENVCFG | HENVCFGH => {}
+            HTVAL => self.htval = value,
+            HTINST => self.htinst = value,
+            HGATP => self.hgatp = value & HGATP_MASK,
+            VSSTATUS => self.vsstatus = (self.vsstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK),
+            VSIE => {
+                let writable = self.hideleg;
+                self.mie = (self.mie & !writable) | ((value << 1) & writable);
+            }
+            VSIP => {
+                let writable = self.hideleg & MIP_VSSIP;
+                self.mip = (self.mip & !writable) | ((value << 1) & writable);
+            }
+            VSTVEC => self.vstvec = write_tvec(self.vstvec, value),
+            VSSCRATCH => self.vsscratch = value,
+            VSEPC => self.vsepc = value & !0x3,
+            VSCAUSE => self.vscause = value,
+            VSTVAL => self.vstval = value,
+            VSATP => self.vsatp = value,
+            trigger::TSELECT..=trigger::TCONTROL => self.triggers.write(address, value, false)?,
+            pmp::PMPCFG0..=pmp::PMPCFG15 => {
+                self.pmp.write_cfg((address - pmp::PMPCFG0) as usize, value)
+            }
+            pmp::PMPADDR0..=pmp::PMPADDR63 => self
+                .pmp
+                .write_addr((address - pmp::PMPADDR0) as usize, value),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // Interrupts handled below M mode, the VS interrupts and the guest
+    // external interrupt are always delegated
+    pub(crate) fn mideleg(&self) -> u32 {
+        self.mideleg | VS_INTERRUPTS | MIP_SGEIP
+    }
+
+    // mip as seen by software and the interrupt logic
+    pub(crate) fn pending(&self) -> u32 {
+        if self.seip_line {
+            self.mip | MIP_SEIP
+        } else {
+            self.mip
+        }
+    }
+
+    // xtopi: the highest priority interrupt of `pending` in bits 27:16 and its
+    // priority in 7:0. The iprio arrays are read only zero, every interrupt
+    // has the default order and reports priority 1.
+    fn topi(&self, pending: u32) -> u32 {
+        INTERRUPT_PRIORITY
+            .into_iter()
+            .find(|interrupt| pending & (1 << *interrupt as u32) != 0)
+            .map_or(0, |interrupt| (interrupt as u32) << 16 | 1)
+    }
+
+    pub(crate) fn stimecmp_enabled(&self) -> bool {
+        self.menvcfgh & MENVCFGH_STCE != 0
+    }
+
+    // Sstc: the supervisor timer fires while time >= stimecmp
+    pub(crate) fn supervisor_timer_pending(&self) -> bool {
+        self.time >= self.stimecmp
+    }
+
+    // The hypervisor extension is in misa
+    pub(crate) fn hypervisor(&self) -> bool {
+        self.misa & MISA_H != 0
+    }
+
+    // csr[9:8] is the lowest privilege that can access a csr, hypervisor and
+    // VS csrs (2) belong to HS mode and only exist with H. On top of that satp
+    // and hgatp are trapped from S mode by mstatus.TVM, time by the counter
+    // enables and stimecmp by menvcfg.STCE and mcounteren.TM.
+    fn accessible(&self, address: u32, privilege: Privilege) -> bool {
+        let level = match (address >> 8) & 0x3 {
+            2 if !self.hypervisor() => return false,
+            2 => Privilege::Supervisor as u32,
+            level => level,
+        };
+        if level > privilege as u32 {
+            return false;
+        }
+        match address {
+            MTVAL2 | MTINST => self.hypervisor(),
+            SATP | HGATP => {
+                !(privilege == Privilege::Supervisor && self.mstatus & MSTATUS_TVM != 0)
+            }
+            TIME | TIMEH => match privilege {
+                Privilege::Machine => true,
+                Privilege::Supervisor => self.mcounteren & COUNTEREN_TM != 0,
+                Privilege::User => self.mcounteren & self.scounteren & COUNTEREN_TM != 0,
+            },
+            STIMECMP | STIMECMPH => {
+                privilege == Privilege::Machine
+                    || (self.stimecmp_enabled() && self.mcounteren & COUNTEREN_TM != 0)
+            }
+            _ => true,
+        }
+    }
+}
+
+// only direct (0) and vectored (1) modes exist
+fn write_tvec(old: u32, value: u32) -> u32 {
+    if value & 0x3 < 2 {
+        value
+    } else {
+        (value & !0x3) | (old & 0x3)
+    }
+}
+
+// csr[11:10] == 0b11 marks a read only register
+pub(crate) fn is_read_only(address: u32) -> bool {
+    (address >> 10) & 0x3 == 0x3
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        instruction::into_byte,
+        registers::BaseIsa,
+        tests::vm_with_program,
+        trap::{Exception, Interrupt},
+        Vm,
+    };
+
+    use super::{
+        Privilege, HSTATUS, MCOUNTEREN, MEDELEG, MENVCFGH, MENVCFGH_STCE, MIP, MIP_STIP, MISA,
+        MISA_H, MSTATUSH, MSTATUSH_MPV, MSTATUS_SIE, MTVAL2, STIMECMP, STIMECMPH, TIME, VSATP,
+    };
+
+    #[test]
+    fn test_sstc_gates() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        let s = Privilege::Supervisor;
+
+        // STCE clear: S mode cannot touch stimecmp, M mode drives STIP
+        assert_eq!(vm.csr.read(STIMECMP, s), None);
+        vm.csr.write(MIP, MIP_STIP, Privilege::Machine).unwrap();
+        assert_eq!(vm.csr.mip & MIP_STIP, MIP_STIP);
+
+        vm.csr
+            .write(MENVCFGH, MENVCFGH_STCE, Privilege::Machine)
+            .unwrap();
+        assert_eq!(vm.csr.read(STIMECMP, s), None); // mcounteren.TM still clear
+        assert_eq!(vm.csr.read(TIME, s), None);
+        vm.csr
+            .write(MCOUNTEREN, 1 << 1, Privilege::Machine)
+            .unwrap();
+        vm.csr.write(STIMECMP, 100, s).unwrap();
+        vm.csr.write(STIMECMPH, 0, s).unwrap();
+        assert_eq!(vm.csr.stimecmp, 100);
+
+        // STIP now follows the comparator and ignores software writes
+        vm.step();
+        assert_eq!(vm.csr.mip & MIP_STIP, 0);
+        vm.csr.write(MIP, MIP_STIP, Privilege::Machine).unwrap();
+        assert_eq!(vm.csr.mip & MIP_STIP, 0);
+
+        vm.clint.mtime = 99;
+        vm.step();
+        assert_eq!(vm.csr.read(TIME, s), Some(100));
+        assert_eq!(vm.csr.mip & MIP_STIP, MIP_STIP);
+    }
+
+    #[test]
+    fn test_supervisor_timer_interrupt() {
+        let mut vm = vm_with_program(&[0x10500073]); // wfi
+        vm.csr
+            .write(MENVCFGH, MENVCFGH_STCE, Privilege::Machine)
+            .unwrap();
+        vm.csr.stimecmp = 5000;
+        vm.csr.mideleg = MIP_STIP;
+        vm.csr.mie = MIP_STIP;
+        vm.csr.mstatus |= MSTATUS_SIE;
+        vm.csr.stvec = 0x200;
+        vm.privilege = Privilege::Supervisor;
+
+        // wfi, fast forward to stimecmp, then the interrupt
+        vm.run(3);
+        assert_eq!(vm.clint.mtime, 5001);
+        assert_eq!(vm.csr.scause, 1 << 31 | Interrupt::SupervisorTimer as u32);
+        assert_eq!(vm.csr.sepc, 4);
+    }
+
+    #[test]
+    fn test_no_hypervisor_on_rv32e() {
+        let mut vm = Vm::with_base(BaseIsa::Rv32e);
+        let m = Privilege::Machine;
+        assert_eq!(vm.csr.read(MISA, m).unwrap() & MISA_H, 0);
+        for address in [HSTATUS, VSATP, MTVAL2] {
+            assert_eq!(vm.csr.read(address, m), None);
+        }
+        vm.csr.write(MSTATUSH, MSTATUSH_MPV, m).unwrap();
+        assert_eq!(vm.csr.mstatush, 0);
+        vm.csr.write(MEDELEG, !0, m).unwrap();
+        assert_eq!(vm.csr.medeleg, 0xB7FF);
+
+        // hfence.gvma x0, x0
+        vm.mem_write(4, 0, &into_byte(0x62000073)).unwrap();
+        assert_eq!(
+            vm.run_program(),
+            Err(Exception::IllegalInstruction(0x62000073))
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// Peripherals written outside the crate. A device is attached at a range of
+// physical addresses and sees accesses as offsets from its base; it can also
+// follow mtime and drive an interrupt source of the PLIC and APLIC.
+use super::{
+    bus::{MapError, Mmio, Region, RegionKind},
+    Vm,
+};
+
+pub trait Device {
+    // Register reads and writes of 1, 2, 4 or 8 bytes, the value in the low
+    // bytes. None makes the access fault.
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64>;
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()>;
+
+    // Called before every instruction with the current mtime
+    fn tick(&mut self, _time: u64) {}
+
+    // Level of the device's interrupt line
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    // The next mtime the device has something to do at, wfi does not skip
+    // time past it
+    fn deadline(&self) -> Option<u64> {
+        None
+    }
+
+    // Set when the device wants the VM stopped, Vm::run returns it
+    fn exit(&self) -> Option<Exit> {
+        None
+    }
+
+    // How the device shows up in the generated device tree, devices without
+    // a node are left out
+    fn device_node(&self) -> Option<DeviceNode> {
+        None
+    }
+}
+
+// A device tree node for an attached device. reg, interrupts and
+// interrupt-parent are filled in from where it is attached.
+pub struct DeviceNode {
+    pub name: &'static str, // node name before the unit address
+    pub compatible: &'static [&'static str],
+    pub properties: Vec<(&'static str, u32)>,
+}
+
+// Why a guest stopped the VM
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Exit {
+    Pass,
+    Fail(u16), // exit code
+    Reset,
+}
+
+pub(crate) struct Attached {
+    pub(crate) device: Box<dyn Device>,
+    pub(crate) source: Option<usize>, // interrupt source on the PLIC and APLIC
+}
+
+impl Vm {
+    // Maps `device` at base..base + size, its line (if any) drives `source`
+    pub fn attach(
+        &mut self,
+        name: &str,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+        source: Option<usize>,
+    ) -> Result<(), MapError> {
+        if source.is_some_and(|source| source == 0 || source > self.plic.sources()) {
+            return Err(MapError::Source(name.to_string()));
+        }
+        let index = self.devices.len();
+        let region = Region::new(name, base, size, RegionKind::Mmio(Mmio::Device(index)));
+        self.bus.add(region)?;
+        self.devices.push(Attached { device, source });
+        Ok(())
+    }
+
+    pub(crate) fn device_read(&mut self, index: usize, offset: u64, size: usize) -> Option<u32> {
+        let value = self.devices[index].device.read(offset, size)?;
+        Some(value as u32)
+    }
+
+    pub(crate) fn device_write(
+        &mut self,
+        index: usize,
+        offset: u64,
+        size: usize,
+        value: u32,
+    ) -> Option<()> {
+        let device = &mut self.devices[index].device;
+        device.write(offset, size, value as u64)?;
+        self.exit = self.exit.or(device.exit());
+        Some(())
+    }
+
+    // Advances every device to mtime and samples the interrupt lines
+    pub(crate) fn tick_devices(&mut self) {
+        let time = self.clint.mtime;
+        for index in 0..self.devices.len() {
+            let attached = &mut self.devices[index];
+            attached.device.tick(time);
+            self.exit = self.exit.or(attached.device.exit());
+            if let Some(source) = attached.source {
+                let level = attached.device.interrupt();
+                self.set_interrupt_line(source, level);
+            }
+        }
+    }
+
+    pub(crate) fn device_deadline(&self) -> Option<u64> {
+        self.devices
+            .iter()
+            .filter_map(|attached| attached.device.deadline())
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::vm::{
+        bus::{Bus, MapError, DRAM_BASE},
+        csr::{self, Privilege},
+        registers::BaseIsa,
+        tests::allow_all_memory,
+        trap::Exception,
+        Vm, BYTE, WORD_SIZE,
+    };
+
+    use super::Device;
+
+    const TIMER: u64 = 0x1000_0000;
+
+    // Counts down from the value written to it, then raises its line until
+    // written again
+    struct CountdownTimer {
+        deadline: Option<u64>,
+        time: u64,
+        fired: bool,
+        writes: Rc<Cell<u32>>,
+    }
+
+    impl Device for CountdownTimer {
+        fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+            match (offset, size) {
+                (0, 4) => Some(self.deadline.map_or(0, |deadline| deadline - self.time)),
+                _ => None,
+            }
+        }
+
+        fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+            if (offset, size) != (0, 4) {
+                return None;
+            }
+            self.writes.set(self.writes.get() + 1);
+            self.deadline = Some(self.time + value);
+            self.fired = false;
+            Some(())
+        }
+
+        fn tick(&mut self, time: u64) {
+            self.time = time;
+            if self.deadline.is_some_and(|deadline| time >= deadline) {
+                self.deadline = None;
+                self.fired = true;
+            }
+        }
+
+        fn interrupt(&self) -> bool {
+            self.fired
+        }
+
+        fn deadline(&self) -> Option<u64> {
+            self.deadline
+        }
+    }
+
+    #[test]
+    fn test_attached_device() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        allow_all_memory(&mut vm);
+        let writes = Rc::new(Cell::new(0));
+        let timer = CountdownTimer {
+            deadline: None,
+            time: 0,
+            fired: false,
+            writes: writes.clone(),
+        };
+        vm.attach("timer", TIMER, 0x100, Box::new(timer), Some(5))
+            .unwrap();
+
+        assert_eq!(
+            vm.attach("ram", DRAM_BASE, 0x100, Box::new(Null), None),
+            Err(MapError::Overlap("ram".to_string(), "ram".to_string()))
+        );
+        assert_eq!(
+            vm.attach("null", 0x2000_0000, 0x100, Box::new(Null), Some(0)),
+            Err(MapError::Source("null".to_string()))
+        );
+        assert_eq!(
+            vm.attach("null", 0x2000_0000, 0x100, Box::new(Null), Some(40)),
+            Err(MapError::Source("null".to_string()))
+        );
+
+        vm.store(WORD_SIZE, TIMER as u32, 100).unwrap();
+        assert_eq!(writes.get(), 1);
+        assert_eq!(
+            vm.load(BYTE, TIMER as u32),
+            Err(Exception::LoadAccessFault(TIMER as u32))
+        );
+
+        // the hart sleeps in wfi until the device's deadline
+        vm.store(WORD_SIZE, 0xC00_0000 + 5 * 4, 1).unwrap(); // priority[5]
+        vm.store(WORD_SIZE, 0xC00_2000, 1 << 5).unwrap(); // M context enable
+        vm.csr.mie = csr::MIP_MEIP;
+        vm.waiting = true;
+        vm.step();
+        assert_eq!(vm.clint.mtime, 100);
+        vm.step();
+        assert_ne!(
+            vm.csr.read(csr::MIP, Privilege::Machine).unwrap() & csr::MIP_MEIP,
+            0
+        );
+        assert!(!vm.waiting);
+    }
+
+    struct Null;
+
+    impl Device for Null {
+        fn read(&mut self, _offset: u64, _size: usize) -> Option<u64> {
+            Some(0)
+        }
+
+        fn write(&mut self, _offset: u64, _size: usize, _value: u64) -> Option<()> {
+            Some(())
+        }
+    }
+
+    #[test]
+    fn test_interrupt_source_count() {
+        assert_eq!(
+            Vm::with_sources(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap(), 0).err(),
+            Some(MapError::Sources(0))
+        );
+        let mut vm = Vm::with_sources(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap(), 64).unwrap();
+        vm.attach("null", 0x2000_0000, 0x100, Box::new(Null), Some(40))
+            .unwrap();
+        // priority[40] exists, priority[64] is the last one
+        vm.store(WORD_SIZE, 0xC00_0000 + 40 * 4, 3).unwrap();
+        assert_eq!(vm.load(WORD_SIZE, 0xC00_0000 + 40 * 4), Ok(3));
+        assert_eq!(vm.load(WORD_SIZE, 0xC00_0000 + 64 * 4), Ok(0));
+        assert_eq!(
+            vm.load(WORD_SIZE, 0xC00_0000 + 65 * 4),
+            Err(Exception::LoadAccessFault(0xC00_0000 + 65 * 4))
+        );
+    }
+}
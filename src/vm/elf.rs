@@ -0,0 +1,121 @@
+// ELF32 executables for RISC-V: the loadable segments, the entry point and
+// the symbol table, which is where HTIF programs name tohost and fromhost.
+use std::{collections::HashMap, io};
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_32: u8 = 1;
+const DATA_LE: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+pub(crate) struct Segment {
+    pub(crate) address: u64,
+    pub(crate) data: Vec<u8>, // zero filled up to the size in memory
+}
+
+pub(crate) struct Elf {
+    pub(crate) entry: u32,
+    pub(crate) segments: Vec<Segment>,
+    symbols: HashMap<String, u32>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn is_elf(file: &[u8]) -> bool {
+    file.starts_with(&MAGIC)
+}
+
+// Little endian fields, out of range reads are a truncated file
+fn half(file: &[u8], offset: usize) -> io::Result<u16> {
+    let bytes = file
+        .get(offset..offset + 2)
+        .ok_or_else(|| invalid("truncated elf"))?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn word(file: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = file
+        .get(offset..offset + 4)
+        .ok_or_else(|| invalid("truncated elf"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn bytes(file: &[u8], offset: u32, size: u32) -> io::Result<&[u8]> {
+    let start = offset as usize;
+    file.get(start..start + size as usize)
+        .ok_or_else(|| invalid("truncated elf"))
+}
+
+impl Elf {
+    pub(crate) fn parse(file: &[u8]) -> io::Result<Self> {
+        if !is_elf(file) || file.len() < 52 {
+            return Err(invalid("not an elf file"));
+        }
+        if file[4] != CLASS_32 || file[5] != DATA_LE || half(file, 18)? != MACHINE_RISCV {
+            return Err(invalid("not a little endian rv32 elf"));
+        }
+        let entry = word(file, 24)?;
+
+        let program_headers = word(file, 28)? as usize;
+        let mut segments = vec![];
+        for index in 0..half(file, 44)? as usize {
+            let header = program_headers + index * PROGRAM_HEADER_SIZE;
+            if word(file, header)? != PT_LOAD {
+                continue;
+            }
+            let (offset, address) = (word(file, header + 4)?, word(file, header + 12)?);
+            let (file_size, memory_size) = (word(file, header + 16)?, word(file, header + 20)?);
+            if file_size > memory_size {
+                return Err(invalid("segment larger in the file than in memory"));
+            }
+            let mut data = bytes(file, offset, file_size)?.to_vec();
+            data.resize(memory_size as usize, 0);
+            segments.push(Segment {
+                address: address as u64,
+                data,
+            });
+        }
+
+        // a stripped file has no symbols, which is not an error
+        let section_headers = word(file, 32)? as usize;
+        let section = |index: usize| section_headers + index * SECTION_HEADER_SIZE;
+        let mut symbols = HashMap::new();
+        for index in 0..half(file, 48)? as usize {
+            if word(file, section(index) + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let table = bytes(
+                file,
+                word(file, section(index) + 16)?,
+                word(file, section(index) + 20)?,
+            )?;
+            let strings = section(word(file, section(index) + 24)? as usize);
+            let strings = bytes(file, word(file, strings + 16)?, word(file, strings + 20)?)?;
+            for symbol in table.chunks_exact(SYMBOL_SIZE) {
+                let name = &strings[(word(symbol, 0)? as usize).min(strings.len())..];
+                let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(0)];
+                if !name.is_empty() {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    symbols.insert(name, word(symbol, 4)?);
+                }
+            }
+        }
+        Ok(Self {
+            entry,
+            segments,
+            symbols,
+        })
+    }
+
+    pub(crate) fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+}
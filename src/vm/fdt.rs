@@ -0,0 +1,409 @@
+// Flattened device tree for the machine as configured: the hart, RAM, the
+// CLINT and PLIC, and every attached device that describes itself. Booting
+// follows the Linux convention, a0 holds the hart id and a1 the blob.
+use std::{collections::HashMap, io};
+
+use super::{
+    bus::{Mmio, RegionKind},
+    clint::TIMEBASE_FREQUENCY,
+    Vm,
+};
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+// local interrupt numbers on the cpu's controller
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+const A0: u32 = 10;
+const A1: u32 = 11;
+
+// Builds the structure block and strings block of a blob
+#[derive(Default)]
+struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    names: HashMap<String, u32>, // offsets in strings
+}
+
+impl Fdt {
+    fn token(&mut self, token: u32) {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    // names and values are padded to 4 bytes
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.structure.extend(bytes);
+        let padding = self.structure.len().next_multiple_of(4) - self.structure.len();
+        self.structure.extend(vec![0; padding]);
+    }
+
+    fn begin(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.bytes(format!("{}\0", name).as_bytes());
+    }
+
+    fn end(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let offset = *self.names.entry(name.to_string()).or_insert_with(|| {
+            let offset = strings.len() as u32;
+            strings.extend(name.as_bytes());
+            strings.push(0);
+            offset
+        });
+        self.token(FDT_PROP);
+        self.structure.extend((value.len() as u32).to_be_bytes());
+        self.structure.extend(offset.to_be_bytes());
+        self.bytes(value);
+    }
+
+    fn empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    fn cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    fn string(&mut self, name: &str, value: &str) {
+        self.strings_list(name, &[value]);
+    }
+
+    fn strings_list(&mut self, name: &str, values: &[&str]) {
+        let value: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.bytes().chain([0]))
+            .collect();
+        self.property(name, &value);
+    }
+
+    // base and size with two cells each
+    fn reg(&mut self, base: u64, size: u64) {
+        let cells = [base >> 32, base, size >> 32, size].map(|cell| cell as u32);
+        self.cells("reg", &cells);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // an empty memory reservation block right after the header
+        let reservations = HEADER_SIZE;
+        let structure = reservations + 16;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            reservations as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            0, // boot cpu
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.extend([0; 16]);
+        blob.extend(self.structure);
+        blob.extend(self.strings);
+        blob
+    }
+}
+
+impl Vm {
+    // "rv32" and the single letter extensions in misa, then the others
+    fn isa_string(&self) -> String {
+        let mut isa = "rv32".to_string();
+        for letter in "ieh".chars() {
+            if self.csr.misa & 1 << (letter as u8 - b'a') != 0 {
+                isa.push(letter);
+            }
+        }
+        isa + "_zicsr_sstc"
+    }
+
+    // The blob for the machine as it is now, with `bootargs` for the kernel
+    pub fn device_tree(&self, bootargs: Option<&str>) -> Vec<u8> {
+        let mut fdt = Fdt::default();
+        fdt.begin("");
+        fdt.cells("#address-cells", &[2]);
+        fdt.cells("#size-cells", &[2]);
+        fdt.string("compatible", "riscv-virtio");
+        fdt.string("model", "riscv_vm,virt");
+
+        fdt.begin("cpus");
+        fdt.cells("#address-cells", &[1]);
+        fdt.cells("#size-cells", &[0]);
+        fdt.cells("timebase-frequency", &[TIMEBASE_FREQUENCY as u32]);
+        fdt.begin("cpu@0");
+        fdt.string("device_type", "cpu");
+        fdt.cells("reg", &[0]);
+        fdt.string("status", "okay");
+        fdt.string("compatible", "riscv");
+        fdt.string("riscv,isa", &self.isa_string());
+        fdt.string("mmu-type", "riscv,sv32");
+        fdt.begin("interrupt-controller");
+        fdt.cells("#interrupt-cells", &[1]);
+        fdt.empty("interrupt-controller");
+        fdt.string("compatible", "riscv,cpu-intc");
+        fdt.cells("phandle", &[CPU_INTC_PHANDLE]);
+        fdt.end();
+        fdt.end();
+        fdt.end();
+
+        let regions = self.bus.regions();
+        for region in regions
+            .iter()
+            .filter(|region| region.kind == RegionKind::Ram)
+        {
+            fdt.begin(&format!("memory@{:x}", region.base));
+            fdt.string("device_type", "memory");
+            fdt.reg(region.base, region.size);
+            fdt.end();
+        }
+
+        let mut stdout = None;
+        fdt.begin("soc");
+        fdt.cells("#address-cells", &[2]);
+        fdt.cells("#size-cells", &[2]);
+        fdt.string("compatible", "simple-bus");
+        fdt.empty("ranges");
+        for region in regions {
+            let RegionKind::Mmio(device) = region.kind else {
+                continue;
+            };
+            // the APLIC and IMSICs are left out, guests get the PLIC
+            let (name, source) = match device {
+                Mmio::Clint => {
+                    fdt.begin(&format!("clint@{:x}", region.base));
+                    fdt.strings_list("compatible", &["sifive,clint0", "riscv,clint0"]);
+                    let interrupts = [CPU_INTC_PHANDLE, IRQ_M_SOFT, CPU_INTC_PHANDLE, IRQ_M_TIMER];
+                    fdt.cells("interrupts-extended", &interrupts);
+                    ("clint", None)
+                }
+                Mmio::Plic => {
+                    fdt.begin(&format!("plic@{:x}", region.base));
+                    fdt.strings_list("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                    fdt.cells("#address-cells", &[0]);
+                    fdt.cells("#interrupt-cells", &[1]);
+                    fdt.empty("interrupt-controller");
+                    // context 0 is M mode, context 1 S mode
+                    let interrupts = [CPU_INTC_PHANDLE, IRQ_M_EXT, CPU_INTC_PHANDLE, IRQ_S_EXT];
+                    fdt.cells("interrupts-extended", &interrupts);
+                    fdt.cells("riscv,ndev", &[self.plic.sources() as u32]);
+                    fdt.cells("phandle", &[PLIC_PHANDLE]);
+                    ("plic", None)
+                }
+                Mmio::Device(index) => {
+                    let attached = &self.devices[index];
+                    let Some(node) = attached.device.device_node() else {
+                        continue;
+                    };
+                    fdt.begin(&format!("{}@{:x}", node.name, region.base));
+                    fdt.strings_list("compatible", node.compatible);
+                    for (name, value) in &node.properties {
+                        fdt.cells(name, &[*value]);
+                    }
+                    (node.name, attached.source)
+                }
+                Mmio::Virtio(index) => {
+                    fdt.begin(&format!("virtio_mmio@{:x}", region.base));
+                    fdt.string("compatible", "virtio,mmio");
+                    ("virtio_mmio", Some(self.virtio[index].source))
+                }
+                Mmio::Aplic | Mmio::ImsicMachine | Mmio::ImsicSupervisor => continue,
+            };
+            fdt.reg(region.base, region.size);
+            if let Some(source) = source {
+                fdt.cells("interrupts", &[source as u32]);
+                fdt.cells("interrupt-parent", &[PLIC_PHANDLE]);
+            }
+            fdt.end();
+            if name == "serial" && stdout.is_none() {
+                stdout = Some(format!("/soc/serial@{:x}", region.base));
+            }
+        }
+        if let Some(framebuffer) = &self.framebuffer {
+            fdt.begin(&format!("framebuffer@{:x}", framebuffer.base));
+            fdt.string("compatible", "simple-framebuffer");
+            let size = framebuffer.stride as u64 * framebuffer.height as u64;
+            fdt.reg(framebuffer.base, size);
+            fdt.cells("width", &[framebuffer.width]);
+            fdt.cells("height", &[framebuffer.height]);
+            fdt.cells("stride", &[framebuffer.stride]);
+            fdt.string("format", framebuffer.format.name());
+            fdt.end();
+        }
+        fdt.end();
+
+        fdt.begin("chosen");
+        if let Some(bootargs) = bootargs {
+            fdt.string("bootargs", bootargs);
+        }
+        if let Some(stdout) = &stdout {
+            fdt.string("stdout-path", stdout);
+        }
+        fdt.end();
+        fdt.end();
+        fdt.finish()
+    }
+
+    // Places the blob at the top of the first RAM region and points a1 at it,
+    // a0 gets the hart id. Returns where it went.
+    pub fn load_device_tree(&mut self, bootargs: Option<&str>) -> io::Result<u64> {
+        let blob = self.device_tree(bootargs);
+        let (base, ram_size) = self
+            .bus
+            .regions()
+            .iter()
+            .find(|region| region.kind == RegionKind::Ram)
+            .map(|region| (region.base, region.size))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no ram for the device tree")
+            })?;
+        let size = (blob.len() as u64).next_multiple_of(0x1000);
+        if size > ram_size || base + ram_size > 1 << 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "device tree does not fit in ram",
+            ));
+        }
+        let address = base + ram_size - size;
+        self.bus.write(address, &blob).unwrap();
+        self.set_register(A0, 0);
+        self.set_register(A1, address as u32);
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        bus::{Bus, DRAM_BASE},
+        registers::BaseIsa,
+        uart::{BufferSerial, Uart, UART_BASE, UART_SIZE, UART_SOURCE},
+        virtio::rng::VirtioRng,
+        Vm,
+    };
+
+    use super::*;
+
+    fn word(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    // a node's path and its properties
+    type Node = (String, Vec<(String, Vec<u8>)>);
+
+    // The nodes of a blob, read back
+    fn parse(blob: &[u8]) -> Vec<Node> {
+        let (structure, strings) = (word(blob, 8) as usize, word(blob, 12) as usize);
+        let string = |offset: usize| {
+            let end = blob[offset..].iter().position(|byte| *byte == 0).unwrap();
+            String::from_utf8(blob[offset..offset + end].to_vec()).unwrap()
+        };
+        let (mut nodes, mut path) = (vec![], vec![]);
+        let mut offset = structure;
+        loop {
+            let token = word(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = string(offset);
+                    offset += (name.len() + 1).next_multiple_of(4);
+                    path.push(name);
+                    nodes.push((path.join("/"), vec![]));
+                }
+                FDT_END_NODE => {
+                    path.pop();
+                }
+                FDT_PROP => {
+                    let length = word(blob, offset) as usize;
+                    let name = string(strings + word(blob, offset + 4) as usize);
+                    let value = blob[offset + 8..offset + 8 + length].to_vec();
+                    offset += 8 + length.next_multiple_of(4);
+                    let node: &mut Node = nodes.last_mut().unwrap();
+                    node.1.push((name, value));
+                }
+                _ => return nodes,
+            }
+        }
+    }
+
+    fn property(nodes: &[Node], node: &str, name: &str) -> Vec<u8> {
+        let (_, properties) = nodes.iter().find(|(path, _)| path == node).unwrap();
+        let (_, value) = properties.iter().find(|(key, _)| key == name).unwrap();
+        value.clone()
+    }
+
+    #[test]
+    fn test_device_tree() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x10_0000).unwrap());
+        let uart = Box::new(Uart::new(Box::new(BufferSerial::new())));
+        vm.attach("uart", UART_BASE, UART_SIZE, uart, Some(UART_SOURCE))
+            .unwrap();
+        vm.add_virtio("rng", Box::new(VirtioRng::seeded(0)))
+            .unwrap();
+
+        let address = vm.load_device_tree(Some("console=ttyS0")).unwrap();
+        assert_eq!(address, DRAM_BASE + 0x10_0000 - 0x1000);
+        assert_eq!(vm.get_register(A1), address as u32);
+        let blob = vm.bus.read(address, 0x1000).unwrap().to_vec();
+        assert_eq!(word(&blob, 0), FDT_MAGIC);
+
+        let nodes = parse(&blob);
+        let paths: Vec<&str> = nodes.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "",
+                "/cpus",
+                "/cpus/cpu@0",
+                "/cpus/cpu@0/interrupt-controller",
+                "/memory@80000000",
+                "/soc",
+                "/soc/clint@2000000",
+                "/soc/plic@c000000",
+                "/soc/serial@10000000",
+                "/soc/virtio_mmio@10001000",
+                "/chosen",
+            ]
+        );
+        assert_eq!(
+            property(&nodes, "/cpus/cpu@0", "riscv,isa"),
+            b"rv32ih_zicsr_sstc\0"
+        );
+        assert_eq!(
+            property(&nodes, "/memory@80000000", "reg"),
+            [0, 0x8000_0000u32, 0, 0x10_0000]
+                .map(u32::to_be_bytes)
+                .concat()
+        );
+        assert_eq!(
+            property(&nodes, "/soc/virtio_mmio@10001000", "interrupts"),
+            1u32.to_be_bytes()
+        );
+        assert_eq!(
+            property(&nodes, "/chosen", "stdout-path"),
+            b"/soc/serial@10000000\0"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// The SiFive test finisher of QEMU virt: a guest writes pass, fail with a
+// code, or reset, and the VM stops so the runner can exit with the result.
+use super::device::{Device, DeviceNode, Exit};
+
+pub const FINISHER_BASE: u64 = 0x10_0000;
+pub const FINISHER_SIZE: u64 = 0x1000;
+
+// low half of the value written, a fail code goes in the high half
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Default)]
+pub struct TestFinisher {
+    exit: Option<Exit>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for TestFinisher {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        match (offset, size) {
+            (0, 4) => Some(0),
+            _ => None,
+        }
+    }
+
+    // any other status is ignored
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if (offset, size) != (0, 4) {
+            return None;
+        }
+        let value = value as u32;
+        let exit = match value & 0xFFFF {
+            FINISHER_FAIL => Exit::Fail((value >> 16) as u16),
+            FINISHER_PASS => Exit::Pass,
+            FINISHER_RESET => Exit::Reset,
+            _ => return Some(()),
+        };
+        self.exit = self.exit.or(Some(exit));
+        Some(())
+    }
+
+    fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    fn device_node(&self) -> Option<DeviceNode> {
+        Some(DeviceNode {
+            name: "test",
+            compatible: &["sifive,test1", "sifive,test0", "syscon"],
+            properties: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        bus::{Bus, DRAM_BASE},
+        registers::{BaseIsa, Registers},
+        tests::allow_all_memory,
+        Vm, WORD_SIZE,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_finisher_stops_run() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        allow_all_memory(&mut vm);
+        vm.attach(
+            "test",
+            FINISHER_BASE,
+            FINISHER_SIZE,
+            Box::new(TestFinisher::new()),
+            None,
+        )
+        .unwrap();
+        // lui x5,0x100; lui x6,0x2a3; addi x6,x6,0x333; sw x6,0(x5); j .
+        let program: [u32; 5] = [0x001002B7, 0x002A3337, 0x33330313, 0x0062A023, 0x0000006F];
+        for (index, instruction) in program.iter().enumerate() {
+            let address = DRAM_BASE + 4 * index as u64;
+            vm.bus.write(address, &instruction.to_le_bytes()).unwrap();
+        }
+        vm.set_register(Registers::Pc as u32, DRAM_BASE as u32);
+
+        assert_eq!(vm.run(100), Some(Exit::Fail(0x2A)));
+        assert_eq!(vm.get_register(Registers::Pc as u32), DRAM_BASE as u32 + 16);
+        // stays stopped, and other values are ignored
+        assert_eq!(vm.run(100), Some(Exit::Fail(0x2A)));
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, WORD_SIZE, 0x1234).unwrap();
+        assert_eq!(finisher.exit(), None);
+    }
+}
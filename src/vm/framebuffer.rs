@@ -0,0 +1,301 @@
+// A simple-framebuffer: a block of guest memory laid out as pixels, which the
+// guest draws into and the host saves as PNG images. Nothing is displayed,
+// frames are only counted (60 per second of mtime) so they can be dumped
+// every N of them.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    bus::{MapError, Region, RegionKind},
+    clint::TIMEBASE_FREQUENCY,
+    Vm,
+};
+
+pub const FRAMEBUFFER_BASE: u64 = 0x5000_0000;
+
+const FRAME_TICKS: u64 = TIMEBASE_FREQUENCY / 60;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const STORED_BLOCK: usize = 0xFFFF; // largest uncompressed deflate block
+
+// Pixels are little endian words, the names follow the device tree binding
+// and list the channels from the most significant bits down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    R5G6B5,
+    X8R8G8B8,
+    A8R8G8B8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    fn bytes(self) -> u32 {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            _ => 4,
+        }
+    }
+
+    // Red, green and blue of one pixel, alpha is dropped
+    fn rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (red, green, blue) = (value >> 11, (value >> 5) & 0x3F, value & 0x1F);
+                // widen by repeating the top bits, so full intensity stays 0xFF
+                [
+                    (red << 3 | red >> 2) as u8,
+                    (green << 2 | green >> 4) as u8,
+                    (blue << 3 | blue >> 2) as u8,
+                ]
+            }
+            PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+            PixelFormat::A8B8G8R8 => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+pub(crate) struct Framebuffer {
+    pub(crate) base: u64,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) stride: u32, // bytes per line
+    pub(crate) format: PixelFormat,
+    frame: u64,                    // frames shown so far
+    dumps: Option<(PathBuf, u64)>, // directory, and every how many frames
+}
+
+impl Vm {
+    // Maps a `width` by `height` framebuffer at `base`. A VM has at most one.
+    pub fn attach_framebuffer(
+        &mut self,
+        base: u64,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    ) -> Result<(), MapError> {
+        let name = "framebuffer";
+        if self.framebuffer.is_some() {
+            return Err(MapError::Overlap(name.to_string(), name.to_string()));
+        }
+        let stride = width
+            .checked_mul(format.bytes())
+            .ok_or_else(|| MapError::Overflow(name.to_string()))?;
+        let size = (stride as u64 * height as u64).next_multiple_of(0x1000);
+        self.bus
+            .add(Region::new(name, base, size, RegionKind::Vram))?;
+        self.framebuffer = Some(Framebuffer {
+            base,
+            width,
+            height,
+            stride,
+            format,
+            frame: 0,
+            dumps: None,
+        });
+        Ok(())
+    }
+
+    // Saves every `every`th frame to `directory` as frame-NNNNNN.png. Dumping
+    // stops at the first image that cannot be written.
+    pub fn dump_frames(&mut self, directory: &str, every: u64) {
+        if let Some(framebuffer) = &mut self.framebuffer {
+            framebuffer.dumps = Some((PathBuf::from(directory), every.max(1)));
+        }
+    }
+
+    // Frames shown since the start, none without a framebuffer
+    pub fn frames(&self) -> Option<u64> {
+        self.framebuffer
+            .as_ref()
+            .map(|framebuffer| framebuffer.frame)
+    }
+
+    // Saves what the framebuffer shows now as a PNG image
+    pub fn save_frame(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let framebuffer = self
+            .framebuffer
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no framebuffer to save"))?;
+        let (width, height) = (framebuffer.width as usize, framebuffer.height as usize);
+        let stride = framebuffer.stride as usize;
+        let pixels = self.bus.read(framebuffer.base, stride * height).unwrap();
+        let bytes = framebuffer.format.bytes() as usize;
+        let mut image = Vec::with_capacity((3 * width + 1) * height);
+        for line in pixels.chunks(stride) {
+            image.push(0); // no filter
+            for pixel in line.chunks(bytes).take(width) {
+                image.extend(framebuffer.format.rgb(pixel));
+            }
+        }
+        fs::write(path, png(framebuffer.width, framebuffer.height, &image))
+    }
+
+    // Counts frames as mtime passes and dumps the ones asked for. mtime can
+    // skip ahead in wfi, so the dump is due whenever a multiple was passed.
+    pub(crate) fn tick_framebuffer(&mut self) {
+        let frame = self.clint.mtime / FRAME_TICKS;
+        let Some(framebuffer) = &mut self.framebuffer else {
+            return;
+        };
+        let last = framebuffer.frame;
+        if frame == last {
+            return;
+        }
+        framebuffer.frame = frame;
+        let Some((directory, every)) = &framebuffer.dumps else {
+            return;
+        };
+        if frame / every == last / every {
+            return;
+        }
+        let path = directory.join(format!("frame-{:06}.png", frame - frame % every));
+        if self.save_frame(path).is_err() {
+            self.framebuffer.as_mut().unwrap().dumps = None;
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+// An 8 bit RGB image of filtered lines. The zlib stream uses stored blocks,
+// the images are for tests and compression is not worth a dependency.
+fn png(width: u32, height: u32, image: &[u8]) -> Vec<u8> {
+    let mut header = width.to_be_bytes().to_vec();
+    header.extend(height.to_be_bytes());
+    header.extend([8, 2, 0, 0, 0]); // depth, RGB, deflate, adaptive filters, no interlace
+
+    let mut data = vec![0x78, 0x01];
+    let blocks = image.chunks(STORED_BLOCK).count();
+    for (index, block) in image.chunks(STORED_BLOCK).enumerate() {
+        data.push((index + 1 == blocks) as u8); // BFINAL, BTYPE 0
+        data.extend((block.len() as u16).to_le_bytes());
+        data.extend((!(block.len() as u16)).to_le_bytes());
+        data.extend(block);
+    }
+    data.extend(adler32(image).to_be_bytes());
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &data);
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use crate::vm::{bus::Bus, registers::BaseIsa};
+
+    use super::*;
+
+    // Width, height and lines of an image written by png()
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let word = |offset: usize| u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(word(29), crc32(&png[12..29]));
+        let length = word(33) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let data = &png[41..41 + length];
+        let mut image = vec![];
+        let mut offset = 2;
+        loop {
+            let size = u16::from_le_bytes([data[offset + 1], data[offset + 2]]) as usize;
+            image.extend(&data[offset + 5..offset + 5 + size]);
+            offset += 5 + size;
+            if data[offset - 5 - size] & 1 == 1 {
+                break;
+            }
+        }
+        assert_eq!(data[offset..], adler32(&image).to_be_bytes());
+        (word(16), word(20), image)
+    }
+
+    #[test]
+    fn test_save_frame() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        vm.attach_framebuffer(FRAMEBUFFER_BASE, 2, 2, PixelFormat::R5G6B5)
+            .unwrap();
+        let base = FRAMEBUFFER_BASE as u32;
+        vm.store(2, base, 0xF800).unwrap(); // red
+        vm.store(2, base + 2, 0x07E0).unwrap(); // green
+        vm.store(2, base + 6, 0xFFFF).unwrap(); // white
+        assert_eq!(vm.load(2, base + 2), Ok(0x07E0));
+        let path = env::temp_dir().join(format!("riscv_vm-{}.png", process::id()));
+        vm.save_frame(&path).unwrap();
+        let png = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (width, height, image) = decode(&png);
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(
+            image,
+            [0, 0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn test_dump_frames() {
+        let directory = env::temp_dir().join(format!("riscv_vm-{}-frames", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        vm.attach_framebuffer(FRAMEBUFFER_BASE, 1, 1, PixelFormat::A8R8G8B8)
+            .unwrap();
+        vm.dump_frames(directory.to_str().unwrap(), 2);
+        vm.clint.mtime = FRAME_TICKS;
+        vm.tick_framebuffer();
+        vm.store(4, FRAMEBUFFER_BASE as u32, 0xFF12_3456).unwrap();
+        // wfi can skip frames, the dump still happens
+        vm.clint.mtime = FRAME_TICKS * 5;
+        vm.tick_framebuffer();
+        assert_eq!(vm.frames(), Some(5));
+        let mut files: Vec<String> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["frame-000004.png"]);
+        let png = fs::read(directory.join(&files[0])).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(decode(&png).2, [0, 0x12, 0x34, 0x56]);
+    }
+}
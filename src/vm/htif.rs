@@ -0,0 +1,242 @@
+// Berkeley HTIF, as used by riscv-tests and the proxy kernel: the program
+// writes commands to its tohost word in memory and the host answers through
+// fromhost. Both are found by symbol in the loaded ELF. Device 0 proxies
+// syscalls, and an odd payload is the exit code; device 1 is the console.
+use std::collections::VecDeque;
+
+use super::{device::Exit, uart::Serial, Vm};
+
+const SYSCALL: u64 = 0;
+const CONSOLE: u64 = 1;
+const GETCHAR: u64 = 0;
+const PUTCHAR: u64 = 1;
+
+// syscalls proxied for pk-style programs, anything else fails with ENOSYS
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const EBADF: i64 = 9;
+const ENOSYS: i64 = 38;
+
+const ARGUMENTS: usize = 8; // magic_mem: number and up to 7 arguments
+
+pub(crate) struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    console: Option<Box<dyn Serial>>,
+    // tohost as of the last step. On rv32 it takes two stores to write, so a
+    // command is only taken once it has held still for a step.
+    seen: u64,
+    reading: bool,            // a getchar waits for input
+    responses: VecDeque<u64>, // for fromhost, once the program has taken the last one
+}
+
+impl Htif {
+    pub(crate) fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            console: None,
+            seen: 0,
+            reading: false,
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+fn command(device: u64, command: u64, payload: u64) -> u64 {
+    device << 56 | command << 48 | payload
+}
+
+fn exit_code(code: u64) -> Exit {
+    match code {
+        0 => Exit::Pass,
+        _ => Exit::Fail(code.min(u16::MAX as u64) as u16),
+    }
+}
+
+impl Vm {
+    // The loaded program talks to the host through tohost
+    pub fn has_htif(&self) -> bool {
+        self.htif.is_some()
+    }
+
+    // Backend of the HTIF console and of the proxied reads and writes
+    pub fn set_htif_console(&mut self, console: Box<dyn Serial>) {
+        if let Some(htif) = &mut self.htif {
+            htif.console = Some(console);
+        }
+    }
+
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        let bytes = self.bus.read(address, 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn tick_htif(&mut self) {
+        let Some(htif) = &self.htif else {
+            return;
+        };
+        let (tohost, fromhost) = (htif.tohost, htif.fromhost);
+        let value = self.read_u64(tohost).unwrap_or(0);
+        let htif = self.htif.as_mut().unwrap();
+        if value != htif.seen {
+            htif.seen = value;
+        } else if value != 0 {
+            htif.seen = 0;
+            self.bus.write(tohost, &[0; 8]);
+            self.htif_command(value);
+        }
+
+        let htif = self.htif.as_mut().unwrap();
+        if htif.reading {
+            if let Some(byte) = htif
+                .console
+                .as_mut()
+                .and_then(|console| console.read_byte())
+            {
+                htif.responses
+                    .push_back(command(CONSOLE, GETCHAR, 0x100 | byte as u64));
+                htif.reading = false;
+            }
+        }
+        let Some(fromhost) = fromhost else {
+            return;
+        };
+        if self.read_u64(fromhost) == Some(0) {
+            if let Some(response) = self.htif.as_mut().unwrap().responses.pop_front() {
+                self.bus.write(fromhost, &response.to_le_bytes());
+            }
+        }
+    }
+
+    fn htif_command(&mut self, value: u64) {
+        let (device, cmd, payload) = (value >> 56, value >> 48 & 0xFF, value & 0xFFFF_FFFF_FFFF);
+        let htif = self.htif.as_mut().unwrap();
+        match (device, cmd) {
+            (SYSCALL, 0) if payload & 1 == 1 => {
+                self.exit = self.exit.or(Some(exit_code(payload >> 1)));
+            }
+            (SYSCALL, 0) => {
+                self.proxy_syscall(payload);
+                let htif = self.htif.as_mut().unwrap();
+                htif.responses.push_back(command(SYSCALL, 0, 1));
+            }
+            (CONSOLE, GETCHAR) => htif.reading = true,
+            (CONSOLE, PUTCHAR) => {
+                if let Some(console) = &mut htif.console {
+                    console.write_byte(payload as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // magic_mem at `address` holds the number and arguments, the result
+    // replaces the number
+    fn proxy_syscall(&mut self, address: u64) {
+        let arguments: Option<Vec<u64>> = (0..ARGUMENTS as u64)
+            .map(|index| self.read_u64(address + 8 * index))
+            .collect();
+        let Some(arguments) = arguments else {
+            return;
+        };
+        let (fd, buffer, length) = (arguments[1], arguments[2], arguments[3] as usize);
+        let console = self.htif.as_mut().unwrap().console.as_mut();
+        let result = match arguments[0] {
+            SYS_WRITE if fd == 1 || fd == 2 => match self.bus.read(buffer, length) {
+                Some(bytes) => {
+                    if let Some(console) = console {
+                        bytes.iter().for_each(|&byte| console.write_byte(byte));
+                    }
+                    length as i64
+                }
+                None => -EBADF,
+            },
+            SYS_READ if fd == 0 => {
+                let mut bytes = vec![];
+                if let Some(console) = console {
+                    while bytes.len() < length {
+                        match console.read_byte() {
+                            Some(byte) => bytes.push(byte),
+                            None => break,
+                        }
+                    }
+                }
+                match self.bus.write(buffer, &bytes) {
+                    Some(()) => bytes.len() as i64,
+                    None => -EBADF,
+                }
+            }
+            SYS_EXIT => {
+                self.exit = self.exit.or(Some(exit_code(arguments[1])));
+                0
+            }
+            SYS_READ | SYS_WRITE => -EBADF,
+            _ => -ENOSYS,
+        };
+        self.bus.write(address, &result.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        bus::{Bus, DRAM_BASE},
+        registers::BaseIsa,
+        uart::BufferSerial,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_riscv_test_passes() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x10_0000).unwrap());
+        vm.load_program_from_file("src/examples/rv32ui-p-add")
+            .unwrap();
+        assert!(vm.has_htif());
+        assert_eq!(vm.run(100_000), Some(Exit::Pass));
+    }
+
+    #[test]
+    fn test_console_and_syscalls() {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x10_0000).unwrap());
+        let (tohost, fromhost) = (DRAM_BASE + 0x1000, DRAM_BASE + 0x1040);
+        vm.htif = Some(Htif::new(tohost, Some(fromhost)));
+        let console = BufferSerial::new();
+        vm.set_htif_console(Box::new(console.clone()));
+        let send = |vm: &mut Vm, value: u64| {
+            vm.bus.write(tohost, &value.to_le_bytes());
+            vm.tick_htif();
+            vm.tick_htif();
+            assert_eq!(vm.read_u64(tohost), Some(0));
+        };
+
+        send(&mut vm, command(CONSOLE, PUTCHAR, b'h' as u64));
+        console.push_input(b"i");
+        send(&mut vm, command(CONSOLE, GETCHAR, 0));
+        assert_eq!(
+            vm.read_u64(fromhost),
+            Some(command(CONSOLE, GETCHAR, 0x100 | b'i' as u64))
+        );
+        vm.bus.write(fromhost, &[0; 8]);
+
+        // write(1, "proxied", 7), then an unknown syscall
+        let magic = DRAM_BASE + 0x2000;
+        vm.bus.write(DRAM_BASE + 0x3000, b"proxied");
+        for (index, value) in [SYS_WRITE, 1, DRAM_BASE + 0x3000, 7].iter().enumerate() {
+            vm.bus.write(magic + 8 * index as u64, &value.to_le_bytes());
+        }
+        send(&mut vm, magic);
+        assert_eq!(console.take_output(), b"hproxied");
+        assert_eq!(vm.read_u64(magic), Some(7));
+        assert_eq!(vm.read_u64(fromhost), Some(1));
+        vm.bus.write(fromhost, &[0; 8]);
+        vm.bus.write(magic, &500u64.to_le_bytes());
+        send(&mut vm, magic);
+        assert_eq!(vm.read_u64(magic), Some(-ENOSYS as u64));
+
+        send(&mut vm, 3 << 1 | 1);
+        assert_eq!(vm.exit(), Some(Exit::Fail(3)));
+    }
+}
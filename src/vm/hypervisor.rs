@@ -0,0 +1,390 @@
+// Hypervisor extension: what changes for instructions run with V = 1 and
+// the hypervisor's own instructions. Anything that would be legal in HS or
+// U mode but is not allowed in VS or VU mode raises a virtual instruction
+// exception, so the hypervisor can emulate it.
+use super::{
+    csr::{self, Privilege},
+    instruction::Instruction,
+    mmu::AccessType,
+    opcodes::Opcodes,
+    trap::Exception,
+    Vm, BYTE, HALF_WORD, WORD_SIZE,
+};
+
+impl Vm {
+    // Csr the guest really accesses. VS mode's s* csrs are backed by the vs*
+    // ones, hypervisor and VS csrs themselves are off limits.
+    pub(crate) fn virtual_csr(&self, address: u32, raw: u32) -> Result<u32, Exception> {
+        let illegal = Exception::IllegalInstruction(raw);
+        let virtual_instruction = Exception::VirtualInstruction(raw);
+
+        match (address >> 8) & 0x3 {
+            3 => return Err(illegal),
+            2 => return Err(virtual_instruction),
+            1 if self.privilege == Privilege::User => return Err(virtual_instruction),
+            _ => {}
+        }
+
+        let address = match address {
+            csr::SSTATUS => csr::VSSTATUS,
+            csr::SIE => csr::VSIE,
+            csr::STVEC => csr::VSTVEC,
+            csr::SSCRATCH => csr::VSSCRATCH,
+            csr::SEPC => csr::VSEPC,
+            csr::SCAUSE => csr::VSCAUSE,
+            csr::STVAL => csr::VSTVAL,
+            csr::SIP => csr::VSIP,
+            csr::SATP if self.csr.hstatus & csr::HSTATUS_VTVM != 0 => {
+                return Err(virtual_instruction)
+            }
+            csr::SATP => csr::VSATP,
+            // there are no guest interrupt files or VS timer compare, the
+            // hypervisor emulates these
+            csr::SISELECT
+            | csr::SIREG
+            | csr::STOPEI
+            | csr::STOPI
+            | csr::SIEH
+            | csr::SIPH
+            | csr::STIMECMP
+            | csr::STIMECMPH => return Err(virtual_instruction),
+            csr::TIME | csr::TIMEH => {
+                if self.csr.mcounteren & csr::COUNTEREN_TM == 0 {
+                    return Err(illegal);
+                }
+                if self.csr.hcounteren & csr::COUNTEREN_TM == 0
+                    || (self.privilege == Privilege::User
+                        && self.csr.scounteren & csr::COUNTEREN_TM == 0)
+                {
+                    return Err(virtual_instruction);
+                }
+                address
+            }
+            address => address,
+        };
+        Ok(address)
+    }
+
+    // Guests read time shifted by htimedelta
+    pub(crate) fn guest_time(&self, address: u32) -> u32 {
+        let time = self.csr.time.wrapping_add(self.csr.htimedelta);
+        if address == csr::TIMEH {
+            (time >> 32) as u32
+        } else {
+            time as u32
+        }
+    }
+
+    // hfence.vvma and hfence.gvma. Guest translations are never cached so
+    // only the permission checks remain.
+    pub(crate) fn execute_hfence(&mut self, instruction: &Instruction) -> Result<(), Exception> {
+        if !self.csr.hypervisor() {
+            return Err(Exception::IllegalInstruction(instruction.raw));
+        }
+        if self.virt {
+            return Err(Exception::VirtualInstruction(instruction.raw));
+        }
+        if self.privilege == Privilege::User
+            || (instruction.opcode == Opcodes::HfenceGvma
+                && self.privilege == Privilege::Supervisor
+                && self.csr.mstatus & csr::MSTATUS_TVM != 0)
+        {
+            return Err(Exception::IllegalInstruction(instruction.raw));
+        }
+        Ok(())
+    }
+
+    // hlv, hlvx and hsv: loads and stores with the translation and
+    // protection VS or VU mode (hstatus.SPVP) would get. Allowed from HS mode
+    // and, with hstatus.HU, from U mode.
+    pub(crate) fn execute_hypervisor_access(
+        &mut self,
+        instruction: &Instruction,
+    ) -> Result<(), Exception> {
+        if !self.csr.hypervisor() {
+            return Err(Exception::IllegalInstruction(instruction.raw));
+        }
+        if self.virt {
+            return Err(Exception::VirtualInstruction(instruction.raw));
+        }
+        if self.privilege == Privilege::User && self.csr.hstatus & csr::HSTATUS_HU == 0 {
+            return Err(Exception::IllegalInstruction(instruction.raw));
+        }
+
+        let privilege = if self.csr.hstatus & csr::HSTATUS_SPVP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        let address = self.get_register(instruction.rs1);
+        let value = self.get_register(instruction.rs2);
+        let load = AccessType::Load;
+        // hlvx needs execute permission, not read permission
+        let execute = AccessType::Instruction;
+
+        let loaded = match instruction.opcode {
+            Opcodes::HlvB => self.load_as(BYTE, address, privilege, true, load)? as i8 as u32,
+            Opcodes::HlvBu => self.load_as(BYTE, address, privilege, true, load)?,
+            Opcodes::HlvH => self.load_as(HALF_WORD, address, privilege, true, load)? as i16 as u32,
+            Opcodes::HlvHu => self.load_as(HALF_WORD, address, privilege, true, load)?,
+            Opcodes::HlvxHu => self.load_as(HALF_WORD, address, privilege, true, execute)?,
+            Opcodes::HlvW => self.load_as(WORD_SIZE, address, privilege, true, load)?,
+            Opcodes::HlvxWu => self.load_as(WORD_SIZE, address, privilege, true, execute)?,
+            Opcodes::HsvB => return self.store_as(BYTE, address, value, privilege, true),
+            Opcodes::HsvH => return self.store_as(HALF_WORD, address, value, privilege, true),
+            _ => return self.store_as(WORD_SIZE, address, value, privilege, true),
+        };
+        self.set_register(instruction.rd, loaded);
+        Ok(())
+    }
+}
+
+// hlvx translates like a fetch but its faults are load faults
+pub(crate) fn hlvx_fault(exception: Exception) -> Exception {
+    match exception {
+        Exception::InstructionAccessFault(address) => Exception::LoadAccessFault(address),
+        Exception::InstructionPageFault(address) => Exception::LoadPageFault(address),
+        Exception::InstructionGuestPageFault(address, gpa) => {
+            Exception::LoadGuestPageFault(address, gpa)
+        }
+        exception => exception,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        instruction::into_byte,
+        registers::Registers,
+        tests::vm_with_program,
+        trap::Exception,
+        Vm, WORD_SIZE,
+    };
+
+    // page table entry flags
+    const V: u32 = 1 << 0;
+    const R: u32 = 1 << 1;
+    const W: u32 = 1 << 2;
+    const X: u32 = 1 << 3;
+    const U: u32 = 1 << 4;
+    const A: u32 = 1 << 6;
+    const D: u32 = 1 << 7;
+
+    const G_ROOT: u32 = 0x4_0000; // 16 KiB aligned
+    const VS_ROOT_GPA: u32 = 0x8000_0000;
+
+    fn write_word(vm: &mut Vm, address: u32, value: u32) {
+        vm.mem_write(WORD_SIZE, address as u64, &into_byte(value))
+            .unwrap();
+    }
+
+    // G-stage: guest 0x8000_0000 - 0x803F_FFFF -> host 0x40_0000.
+    // VS-stage, with its root at guest 0x8000_0000: 0x1000_0000 -> guest
+    // 0x8000_0000 (RW) and 0x2000_0000 -> guest 0xC000_0000, which the
+    // G-stage does not map.
+    fn vm_with_guest_page_tables() -> Vm {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        write_word(
+            &mut vm,
+            G_ROOT + (VS_ROOT_GPA >> 22) * 4,
+            (0x40_0000 >> 12) << 10 | V | R | W | X | U | A | D,
+        );
+        let vs_root = 0x40_0000;
+        write_word(
+            &mut vm,
+            vs_root + 0x40 * 4,
+            (VS_ROOT_GPA >> 12) << 10 | V | R | W | A | D,
+        );
+        write_word(
+            &mut vm,
+            vs_root + 0x80 * 4,
+            (0xC000_0000u32 >> 12) << 10 | V | R | W | A | D,
+        );
+        vm.csr.hgatp = 1 << 31 | G_ROOT >> 12;
+        vm.csr.vsatp = 1 << 31 | VS_ROOT_GPA >> 12;
+        vm
+    }
+
+    fn run_at(vm: &mut Vm, pc: u32, instruction: u32) -> Result<(), Exception> {
+        write_word(vm, pc, instruction);
+        vm.register[Registers::Pc as usize] = pc;
+        vm.run_program()
+    }
+
+    #[test]
+    fn test_two_stage_translation() {
+        let mut vm = vm_with_guest_page_tables();
+        write_word(&mut vm, 0x41_0004, 0xfeed);
+        vm.virt = true;
+        vm.privilege = Privilege::Supervisor;
+
+        assert_eq!(vm.load(WORD_SIZE, 0x1001_0004), Ok(0xfeed));
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x3000_0000),
+            Err(Exception::LoadPageFault(0x3000_0000))
+        );
+        let fault = vm.store(WORD_SIZE, 0x2000_0010, 1).unwrap_err();
+        assert_eq!(
+            fault,
+            Exception::StoreGuestPageFault(0x2000_0010, 0xC000_0010 >> 2)
+        );
+
+        // VU mode cannot use the guest kernel's pages
+        vm.privilege = Privilege::User;
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x1001_0004),
+            Err(Exception::LoadPageFault(0x1001_0004))
+        );
+
+        // guest page faults go to HS mode with the guest physical address
+        vm.privilege = Privilege::Supervisor;
+        vm.guest_access = true;
+        vm.csr.medeleg = 1 << 23;
+        vm.csr.stvec = 0x100;
+        vm.take_trap(fault);
+        assert_eq!(
+            (vm.csr.scause, vm.csr.stval, vm.csr.htval),
+            (23, 0x2000_0010, 0xC000_0010 >> 2)
+        );
+        let hstatus = csr::HSTATUS_SPV | csr::HSTATUS_SPVP | csr::HSTATUS_GVA;
+        assert_eq!(vm.csr.hstatus & hstatus, hstatus);
+        assert_eq!((vm.privilege, vm.virt), (Privilege::Supervisor, false));
+
+        // the same fault undelegated lands in M mode, mtval2 instead of htval
+        vm.virt = true;
+        vm.csr.medeleg = 0;
+        vm.take_trap(fault);
+        assert_eq!((vm.csr.mcause, vm.csr.mtval2), (23, 0xC000_0010 >> 2));
+        assert_eq!(vm.csr.mstatush, csr::MSTATUSH_MPV | csr::MSTATUSH_GVA);
+    }
+
+    #[test]
+    fn test_virtual_instructions() {
+        let mut vm = vm_with_program(&[]);
+        vm.virt = true;
+        vm.privilege = Privilege::Supervisor;
+        vm.csr.vsstatus = csr::MSTATUS_SIE;
+
+        // csrr x5, sstatus reads vsstatus
+        assert_eq!(run_at(&mut vm, 0, 0x100022F3), Ok(()));
+        assert_eq!(vm.get_register(5), csr::MSTATUS_SIE);
+        // csrr x5, hstatus / csrr x5, mstatus
+        assert_eq!(
+            run_at(&mut vm, 0, 0x600022F3),
+            Err(Exception::VirtualInstruction(0x600022F3))
+        );
+        assert_eq!(
+            run_at(&mut vm, 0, 0x300022F3),
+            Err(Exception::IllegalInstruction(0x300022F3))
+        );
+
+        // csrr x5, time: shifted by htimedelta once both counter enables allow it
+        vm.csr.mcounteren = 1 << 1;
+        assert_eq!(
+            run_at(&mut vm, 0, 0xC01022F3),
+            Err(Exception::VirtualInstruction(0xC01022F3))
+        );
+        vm.csr.hcounteren = 1 << 1;
+        vm.csr.time = 100;
+        vm.csr.htimedelta = 5;
+        assert_eq!(run_at(&mut vm, 0, 0xC01022F3), Ok(()));
+        assert_eq!(vm.get_register(5), 105);
+
+        // wfi with VTW, hfence.gvma, hlv.w x5, (x6)
+        vm.csr.hstatus = csr::HSTATUS_VTW;
+        for raw in [0x10500073, 0x62000073, 0x680342F3] {
+            assert_eq!(
+                run_at(&mut vm, 0, raw),
+                Err(Exception::VirtualInstruction(raw))
+            );
+        }
+
+        // ecall from VS mode goes to HS mode, sret takes it back
+        assert_eq!(
+            run_at(&mut vm, 0x40, 0x00000073),
+            Err(Exception::EnvironmentCallFromVSMode)
+        );
+        vm.csr.medeleg = 1 << 10;
+        vm.take_trap(Exception::EnvironmentCallFromVSMode);
+        assert_eq!((vm.csr.scause, vm.csr.sepc, vm.virt), (10, 0x40, false));
+        vm.csr.sepc = 0x44;
+        assert_eq!(run_at(&mut vm, 0x100, 0x10200073), Ok(()));
+        assert_eq!((vm.privilege, vm.virt), (Privilege::Supervisor, true));
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x44);
+    }
+
+    #[test]
+    fn test_hypervisor_loads_and_stores() {
+        let mut vm = vm_with_guest_page_tables();
+        vm.privilege = Privilege::Supervisor;
+        vm.csr.hstatus = csr::HSTATUS_SPVP;
+        vm.register[6] = 0x1001_0008;
+        vm.register[7] = 0x1234_5678;
+
+        // hsv.w x7, (x6) then hlv.w x5, (x6) through both stages
+        assert_eq!(run_at(&mut vm, 0, 0x6A734073), Ok(()));
+        assert_eq!(
+            vm.mem_read(WORD_SIZE, 0x41_0008).unwrap(),
+            into_byte(0x1234_5678)
+        );
+        assert_eq!(run_at(&mut vm, 0, 0x680342F3), Ok(()));
+        assert_eq!(vm.get_register(5), 0x1234_5678);
+        assert!(!vm.virt);
+
+        // hlvx.wu x5, (x6) needs execute permission
+        assert_eq!(
+            run_at(&mut vm, 0, 0x683342F3),
+            Err(Exception::LoadPageFault(0x1001_0008))
+        );
+
+        // from U mode only with hstatus.HU
+        vm.privilege = Privilege::User;
+        assert_eq!(
+            run_at(&mut vm, 0, 0x680342F3),
+            Err(Exception::IllegalInstruction(0x680342F3))
+        );
+        vm.csr.hstatus |= csr::HSTATUS_HU;
+        assert_eq!(run_at(&mut vm, 0, 0x680342F3), Ok(()));
+    }
+
+    #[test]
+    fn test_virtual_supervisor_interrupts() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        vm.virt = true;
+        vm.privilege = Privilege::User;
+        vm.csr.vstvec = 0x8;
+        vm.csr.stvec = 0x300;
+        vm.csr
+            .write(csr::HIE, csr::MIP_VSTIP, Privilege::Machine)
+            .unwrap();
+        vm.csr
+            .write(csr::HVIP, csr::MIP_VSTIP, Privilege::Machine)
+            .unwrap();
+
+        // not delegated by hideleg: HS mode takes it as cause 6
+        vm.step();
+        assert_eq!(vm.csr.scause, 1 << 31 | 6);
+        assert_eq!(vm.csr.hstatus & csr::HSTATUS_SPV, csr::HSTATUS_SPV);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x300);
+
+        // delegated: the guest sees a supervisor timer interrupt
+        vm.virt = true;
+        vm.privilege = Privilege::User;
+        vm.csr
+            .write(csr::HIDELEG, csr::MIP_VSTIP, Privilege::Machine)
+            .unwrap();
+        assert_eq!(
+            vm.csr.read(csr::VSIP, Privilege::Machine),
+            Some(csr::MIP_STIP)
+        );
+        vm.step();
+        assert_eq!(vm.csr.vscause, 1 << 31 | 5);
+        assert_eq!((vm.privilege, vm.virt), (Privilege::Supervisor, true));
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x8);
+
+        // and masks it with vsstatus.SIE while in VS mode
+        vm.step();
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0xC);
+    }
+}
@@ -0,0 +1,228 @@
+// Incoming MSI controller (Smaia/Ssaia). Each privilege level of a hart has
+// an interrupt file; devices signal identity `n` by writing n to the file's
+// seteipnum register.
+pub(crate) const IMSIC_M_BASE: u64 = 0x2400_0000;
+pub(crate) const IMSIC_S_BASE: u64 = 0x2800_0000;
+pub(crate) const IMSIC_FILE_SIZE: u64 = 0x1000;
+
+// identities 1..=IMSIC_IDENTITIES, must be a multiple of 64 minus one
+pub(crate) const IMSIC_IDENTITIES: usize = 255;
+
+// memory mapped registers of an interrupt file
+const SETEIPNUM_LE: u64 = 0x0;
+const SETEIPNUM_BE: u64 = 0x4;
+
+// *iselect values reaching the interrupt file through *ireg
+const ISELECT_IPRIO0: u32 = 0x30;
+const ISELECT_IPRIO15: u32 = 0x3F;
+const EIDELIVERY: u32 = 0x70;
+const EITHRESHOLD: u32 = 0x72;
+const EIP0: u32 = 0x80;
+const EIP63: u32 = 0xBF;
+const EIE0: u32 = 0xC0;
+const EIE63: u32 = 0xFF;
+
+pub(crate) struct InterruptFile {
+    eidelivery: u32,
+    eithreshold: u32,
+    eip: Vec<bool>,
+    eie: Vec<bool>,
+}
+
+impl InterruptFile {
+    pub(crate) fn new() -> Self {
+        Self {
+            eidelivery: 0,
+            eithreshold: 0,
+            eip: vec![false; IMSIC_IDENTITIES + 1],
+            eie: vec![false; IMSIC_IDENTITIES + 1],
+        }
+    }
+
+    // MSI arrival, identity 0 and unimplemented ones are dropped
+    pub(crate) fn set_pending(&mut self, identity: u32) {
+        let identity = identity as usize;
+        if identity != 0 && identity <= IMSIC_IDENTITIES {
+            self.eip[identity] = true;
+        }
+    }
+
+    // Lowest pending and enabled identity, below eithreshold unless it is 0
+    pub(crate) fn top(&self) -> Option<u32> {
+        (1..=IMSIC_IDENTITIES)
+            .find(|identity| self.eip[*identity] && self.eie[*identity])
+            .filter(|identity| self.eithreshold == 0 || (*identity as u32) < self.eithreshold)
+            .map(|identity| identity as u32)
+    }
+
+    // the file's interrupt to the hart, mip.MEIP or mip.SEIP
+    pub(crate) fn interrupt(&self) -> bool {
+        self.eidelivery == 1 && self.top().is_some()
+    }
+
+    // xtopei: identity in both 26:16 and 10:0, zero when nothing is pending
+    pub(crate) fn topei(&self) -> u32 {
+        self.top().map_or(0, |identity| identity << 16 | identity)
+    }
+
+    // a write to xtopei claims the top interrupt
+    pub(crate) fn claim(&mut self) {
+        if let Some(identity) = self.top() {
+            self.eip[identity as usize] = false;
+        }
+    }
+
+    // None for *iselect values that do not exist
+    pub(crate) fn read_indirect(&self, select: u32) -> Option<u32> {
+        let value = match select {
+            // major interrupt priorities are not configurable
+            ISELECT_IPRIO0..=ISELECT_IPRIO15 => 0,
+            EIDELIVERY => self.eidelivery,
+            EITHRESHOLD => self.eithreshold,
+            EIP0..=EIP63 => pack(&self.eip, select - EIP0),
+            EIE0..=EIE63 => pack(&self.eie, select - EIE0),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub(crate) fn write_indirect(&mut self, select: u32, value: u32) -> Option<()> {
+        match select {
+            ISELECT_IPRIO0..=ISELECT_IPRIO15 => {}
+            // 0 off, 1 interrupt file delivery
+            EIDELIVERY => self.eidelivery = value & 1,
+            EITHRESHOLD => self.eithreshold = value & IMSIC_IDENTITIES as u32,
+            EIP0..=EIP63 => unpack(&mut self.eip, select - EIP0, value),
+            EIE0..=EIE63 => unpack(&mut self.eie, select - EIE0, value),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // 32 bit writes of an identity, reads return zero
+    pub(crate) fn mmio_read(&self, offset: u64, size: usize) -> Option<u32> {
+        match (offset, size) {
+            (SETEIPNUM_LE | SETEIPNUM_BE, 4) => Some(0),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn mmio_write(&mut self, offset: u64, size: usize, value: u32) -> Option<()> {
+        match (offset, size) {
+            (SETEIPNUM_LE, 4) => self.set_pending(value),
+            (SETEIPNUM_BE, 4) => self.set_pending(value.swap_bytes()),
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+// eip/eie register `index` holds identities 32 * index ..= 32 * index + 31,
+// bit 0 of register 0 (identity 0) is read only zero
+fn pack(bits: &[bool], index: u32) -> u32 {
+    (0..32).fold(0, |value, bit| {
+        let identity = (index * 32 + bit) as usize;
+        if bits.get(identity).copied().unwrap_or(false) {
+            value | 1 << bit
+        } else {
+            value
+        }
+    })
+}
+
+fn unpack(bits: &mut [bool], index: u32, value: u32) {
+    for bit in 0..32 {
+        let identity = (index * 32 + bit) as usize;
+        if identity != 0 && identity < bits.len() {
+            bits[identity] = value & (1 << bit) != 0;
+        }
+    }
+}
+
+// The two interrupt files of the hart
+pub(crate) struct Imsic {
+    pub(crate) machine: InterruptFile,
+    pub(crate) supervisor: InterruptFile,
+}
+
+impl Imsic {
+    pub(crate) fn new() -> Self {
+        Self {
+            machine: InterruptFile::new(),
+            supervisor: InterruptFile::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        tests::vm_with_program,
+        WORD_SIZE,
+    };
+
+    use super::IMSIC_M_BASE;
+
+    #[test]
+    fn test_interrupt_file_through_indirect_csrs() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        let m = Privilege::Machine;
+
+        // enable identities 5 and 40, turn on delivery
+        vm.csr.write(csr::MISELECT, 0xC0, m).unwrap();
+        vm.csr.write(csr::MIREG, 1 << 5 | 1, m).unwrap();
+        assert_eq!(vm.csr.read(csr::MIREG, m), Some(1 << 5)); // identity 0 is read only
+        vm.csr.write(csr::MISELECT, 0xC1, m).unwrap();
+        vm.csr.write(csr::MIREG, 1 << 8, m).unwrap();
+        vm.csr.write(csr::MISELECT, 0x70, m).unwrap();
+        vm.csr.write(csr::MIREG, 1, m).unwrap();
+
+        // reserved selects are illegal
+        vm.csr.write(csr::MISELECT, 0x20, m).unwrap();
+        assert_eq!(vm.csr.read(csr::MIREG, m), None);
+
+        // MSIs for 40 and 5, then an unimplemented identity
+        let file = IMSIC_M_BASE as u32;
+        vm.store(WORD_SIZE, file, 40).unwrap();
+        vm.store(WORD_SIZE, file, 5).unwrap();
+        vm.store(WORD_SIZE, file, 4000).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.mip & csr::MIP_MEIP, csr::MIP_MEIP);
+        assert_eq!(vm.csr.read(csr::MTOPEI, m), Some(5 << 16 | 5));
+
+        // the threshold masks identities at or above it
+        vm.csr.write(csr::MISELECT, 0x72, m).unwrap();
+        vm.csr.write(csr::MIREG, 5, m).unwrap();
+        assert_eq!(vm.csr.read(csr::MTOPEI, m), Some(0));
+        vm.csr.write(csr::MIREG, 0, m).unwrap();
+
+        // claims go lowest identity first
+        vm.csr.write(csr::MTOPEI, 0, m).unwrap();
+        assert_eq!(vm.csr.read(csr::MTOPEI, m), Some(40 << 16 | 40));
+        vm.csr.write(csr::MTOPEI, 0, m).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.mip & csr::MIP_MEIP, 0);
+        vm.csr.write(csr::MISELECT, 0x81, m).unwrap();
+        assert_eq!(vm.csr.read(csr::MIREG, m), Some(0));
+    }
+
+    #[test]
+    fn test_topi() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        let m = Privilege::Machine;
+        vm.csr.mideleg = csr::MIP_SSIP | csr::MIP_STIP;
+        vm.csr.mie = csr::MIP_MTIP | csr::MIP_SSIP | csr::MIP_STIP;
+        vm.csr.mip = csr::MIP_SSIP | csr::MIP_STIP;
+        assert_eq!(vm.csr.read(csr::MTOPI, m), Some(0));
+        assert_eq!(vm.csr.read(csr::STOPI, m), Some(1 << 16 | 1));
+
+        vm.clint.mtimecmp = 0;
+        vm.step();
+        assert_eq!(vm.csr.read(csr::MTOPI, m), Some(7 << 16 | 1));
+
+        // read only, and not reachable from U mode
+        assert_eq!(vm.csr.write(csr::MTOPI, 0, m), None);
+        assert_eq!(vm.csr.read(csr::STOPI, Privilege::User), None);
+    }
+}
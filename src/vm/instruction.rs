@@ -1,4 +1,4 @@
-use super::opcodes::Opcodes;
+use super::{opcodes::Opcodes, trap::Exception};
 
 // todo!: verify the types
 pub(crate) struct Instruction {
@@ -13,6 +13,7 @@ pub(crate) struct Instruction {
     pub(crate) succ: u32,
     pub(crate) pred: u32,
     pub(crate) fm: u32,
+    pub(crate) raw: u32, // undecoded instruction, reported in mtval
 }
 
 impl Instruction {
@@ -29,78 +30,80 @@ impl Instruction {
             succ: 0,
             pred: 0,
             fm: 0,
+            raw: 0,
         }
     }
 
-    pub(crate) fn decode(instruction: &[u8]) -> Self {
+    pub(crate) fn decode(instruction: &[u8]) -> Result<Self, Exception> {
         let instr = into_u32(instruction);
         let mut res = Instruction::new();
+        res.raw = instr;
 
         if instr & 0x7F == 0x33 {
             // R type
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.rs2 = (instr >> 20) & 0xF;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.rs2 = (instr >> 20) & 0x1F;
             res.funct7 = instr >> 25;
 
-            match res.funct3 {
-                0x0 => match res.funct7 {
-                    0x00 => res.opcode = Opcodes::Add,
-                    0x20 => res.opcode = Opcodes::Sub,
-                    _ => panic!("Invalid instruction"),
-                },
-                0x1 => res.opcode = Opcodes::Sll,
-                0x2 => res.opcode = Opcodes::Slt,
-                0x3 => res.opcode = Opcodes::Sltu,
-                0x4 => res.opcode = Opcodes::Xor,
-                0x5 => match res.funct7 {
-                    0x00 => res.opcode = Opcodes::Srl,
-                    0x20 => res.opcode = Opcodes::Sra,
-                    _ => panic!("Invalid instruction"),
-                },
-                0x6 => res.opcode = Opcodes::Or,
-                0x7 => res.opcode = Opcodes::And,
-                _ => panic!("Invalid instruction"),
+            // any other funct7 is M (0x01) or reserved, neither is implemented
+            match (res.funct3, res.funct7) {
+                (0x0, 0x00) => res.opcode = Opcodes::Add,
+                (0x0, 0x20) => res.opcode = Opcodes::Sub,
+                (0x1, 0x00) => res.opcode = Opcodes::Sll,
+                (0x2, 0x00) => res.opcode = Opcodes::Slt,
+                (0x3, 0x00) => res.opcode = Opcodes::Sltu,
+                (0x4, 0x00) => res.opcode = Opcodes::Xor,
+                (0x5, 0x00) => res.opcode = Opcodes::Srl,
+                (0x5, 0x20) => res.opcode = Opcodes::Sra,
+                (0x6, 0x00) => res.opcode = Opcodes::Or,
+                (0x7, 0x00) => res.opcode = Opcodes::And,
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x13 {
             // I type
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.imm = instr >> 20;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.imm = sign_extend(instr >> 20, 12);
 
             match res.funct3 {
                 0x00 => res.opcode = Opcodes::Addi,
                 0x01 => {
-                    res.funct7 = (instr >> 25) & 0x3F;
-                    res.opcode = Opcodes::Slli;
+                    res.funct7 = instr >> 25;
+                    res.imm &= 0x1F;
+                    match res.funct7 {
+                        0x00 => res.opcode = Opcodes::Slli,
+                        _ => return Err(Exception::IllegalInstruction(instr)),
+                    };
                 }
                 0x02 => res.opcode = Opcodes::Slti,
                 0x03 => res.opcode = Opcodes::Sltiu,
                 0x04 => res.opcode = Opcodes::Xori,
                 0x05 => {
-                    res.funct7 = (instr >> 25) & 0x3F;
+                    res.funct7 = instr >> 25;
+                    res.imm &= 0x1F;
                     match res.funct7 {
                         0x00 => res.opcode = Opcodes::Srli,
                         0x20 => res.opcode = Opcodes::Srai,
-                        _ => panic!("Invalid instruction"),
+                        _ => return Err(Exception::IllegalInstruction(instr)),
                     };
                 }
                 0x06 => res.opcode = Opcodes::Ori,
                 0x07 => res.opcode = Opcodes::Andi,
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x3 {
             // Load I type
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.imm = instr >> 20;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.imm = sign_extend(instr >> 20, 12);
 
             match res.funct3 {
                 0x00 => res.opcode = Opcodes::Lb,
@@ -108,33 +111,40 @@ impl Instruction {
                 0x02 => res.opcode = Opcodes::Lw,
                 0x04 => res.opcode = Opcodes::Lbu,
                 0x05 => res.opcode = Opcodes::Lhu,
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x23 {
             // S type
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.rs2 = (instr >> 20) & 0xF;
-            res.imm = (instr >> 7) & 0xF;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.rs2 = (instr >> 20) & 0x1F;
             res.imm_1 = instr >> 25;
+            res.imm = sign_extend(res.imm_1 << 5 | (instr >> 7) & 0x1F, 12);
 
             match res.funct3 {
                 0x0 => res.opcode = Opcodes::Sb,
                 0x1 => res.opcode = Opcodes::Sh,
                 0x2 => res.opcode = Opcodes::Sw,
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x63 {
             // B type
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.rs2 = (instr >> 20) & 0xF;
-            res.imm = (instr >> 7) & 0xF;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.rs2 = (instr >> 20) & 0x1F;
             res.imm_1 = instr >> 25;
+            // imm[12|10:5] rs2 rs1 funct3 imm[4:1|11]
+            res.imm = sign_extend(
+                (instr >> 31) << 12
+                    | ((instr >> 7) & 0x1) << 11
+                    | ((instr >> 25) & 0x3F) << 5
+                    | ((instr >> 8) & 0xF) << 1,
+                13,
+            );
 
             match res.funct3 {
                 0x0 => res.opcode = Opcodes::Beq,
@@ -143,70 +153,136 @@ impl Instruction {
                 0x5 => res.opcode = Opcodes::Bge,
                 0x6 => res.opcode = Opcodes::Bltu,
                 0x7 => res.opcode = Opcodes::Bgeu,
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x6f {
             // J type
             res.opcode = Opcodes::Jal;
-            res.rd = (instr >> 7) & 0xF;
-            res.imm = instr >> 12;
-            res
+            res.rd = (instr >> 7) & 0x1F;
+            // imm[20|10:1|11|19:12] rd
+            res.imm = sign_extend(
+                (instr >> 31) << 20
+                    | instr & 0xFF000
+                    | ((instr >> 20) & 0x1) << 11
+                    | ((instr >> 21) & 0x3FF) << 1,
+                21,
+            );
+            Ok(res)
         } else if instr & 0x7F == 0x67 {
             // I type
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
-            res.imm = instr >> 20;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.imm = sign_extend(instr >> 20, 12);
             res.opcode = Opcodes::Jalr;
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x37 {
             // U type
 
-            res.rd = (instr >> 7) & 0xF;
-            res.imm = instr >> 12;
+            res.rd = (instr >> 7) & 0x1F;
+            res.imm = instr & 0xFFFFF000;
             res.opcode = Opcodes::Lui;
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x17 {
             // U type
-            res.rd = (instr >> 7) & 0xF;
-            res.imm = instr >> 12;
+            res.rd = (instr >> 7) & 0x1F;
+            res.imm = instr & 0xFFFFF000;
             res.opcode = Opcodes::Auipc;
-            res
+            Ok(res)
         } else if instr & 0x7F == 0x73 {
             // I type
 
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
+            res.imm = instr >> 20; // csr address for Zicsr
+            res.funct7 = instr >> 25;
 
-            match res.imm {
-                0x0 => res.opcode = Opcodes::Ecall,
-                0x1 => res.opcode = Opcodes::Ebreak,
-                _ => panic!("Invalid instruction"),
+            match res.funct3 {
+                // sfence.vma rs1, rs2
+                0x0 if res.funct7 == 0x09 && res.rd == 0 => {
+                    res.rs2 = (instr >> 20) & 0x1F;
+                    res.opcode = Opcodes::SfenceVma;
+                }
+                // hfence.vvma / hfence.gvma rs1, rs2
+                0x0 if (res.funct7 == 0x11 || res.funct7 == 0x31) && res.rd == 0 => {
+                    res.rs2 = (instr >> 20) & 0x1F;
+                    res.opcode = if res.funct7 == 0x11 {
+                        Opcodes::HfenceVvma
+                    } else {
+                        Opcodes::HfenceGvma
+                    };
+                }
+                0x0 => match (res.imm, res.rs1, res.rd) {
+                    (0x000, 0, 0) => res.opcode = Opcodes::Ecall,
+                    (0x001, 0, 0) => res.opcode = Opcodes::Ebreak,
+                    (0x102, 0, 0) => res.opcode = Opcodes::Sret,
+                    (0x302, 0, 0) => res.opcode = Opcodes::Mret,
+                    (0x105, 0, 0) => res.opcode = Opcodes::Wfi,
+                    _ => return Err(Exception::IllegalInstruction(instr)),
+                },
+                // hypervisor virtual-machine loads (rs2 picks the variant)
+                // and stores (rd = 0)
+                0x4 => {
+                    res.rs2 = (instr >> 20) & 0x1F;
+                    res.opcode = match (res.funct7, res.rs2, res.rd) {
+                        (0x30, 0, _) => Opcodes::HlvB,
+                        (0x30, 1, _) => Opcodes::HlvBu,
+                        (0x32, 0, _) => Opcodes::HlvH,
+                        (0x32, 1, _) => Opcodes::HlvHu,
+                        (0x32, 3, _) => Opcodes::HlvxHu,
+                        (0x34, 0, _) => Opcodes::HlvW,
+                        (0x34, 3, _) => Opcodes::HlvxWu,
+                        (0x31, _, 0) => Opcodes::HsvB,
+                        (0x33, _, 0) => Opcodes::HsvH,
+                        (0x35, _, 0) => Opcodes::HsvW,
+                        _ => return Err(Exception::IllegalInstruction(instr)),
+                    };
+                    // the register fields are not a csr address
+                    res.imm = 0;
+                }
+                0x1 => res.opcode = Opcodes::Csrrw,
+                0x2 => res.opcode = Opcodes::Csrrs,
+                0x3 => res.opcode = Opcodes::Csrrc,
+                0x5 => res.opcode = Opcodes::Csrrwi,
+                0x6 => res.opcode = Opcodes::Csrrsi,
+                0x7 => res.opcode = Opcodes::Csrrci,
+                _ => return Err(Exception::IllegalInstruction(instr)),
             }
 
-            res
+            Ok(res)
         } else if instr & 0x7F == 0xF {
             // Fence
 
             res.opcode = Opcodes::Fence;
-            res.rd = (instr >> 7) & 0xF;
-            res.funct3 = (instr >> 12) & 0x3;
-            res.rs1 = (instr >> 15) & 0xF;
+            res.rd = (instr >> 7) & 0x1F;
+            res.funct3 = (instr >> 12) & 0x7;
+            res.rs1 = (instr >> 15) & 0x1F;
             res.succ = (instr >> 20) & 0x7;
             res.pred = (instr >> 24) & 0x7;
             res.fm = instr >> 28;
 
-            res
+            // fence.i is a no-op as well, there is no instruction cache
+            if res.funct3 > 0x1 {
+                return Err(Exception::IllegalInstruction(instr));
+            }
+
+            Ok(res)
         } else {
-            panic!("Invalid instruction")
+            Err(Exception::IllegalInstruction(instr))
         }
     }
 }
 
+// sign extends the low `bits` bits of val
+pub(crate) fn sign_extend(val: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((val << shift) as i32) >> shift) as u32
+}
+
 // u32 returned is le(little endian)
 pub(crate) fn into_u32(instr: &[u8]) -> u32 {
     (instr[0] as u32) << 24 | (instr[1] as u32) << 16 | (instr[2] as u32) << 8 | (instr[3] as u32)
@@ -227,6 +303,7 @@ mod tests {
     use crate::vm::{
         instruction::{into_byte, into_u32, Instruction},
         opcodes::Opcodes,
+        trap::Exception,
     };
 
     #[test]
@@ -248,7 +325,7 @@ mod tests {
         dbg!(format!("rs2 : {:b}", (val >> 20) & 0xF));
         dbg!(format!("funct7 : {:b}", val >> 25));
 
-        let instr = Instruction::decode(&instr_as_bytes);
+        let instr = Instruction::decode(&instr_as_bytes).unwrap();
         assert_eq!(instr.opcode, Opcodes::Add);
         assert_eq!(instr.rd, 10);
         assert_eq!(instr.rs1, 11);
@@ -257,4 +334,17 @@ mod tests {
         let res = into_byte(val);
         assert_eq!(res, instr_as_bytes);
     }
+
+    #[test]
+    fn test_decode_m_extension_is_illegal() {
+        // mulh x10, x11, x12 and div x10, x11, x12 share funct3 with sll and xor
+        for raw in [0x02c59533, 0x02c5c533] {
+            assert_eq!(
+                Instruction::decode(&into_byte(raw)).err(),
+                Some(Exception::IllegalInstruction(raw))
+            );
+        }
+        // and x10, x11, x12 with a reserved funct7
+        assert!(Instruction::decode(&into_byte(0x40c5f533)).is_err());
+    }
 }
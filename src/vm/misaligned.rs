@@ -0,0 +1,138 @@
+// Loads and stores whose address is not a multiple of their size. The
+// hardware either raises the address misaligned exception and leaves it to
+// the trap handler, or splits the access into bytes itself.
+use super::{csr::Privilege, mmu::AccessType, trap::Exception, Vm, BYTE};
+
+// What to do with a misaligned load or store. It only covers plain loads and
+// stores: the hart has no A extension, and AMOs, LR and SC must always raise
+// the misaligned exception when one is added since only Zam allows emulating
+// them.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MisalignedPolicy {
+    Trap,    // address misaligned exception
+    Emulate, // byte by byte, not atomic, counted in misaligned_accesses
+}
+
+impl Vm {
+    // Trap by default, like most hardware
+    pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+        self.misaligned = policy;
+    }
+
+    // Misaligned loads and stores emulated to completion so far
+    pub fn misaligned_accesses(&self) -> u64 {
+        self.misaligned_accesses
+    }
+
+    // Every byte is translated and checked on its own, so an access that
+    // straddles a page can fault on the second page with the address of
+    // the byte in tval
+    pub(crate) fn load_misaligned(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        privilege: Privilege,
+        virt: bool,
+        translation: AccessType,
+    ) -> Result<u32, Exception> {
+        if self.misaligned == MisalignedPolicy::Trap {
+            return Err(Exception::LoadAddressMisaligned(memory_address));
+        }
+        let mut value = 0;
+        for byte in 0..size as u32 {
+            let address = memory_address.wrapping_add(byte);
+            value |= self.load_aligned(BYTE, address, privilege, virt, translation)? << (8 * byte);
+        }
+        self.misaligned_accesses += 1;
+        Ok(value)
+    }
+
+    // Bytes before a faulting one stay written, as when a trap handler
+    // emulates the store
+    pub(crate) fn store_misaligned(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        value: u32,
+        privilege: Privilege,
+        virt: bool,
+    ) -> Result<(), Exception> {
+        if self.misaligned == MisalignedPolicy::Trap {
+            return Err(Exception::StoreAddressMisaligned(memory_address));
+        }
+        for byte in 0..size as u32 {
+            let address = memory_address.wrapping_add(byte);
+            self.store_aligned(BYTE, address, value >> (8 * byte) & 0xFF, privilege, virt)?;
+        }
+        self.misaligned_accesses += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        bus::{Bus, DRAM_BASE},
+        registers::{BaseIsa, Registers},
+        tests::vm_with_program,
+        trap::Exception,
+        Vm, HALF_WORD, WORD_SIZE,
+    };
+
+    use super::MisalignedPolicy;
+
+    #[test]
+    fn test_misaligned_traps() {
+        let mut vm = vm_with_program(&[
+            0x10102283, // lw x5, 0x101(x0)
+        ]);
+        assert_eq!(
+            vm.load(HALF_WORD, 0x103),
+            Err(Exception::LoadAddressMisaligned(0x103))
+        );
+        assert_eq!(
+            vm.store(WORD_SIZE, 0x102, 1),
+            Err(Exception::StoreAddressMisaligned(0x102))
+        );
+
+        vm.csr.mtvec = 0x40;
+        vm.step();
+        assert_eq!(vm.csr.mcause, 4);
+        assert_eq!(vm.csr.mtval, 0x101);
+        assert_eq!(vm.misaligned_accesses(), 0);
+    }
+
+    #[test]
+    fn test_misaligned_emulation() {
+        let mut vm = vm_with_program(&[
+            0x10102283, // lw x5, 0x101(x0)
+            0x106013A3, // sh x6, 0x107(x0)
+        ]);
+        vm.set_misaligned_policy(MisalignedPolicy::Emulate);
+        vm.store(WORD_SIZE, 0x100, 0x4433_2211).unwrap();
+        vm.store(WORD_SIZE, 0x104, 0x8877_6655).unwrap();
+        vm.set_register(6, 0xBBAA);
+
+        vm.step();
+        assert_eq!(vm.get_register(5), 0x5544_3322);
+        vm.step();
+        assert_eq!(vm.load(WORD_SIZE, 0x104), Ok(0xAA77_6655));
+        assert_eq!(vm.load(HALF_WORD, 0x107), Ok(0xBBAA));
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x8);
+        assert_eq!(vm.misaligned_accesses(), 3);
+
+        // an access that faults part way is not counted
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        vm.set_misaligned_policy(MisalignedPolicy::Emulate);
+        let end = (DRAM_BASE + 0x1000) as u32;
+        assert_eq!(
+            vm.load(WORD_SIZE, end - 2),
+            Err(Exception::LoadAccessFault(end))
+        );
+        assert_eq!(
+            vm.store(WORD_SIZE, end - 1, 0),
+            Err(Exception::StoreAccessFault(end))
+        );
+        assert_eq!(vm.misaligned_accesses(), 0);
+    }
+}
@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+
+use super::{
+    csr::{self, Privilege},
+    instruction::{into_byte, into_u32},
+    trap::Exception,
+    Vm, WORD_SIZE,
+};
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+const TLB_ENTRIES: usize = 64;
+const PTE_SIZE: usize = 4;
+const VPN_BITS: u32 = 10; // per level, Sv32 has two
+
+// satp fields on RV32 (Sv32)
+const SATP32_MODE_SV32: u32 = 1 << 31;
+const SATP32_ASID_SHIFT: u32 = 22;
+const SATP32_ASID: u32 = 0x1FF << SATP32_ASID_SHIFT;
+const SATP32_PPN: u32 = 0x3F_FFFF;
+
+// hgatp fields on RV32 (Sv32x4)
+const HGATP32_MODE_SV32X4: u32 = 1 << 31;
+const HGATP32_VMID_SHIFT: u32 = 22;
+const HGATP32_VMID: u32 = 0x7F << HGATP32_VMID_SHIFT;
+
+// guest physical addresses under Sv32x4 are 34 bits wide
+const SV32X4_GPA_BITS: u32 = 34;
+
+// page table entry flags
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(&self, address: u32) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(address),
+            AccessType::Load => Exception::LoadPageFault(address),
+            AccessType::Store => Exception::StorePageFault(address),
+        }
+    }
+
+    // G-stage fault, `gpa` is the guest physical address that missed
+    fn guest_page_fault(&self, address: u32, gpa: u64) -> Exception {
+        let htval = (gpa >> 2) as u32;
+        match self {
+            AccessType::Instruction => Exception::InstructionGuestPageFault(address, htval),
+            AccessType::Load => Exception::LoadGuestPageFault(address, htval),
+            AccessType::Store => Exception::StoreGuestPageFault(address, htval),
+        }
+    }
+
+    pub(crate) fn access_fault(&self, address: u32) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault(address),
+            AccessType::Store => Exception::StoreAccessFault(address),
+        }
+    }
+}
+
+// What to do when a leaf PTE is used with A clear, or written with D clear
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AdPolicy {
+    Update, // set the bits in the page table (Svadu)
+    Fault,  // raise a page fault and let software set them (Svade)
+}
+
+// Translation schemes selected by satp.MODE. Sv39, Sv48 and Sv57 (and Svnapot
+// and Svpbmt with them) only exist for XLEN=64, this hart is RV32 only so its
+// satp.MODE can select nothing but Bare and Sv32.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum PagingMode {
+    Bare,
+    Sv32,
+    Sv32x4, // G-stage of the hypervisor extension, Sv32 with a 16 KiB root
+}
+
+impl PagingMode {
+    fn levels(&self) -> usize {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 | PagingMode::Sv32x4 => 2,
+        }
+    }
+
+    // the x4 G-stage scheme widens the root index by 2 bits
+    fn root_index_bits(&self) -> u32 {
+        match self {
+            PagingMode::Sv32x4 => VPN_BITS + 2,
+            _ => VPN_BITS,
+        }
+    }
+
+    // width of a virtual address
+    fn va_bits(&self) -> u32 {
+        PAGE_SHIFT + VPN_BITS * (self.levels() as u32 - 1) + self.root_index_bits()
+    }
+}
+
+// Decoded satp
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct Satp {
+    pub(crate) mode: PagingMode,
+    pub(crate) asid: u32,
+    pub(crate) ppn: u64,
+}
+
+impl Satp {
+    pub(crate) fn from_rv32(satp: u32) -> Self {
+        Self {
+            mode: if satp & SATP32_MODE_SV32 != 0 {
+                PagingMode::Sv32
+            } else {
+                PagingMode::Bare
+            },
+            asid: (satp & SATP32_ASID) >> SATP32_ASID_SHIFT,
+            ppn: (satp & SATP32_PPN) as u64,
+        }
+    }
+
+    // hgatp, the VMID takes the place of the ASID
+    pub(crate) fn from_hgatp(hgatp: u32) -> Self {
+        Self {
+            mode: if hgatp & HGATP32_MODE_SV32X4 != 0 {
+                PagingMode::Sv32x4
+            } else {
+                PagingMode::Bare
+            },
+            asid: (hgatp & HGATP32_VMID) >> HGATP32_VMID_SHIFT,
+            ppn: (hgatp & SATP32_PPN) as u64,
+        }
+    }
+}
+
+// Why a translation failed, turned into the exception for the access type
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Fault {
+    Page,
+    Access,
+    Guest(u64), // G-stage page fault on this guest physical address
+}
+
+// Which translation a page table walk performs
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Stage {
+    Single,            // satp, no virtualization
+    VirtualSupervisor, // vsatp, page tables live in guest physical memory
+    Guest,             // hgatp, guest physical to host physical
+}
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    asid: u32,
+    ppn: u64,   // physical page of the 4 KiB page the vpn maps to
+    flags: u64, // leaf pte flags
+}
+
+// Software TLB caching leaf translations per 4 KiB virtual page. Megapages
+// take one entry for each of their pages that is touched.
+pub(crate) struct Tlb {
+    entries: HashMap<u64, TlbEntry>,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl Tlb {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn lookup(&self, vpn: u64, asid: u32) -> Option<TlbEntry> {
+        self.entries
+            .get(&vpn)
+            .filter(|entry| entry.flags & PTE_G != 0 || entry.asid == asid)
+            .copied()
+    }
+
+    fn insert(&mut self, vpn: u64, entry: TlbEntry) {
+        if self.entries.len() >= TLB_ENTRIES {
+            self.entries.clear();
+        }
+        self.entries.insert(vpn, entry);
+    }
+
+    // sfence.vma, `None` operands mean x0: all addresses / all address spaces.
+    // Global mappings survive an asid specific flush.
+    pub(crate) fn flush(&mut self, address: Option<u64>, asid: Option<u32>) {
+        let vpn = address.map(|address| address >> PAGE_SHIFT);
+        self.entries.retain(|entry_vpn, entry| {
+            let address_match = vpn.is_none_or(|vpn| vpn == *entry_vpn);
+            let asid_match = asid.is_none_or(|asid| entry.flags & PTE_G == 0 && asid == entry.asid);
+            !(address_match && asid_match)
+        });
+    }
+}
+
+impl Vm {
+    // Svadu by default
+    pub fn set_ad_policy(&mut self, policy: AdPolicy) {
+        self.ad_policy = policy;
+    }
+
+    // TLB hits and misses so far
+    pub fn tlb_stats(&self) -> (u64, u64) {
+        (self.tlb.hits, self.tlb.misses)
+    }
+
+    // Virtual to physical translation for an access made by the hart from the
+    // current privilege (or MPP and MPV under mstatus.MPRV for loads and
+    // stores)
+    pub(crate) fn translate(&mut self, address: u32, access: AccessType) -> Result<u64, Exception> {
+        let (privilege, virt) = match access {
+            AccessType::Instruction => (self.privilege, self.virt),
+            _ => (self.data_privilege(), self.data_virt()),
+        };
+        self.translate_as(address, access, privilege, virt)
+    }
+
+    // Translation as if made from `privilege`, two-stage when `virt` is set
+    pub(crate) fn translate_as(
+        &mut self,
+        address: u32,
+        access: AccessType,
+        privilege: Privilege,
+        virt: bool,
+    ) -> Result<u64, Exception> {
+        let result = if virt {
+            self.guest_access = true;
+            self.translate_guest(address as u64, privilege, access)
+        } else {
+            let satp = Satp::from_rv32(self.csr.satp);
+            self.translate_with(address as u64, satp, privilege, access)
+        };
+
+        result.map_err(|fault| match fault {
+            Fault::Page => access.page_fault(address),
+            Fault::Access => access.access_fault(address),
+            Fault::Guest(gpa) => access.guest_page_fault(address, gpa),
+        })
+    }
+
+    // VS-stage under vsatp, then G-stage under hgatp. Guest translations
+    // are not cached, so the hfence instructions have nothing to flush.
+    fn translate_guest(
+        &mut self,
+        address: u64,
+        privilege: Privilege,
+        access: AccessType,
+    ) -> Result<u64, Fault> {
+        let vsatp = Satp::from_rv32(self.csr.vsatp);
+        let gpa = if vsatp.mode == PagingMode::Bare {
+            address
+        } else {
+            let entry = self.walk(
+                address >> PAGE_SHIFT,
+                vsatp,
+                privilege,
+                access,
+                Stage::VirtualSupervisor,
+            )?;
+            entry.ppn << PAGE_SHIFT | address & (PAGE_SIZE - 1)
+        };
+        self.guest_physical(gpa, access)
+    }
+
+    // G-stage translation, every access is checked as a U mode one
+    fn guest_physical(&mut self, gpa: u64, access: AccessType) -> Result<u64, Fault> {
+        let hgatp = Satp::from_hgatp(self.csr.hgatp);
+        if hgatp.mode == PagingMode::Bare {
+            return Ok(gpa);
+        }
+        if gpa >> SV32X4_GPA_BITS != 0 {
+            return Err(Fault::Guest(gpa));
+        }
+        match self.walk(
+            gpa >> PAGE_SHIFT,
+            hgatp,
+            Privilege::User,
+            access,
+            Stage::Guest,
+        ) {
+            Ok(entry) => Ok(entry.ppn << PAGE_SHIFT | gpa & (PAGE_SIZE - 1)),
+            Err(Fault::Page) => Err(Fault::Guest(gpa)),
+            Err(fault) => Err(fault),
+        }
+    }
+
+    // Translation of `address` under `satp`
+    fn translate_with(
+        &mut self,
+        address: u64,
+        satp: Satp,
+        privilege: Privilege,
+        access: AccessType,
+    ) -> Result<u64, Fault> {
+        if privilege == Privilege::Machine || satp.mode == PagingMode::Bare {
+            return Ok(address);
+        }
+
+        let vpn = (address >> PAGE_SHIFT) & ((1 << (satp.mode.va_bits() - PAGE_SHIFT)) - 1);
+        let offset = address & (PAGE_SIZE - 1);
+
+        if let Some(entry) = self.tlb.lookup(vpn, satp.asid) {
+            // a store to a clean page has to go back to the page table
+            if access != AccessType::Store || entry.flags & PTE_D != 0 {
+                self.tlb.hits += 1;
+                self.check_permissions(entry.flags, privilege, access, self.csr.mstatus)?;
+                return Ok(entry.ppn << PAGE_SHIFT | offset);
+            }
+        }
+        self.tlb.misses += 1;
+
+        let entry = self.walk(vpn, satp, privilege, access, Stage::Single)?;
+        self.tlb.insert(vpn, entry);
+        Ok(entry.ppn << PAGE_SHIFT | offset)
+    }
+
+    fn walk(
+        &mut self,
+        vpn: u64,
+        satp: Satp,
+        privilege: Privilege,
+        access: AccessType,
+        stage: Stage,
+    ) -> Result<TlbEntry, Fault> {
+        let mode = satp.mode;
+        let mut table = satp.ppn << PAGE_SHIFT;
+        let mut level = mode.levels() - 1;
+
+        // SUM and MXR of the stage, mstatus.MXR applies to both guest stages
+        let mxr = self.csr.mstatus & csr::MSTATUS_MXR;
+        let status = match stage {
+            Stage::Single => self.csr.mstatus,
+            Stage::VirtualSupervisor => self.csr.vsstatus | mxr,
+            Stage::Guest => mxr,
+        };
+
+        loop {
+            let index_bits = if level == mode.levels() - 1 {
+                mode.root_index_bits()
+            } else {
+                VPN_BITS
+            };
+            let index = (vpn >> (VPN_BITS * level as u32)) & ((1 << index_bits) - 1);
+            let pte_address = table + index * PTE_SIZE as u64;
+            // VS-stage tables are in guest physical memory
+            let pte_address = match stage {
+                Stage::VirtualSupervisor => self.guest_physical(pte_address, AccessType::Load)?,
+                _ => pte_address,
+            };
+            // implicit page table accesses are checked by PMP as S mode ones
+            if !self.csr.pmp.allows(
+                pte_address,
+                PTE_SIZE,
+                Privilege::Supervisor,
+                AccessType::Load,
+            ) {
+                return Err(Fault::Access);
+            }
+            let mut pte = self.read_pte(pte_address).ok_or(Fault::Access)?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(Fault::Page);
+            }
+
+            let mut ppn = pte >> 10;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level, leaf-only bits must be clear
+                if level == 0 || pte & (PTE_D | PTE_A | PTE_U) != 0 {
+                    return Err(Fault::Page);
+                }
+                level -= 1;
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            self.check_permissions(pte, privilege, access, status)?;
+
+            // superpages must be aligned to their size
+            let superpage_mask = (1 << (VPN_BITS * level as u32)) - 1;
+            if ppn & superpage_mask != 0 {
+                return Err(Fault::Page);
+            }
+
+            let dirty = access == AccessType::Store;
+            if pte & PTE_A == 0 || (dirty && pte & PTE_D == 0) {
+                match self.ad_policy {
+                    AdPolicy::Fault => return Err(Fault::Page),
+                    AdPolicy::Update => {
+                        // the G-stage must allow the guest to write its PTE
+                        if stage == Stage::VirtualSupervisor {
+                            let gpa = table + index * PTE_SIZE as u64;
+                            self.guest_physical(gpa, AccessType::Store)?;
+                        }
+                        if !self.csr.pmp.allows(
+                            pte_address,
+                            PTE_SIZE,
+                            Privilege::Supervisor,
+                            AccessType::Store,
+                        ) {
+                            return Err(Fault::Access);
+                        }
+                        pte |= PTE_A;
+                        if dirty {
+                            pte |= PTE_D;
+                        }
+                        // page tables in ROM can not take the update
+                        if !self.bus.is_ram(pte_address, PTE_SIZE) {
+                            return Err(Fault::Access);
+                        }
+                        self.write_pte(pte_address, pte).ok_or(Fault::Access)?;
+                    }
+                }
+            }
+
+            // the untranslated low vpn bits pass through
+            ppn = (ppn & !superpage_mask) | (vpn & superpage_mask);
+
+            return Ok(TlbEntry {
+                asid: satp.asid,
+                ppn,
+                flags: pte & 0xFF,
+            });
+        }
+    }
+
+    fn read_pte(&self, address: u64) -> Option<u64> {
+        if !self.in_memory(PTE_SIZE, address) {
+            return None;
+        }
+        Some(into_u32(&self.mem_read(WORD_SIZE, address)?) as u64)
+    }
+
+    fn write_pte(&mut self, address: u64, pte: u64) -> Option<()> {
+        self.mem_write(WORD_SIZE, address, &into_byte(pte as u32))
+    }
+
+    // `status` supplies SUM and MXR, mstatus or the guest stage's view of it
+    fn check_permissions(
+        &self,
+        flags: u64,
+        privilege: Privilege,
+        access: AccessType,
+        status: u32,
+    ) -> Result<(), Fault> {
+        let user_page = flags & PTE_U != 0;
+
+        // S mode reaches user pages only for data and only with SUM
+        let allowed = match privilege {
+            Privilege::User => user_page,
+            _ => {
+                !user_page || (access != AccessType::Instruction && status & csr::MSTATUS_SUM != 0)
+            }
+        } && match access {
+            AccessType::Instruction => flags & PTE_X != 0,
+            AccessType::Load => {
+                flags & PTE_R != 0 || (status & csr::MSTATUS_MXR != 0 && flags & PTE_X != 0)
+            }
+            AccessType::Store => flags & PTE_W != 0,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(Fault::Page)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        instruction::into_byte,
+        tests::allow_all_memory,
+        trap::Exception,
+        Vm, WORD_SIZE,
+    };
+
+    use super::{AccessType, AdPolicy, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+
+    const ROOT: u64 = 0x10000;
+    const LEAF_TABLE: u64 = 0x11000;
+
+    fn write_pte(vm: &mut Vm, address: u64, pte: u64) {
+        vm.write_pte(address, pte).unwrap();
+    }
+
+    fn read_pte(vm: &Vm, address: u64) -> u64 {
+        vm.read_pte(address).unwrap()
+    }
+
+    // 0x4000_0000 -> 0x2_0000 user page, 0x8000_0000 -> 0x40_0000 megapage
+    fn vm_with_page_table() -> Vm {
+        let mut vm = Vm::initialize();
+        allow_all_memory(&mut vm);
+        write_pte(&mut vm, ROOT + 0x100 * 4, (LEAF_TABLE >> 12) << 10 | PTE_V);
+        write_pte(
+            &mut vm,
+            LEAF_TABLE,
+            (0x20 << 10) | PTE_V | PTE_R | PTE_W | PTE_X | PTE_U,
+        );
+        write_pte(
+            &mut vm,
+            ROOT + 0x200 * 4,
+            (0x400 << 10) | PTE_V | PTE_R | PTE_W | PTE_A | PTE_D,
+        );
+        vm.csr.satp = 1 << 31 | (ROOT >> 12) as u32;
+        vm.privilege = Privilege::Supervisor;
+        vm
+    }
+
+    #[test]
+    fn test_sv32_walk_and_tlb() {
+        let mut vm = vm_with_page_table();
+        vm.privilege = Privilege::User;
+        vm.mem_write(WORD_SIZE, 0x20004, &into_byte(0xdeadbeef))
+            .unwrap();
+
+        assert_eq!(vm.load(WORD_SIZE, 0x4000_0004), Ok(0xdeadbeef));
+        assert_eq!(vm.tlb_stats(), (0, 1));
+        assert_eq!(read_pte(&vm, LEAF_TABLE) & (PTE_A | PTE_D), PTE_A);
+
+        assert_eq!(vm.load(WORD_SIZE, 0x4000_0008), Ok(0));
+        assert_eq!(vm.tlb_stats(), (1, 1));
+
+        // the first store walks again to set D
+        assert_eq!(vm.store(WORD_SIZE, 0x4000_0008, 7), Ok(()));
+        assert_eq!(vm.tlb_stats(), (1, 2));
+        assert_eq!(read_pte(&vm, LEAF_TABLE) & PTE_D, PTE_D);
+        assert_eq!(vm.load(WORD_SIZE, 0x4000_0008), Ok(7));
+
+        // user mode cannot reach supervisor pages
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x8000_0000),
+            Err(Exception::LoadPageFault(0x8000_0000))
+        );
+        assert_eq!(
+            vm.translate(0x5000_0000, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x5000_0000))
+        );
+    }
+
+    #[test]
+    fn test_supervisor_permissions() {
+        let mut vm = vm_with_page_table();
+
+        assert_eq!(vm.translate(0x8012_3456, AccessType::Load), Ok(0x0052_3456));
+        assert_eq!(
+            vm.translate(0x8000_0000, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x8000_0000))
+        );
+
+        // user pages need SUM, and are never executable from S mode
+        assert_eq!(
+            vm.translate(0x4000_0010, AccessType::Load),
+            Err(Exception::LoadPageFault(0x4000_0010))
+        );
+        vm.csr.mstatus |= csr::MSTATUS_SUM;
+        assert_eq!(vm.translate(0x4000_0010, AccessType::Load), Ok(0x2_0010));
+        assert_eq!(
+            vm.translate(0x4000_0010, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x4000_0010))
+        );
+
+        // M mode with MPRV translates data like MPP
+        vm.privilege = Privilege::Machine;
+        assert_eq!(vm.translate(0x8000_0000, AccessType::Load), Ok(0x8000_0000));
+        vm.csr.mstatus &= !csr::MSTATUS_MPP;
+        vm.csr.mstatus |= csr::MSTATUS_MPRV | 1 << csr::MSTATUS_MPP_SHIFT;
+        assert_eq!(vm.translate(0x8000_0000, AccessType::Load), Ok(0x40_0000));
+    }
+
+    #[test]
+    fn test_satp_written_by_csrrw() {
+        let mut vm = vm_with_page_table();
+        vm.csr.satp = 0;
+        vm.privilege = Privilege::Machine;
+        vm.mem_write(WORD_SIZE, 0x40_0004, &into_byte(0xdeadbeef))
+            .unwrap();
+        // csrrw x0, satp, x5; lw x6, 4(x7) with MPRV loading as S mode
+        vm.mem_write(WORD_SIZE, 0, &into_byte(0x18029073)).unwrap();
+        vm.mem_write(WORD_SIZE, 4, &into_byte(0x0043a303)).unwrap();
+        vm.set_register(5, 1 << 31 | (ROOT >> 12) as u32);
+        vm.set_register(7, 0x8000_0000);
+        vm.csr.mstatus &= !csr::MSTATUS_MPP;
+        vm.csr.mstatus |= csr::MSTATUS_MPRV | 1 << csr::MSTATUS_MPP_SHIFT;
+
+        assert_eq!(vm.run_program(), Ok(()));
+        assert_eq!(vm.csr.satp, 1 << 31 | (ROOT >> 12) as u32);
+        assert_eq!(vm.run_program(), Ok(()));
+        assert_eq!(vm.get_register(6), 0xdeadbeef);
+        assert_eq!(vm.tlb_stats(), (0, 1));
+    }
+
+    #[test]
+    fn test_ad_fault_policy_and_sfence() {
+        let mut vm = vm_with_page_table();
+        vm.set_ad_policy(AdPolicy::Fault);
+        vm.csr.mstatus |= csr::MSTATUS_SUM;
+
+        // A is clear on the user page
+        assert_eq!(
+            vm.translate(0x4000_0000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x4000_0000))
+        );
+        assert_eq!(read_pte(&vm, LEAF_TABLE) & PTE_A, 0);
+
+        // the megapage is cached, remapping it only shows after sfence.vma
+        assert_eq!(vm.translate(0x8000_0000, AccessType::Store), Ok(0x40_0000));
+        write_pte(
+            &mut vm,
+            ROOT + 0x200 * 4,
+            (0x800 << 10) | PTE_V | PTE_R | PTE_W | PTE_A | PTE_D,
+        );
+        assert_eq!(vm.translate(0x8000_0000, AccessType::Store), Ok(0x40_0000));
+        vm.tlb.flush(Some(0x8000_0000), None);
+        assert_eq!(vm.translate(0x8000_0000, AccessType::Store), Ok(0x80_0000));
+    }
+}
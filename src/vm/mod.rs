@@ -1,341 +1,691 @@
-mod registers;
-use std::{fs::File, io::Read};
+pub(crate) mod registers;
+use std::{
+    fs::File,
+    io::{self, Read},
+};
 
+use aplic::Aplic;
+use bus::{Bus, MapError, RegionKind};
+use clint::Clint;
+use csr::Privilege;
+use device::{Attached, Exit};
+use elf::Elf;
+use framebuffer::Framebuffer;
+use htif::Htif;
 use instruction::{into_byte, into_u32, Instruction};
+use misaligned::MisalignedPolicy;
+use mmu::{AccessType, AdPolicy, Tlb};
 use opcodes::Opcodes;
-use registers::Registers;
+use plic::Plic;
+use registers::{BaseIsa, Registers};
+use trap::Exception;
+use trigger::DebugHalt;
+use virtio::VirtioMmio;
+
+mod aplic;
+
+pub(crate) mod bus;
+
+pub(crate) mod clint;
+
+mod csr;
+
+pub(crate) mod device;
+
+mod elf;
+
+mod fdt;
+
+pub(crate) mod finisher;
+
+pub(crate) mod framebuffer;
+
+mod htif;
+
+mod hypervisor;
+
+mod imsic;
 
 mod instruction;
 
+pub(crate) mod misaligned;
+
+pub(crate) mod mmu;
+
 mod opcodes;
 
+mod plic;
+
+mod pmp;
+
+pub(crate) mod rtc;
+
+mod trap;
+
+mod trigger;
+
+pub(crate) mod uart;
+
+pub(crate) mod virtio;
+
 const WORD_SIZE: usize = 4; // word size = 32 bits = 8bits * 4
 const HALF_WORD: usize = 2;
 const BYTE: usize = 1;
+#[cfg(test)]
 const MAX_ADDRESSABLE_MEMORY: usize = 1 << 32; // ????
 const TOTAL_REGISTERS: usize = 33;
+pub(crate) const INTERRUPT_SOURCES: usize = 32; // unless the VM is built with_sources
 
-struct Vm {
+pub struct Vm {
     register: [u32; TOTAL_REGISTERS],
-    memory: Vec<u8>,
+    bus: Bus,
+    base: BaseIsa,
+    csr: csr::Csr,
+    privilege: Privilege,
+    virt: bool, // V, with S and U mode as VS and VU mode
+    tlb: Tlb,
+    ad_policy: AdPolicy,
+    misaligned: MisalignedPolicy,
+    misaligned_accesses: u64, // emulated so far
+    clint: Clint,
+    plic: Plic,
+    aplic: Aplic,
+    devices: Vec<Attached>,
+    virtio: Vec<VirtioMmio>,
+    framebuffer: Option<Framebuffer>,
+    htif: Option<Htif>, // the program has tohost
+    waiting: bool,      // stalled in wfi
+    next_pc: u32,       // pc after the instruction being executed retires
+    // the instruction made a guest virtual access, a fault on it sets GVA
+    guest_access: bool,
+    halted: Option<DebugHalt>,    // in debug mode
+    debug_request: Option<usize>, // trigger that wants debug mode
+    exit: Option<Exit>,           // a device stopped the VM
 }
 
-// TODO: work on sign extension
-
 impl Vm {
+    #[cfg(test)]
     fn initialize() -> Self {
-        Self {
-            register: [0; TOTAL_REGISTERS],
-            memory: vec![0; MAX_ADDRESSABLE_MEMORY],
+        Self::with_base(BaseIsa::Rv32i)
+    }
+
+    #[cfg(test)]
+    fn with_base(base: BaseIsa) -> Self {
+        Self::with_bus(base, Bus::flat())
+    }
+
+    pub fn with_bus(base: BaseIsa, bus: Bus) -> Self {
+        Self::with_sources(base, bus, INTERRUPT_SOURCES).unwrap()
+    }
+
+    // Interrupt sources 1..=sources on the PLIC and the APLIC
+    pub fn with_sources(base: BaseIsa, bus: Bus, sources: usize) -> Result<Self, MapError> {
+        if !(1..=plic::MAX_SOURCES).contains(&sources) {
+            return Err(MapError::Sources(sources));
         }
+        Ok(Self {
+            register: [0; TOTAL_REGISTERS],
+            bus,
+            base,
+            csr: csr::Csr::new(base),
+            privilege: Privilege::Machine,
+            virt: false,
+            tlb: Tlb::new(),
+            ad_policy: AdPolicy::Update,
+            misaligned: MisalignedPolicy::Trap,
+            misaligned_accesses: 0,
+            clint: Clint::new(),
+            plic: Plic::new(sources),
+            aplic: Aplic::new(sources, Privilege::Supervisor),
+            devices: Vec::new(),
+            virtio: Vec::new(),
+            framebuffer: None,
+            htif: None,
+            waiting: false,
+            next_pc: 0,
+            guest_access: false,
+            halted: None,
+            debug_request: None,
+            exit: None,
+        })
     }
 
-    fn fetch(&self) -> &[u8] {
-        self.mem_read(WORD_SIZE, Registers::Pc as u32)
+    fn fetch(&mut self) -> Result<Vec<u8>, Exception> {
+        let pc = self.get_register(Registers::Pc as u32);
+        let address = self.translate(pc, AccessType::Instruction)?;
+        if !self.in_memory(WORD_SIZE, address)
+            || !self
+                .csr
+                .pmp
+                .allows(address, WORD_SIZE, self.privilege, AccessType::Instruction)
+        {
+            return Err(Exception::InstructionAccessFault(pc));
+        }
+        self.mem_read(WORD_SIZE, address)
+            .ok_or(Exception::InstructionAccessFault(pc))
     }
 
-    fn load_program_from_file(&mut self, path: String) {
-        let mut file = File::open(path).unwrap();
+    // An ELF executable goes where its segments say and starts at its entry
+    // point, with HTIF if it has a tohost symbol. Anything else is a raw
+    // image at the start of the first RAM region, execution starts there.
+    pub fn load_program_from_file(&mut self, path: &str) -> io::Result<()> {
+        let mut file = File::open(path)?;
         let mut buf = vec![];
-        file.read(&mut buf).unwrap();
-        // self.memory[self.get_register(Registers::Pc as u32)..self.get_register(Registers::Pc as u32) + buf.len() as u32].clone_from_slice(&buf);
+        file.read_to_end(&mut buf)?;
+        if elf::is_elf(&buf) {
+            return self.load_elf(&buf);
+        }
+        let base = self
+            .bus
+            .regions()
+            .iter()
+            .find(|region| region.kind == RegionKind::Ram)
+            .map(|region| region.base)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no ram to load into"))?;
+        self.bus.write(base, &buf).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "program does not fit in ram")
+        })?;
+        self.set_register(Registers::Pc as u32, base as u32);
+        Ok(())
+    }
+
+    fn load_elf(&mut self, file: &[u8]) -> io::Result<()> {
+        let elf = Elf::parse(file)?;
+        for segment in &elf.segments {
+            self.bus
+                .write(segment.address, &segment.data)
+                .ok_or_else(|| {
+                    let message =
+                        format!("segment at {:#x} does not fit in memory", segment.address);
+                    io::Error::new(io::ErrorKind::InvalidInput, message)
+                })?;
+        }
+        self.set_register(Registers::Pc as u32, elf.entry);
+        self.htif = elf.symbol("tohost").map(|tohost| {
+            let fromhost = elf.symbol("fromhost").map(|fromhost| fromhost as u64);
+            Htif::new(tohost as u64, fromhost)
+        });
+        Ok(())
+    }
+
+    // The memory map, with every attached device
+    pub fn bus(&self) -> &Bus {
+        &self.bus
     }
 
-    fn run_program(&mut self) {
-        let instruction = self.fetch();
-        let instr = Instruction::decode(instruction);
-        self.execute(instr);
+    // Runs for `steps` instructions, or until a device stops the VM or the
+    // hart halts in debug mode
+    pub fn run(&mut self, steps: u64) -> Option<Exit> {
+        for _ in 0..steps {
+            if self.exit.is_some() || self.halted.is_some() {
+                break;
+            }
+            self.step();
+        }
+        self.exit
+    }
+
+    pub fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    // Executes one instruction, faults and pending interrupts are handed to the
+    // guest's trap handlers. Every step is one tick of mtime.
+    pub fn step(&mut self) {
+        // a hart halted in debug mode waits for the debugger, a stopped VM
+        // stays stopped
+        if self.halted.is_some() || self.exit.is_some() {
+            return;
+        }
+        self.clint.mtime = self.clint.mtime.wrapping_add(1);
+        self.tick_devices();
+        self.tick_virtio();
+        self.tick_framebuffer();
+        self.tick_htif();
+        self.update_interrupts();
+
+        if self.waiting {
+            // wfi wakes up on any locally enabled interrupt, even if the
+            // global enable keeps it from being taken
+            if self.csr.pending() & self.csr.mie == 0 {
+                self.fast_forward();
+                return;
+            }
+            self.waiting = false;
+        }
+
+        if let Some(interrupt) = self.pending_interrupt() {
+            self.take_interrupt(interrupt);
+            return;
+        }
+        if let Err(exception) = self.run_program() {
+            match self.debug_request.take() {
+                Some(trigger) => self.enter_debug_mode(trigger),
+                None => self.take_trap(exception),
+            }
+        }
+    }
+
+    fn run_program(&mut self) -> Result<(), Exception> {
+        self.guest_access = false;
+        let pc = self.get_register(Registers::Pc as u32);
+        self.check_triggers(AccessType::Instruction, pc, WORD_SIZE, None)?;
+        let instruction = self.fetch()?;
+        self.check_triggers(
+            AccessType::Instruction,
+            pc,
+            WORD_SIZE,
+            Some(into_u32(&instruction)),
+        )?;
+        let instr = Instruction::decode(&instruction)?;
+        self.check_registers(&instr)?;
+        self.next_pc = self
+            .get_register(Registers::Pc as u32)
+            .wrapping_add(WORD_SIZE as u32);
+        self.execute(instr)?;
         self.update_pc();
+        Ok(())
+    }
+
+    // Platform interrupt lines into mip
+    fn update_interrupts(&mut self) {
+        let mut mip = self.csr.mip & !(csr::MIP_MSIP | csr::MIP_MTIP | csr::MIP_MEIP);
+        if self.clint.msip {
+            mip |= csr::MIP_MSIP;
+        }
+        if self.clint.timer_pending() {
+            mip |= csr::MIP_MTIP;
+        }
+        if self.plic.interrupt(0) {
+            mip |= csr::MIP_MEIP;
+        }
+        self.csr.time = self.clint.mtime;
+        if self.csr.stimecmp_enabled() {
+            mip &= !csr::MIP_STIP;
+            if self.csr.supervisor_timer_pending() {
+                mip |= csr::MIP_STIP;
+            }
+        }
+        let mut seip_line = self.plic.interrupt(1);
+
+        // AIA: the APLIC either signals directly or sends MSIs to the IMSIC
+        // interrupt file of its level, each file signals its own mip bit
+        let aplic_interrupt = self.aplic.interrupt();
+        let file = match self.aplic.level {
+            Privilege::Machine => {
+                if aplic_interrupt {
+                    mip |= csr::MIP_MEIP;
+                }
+                &mut self.csr.imsic.machine
+            }
+            _ => {
+                seip_line |= aplic_interrupt;
+                &mut self.csr.imsic.supervisor
+            }
+        };
+        for identity in self.aplic.take_msis() {
+            file.set_pending(identity);
+        }
+        if self.csr.imsic.machine.interrupt() {
+            mip |= csr::MIP_MEIP;
+        }
+        seip_line |= self.csr.imsic.supervisor.interrupt();
+
+        self.csr.mip = mip;
+        self.csr.seip_line = seip_line;
+    }
+
+    // Interrupt request from a device, wired to the same source number on
+    // the PLIC and the APLIC
+    fn set_interrupt_line(&mut self, source: usize, level: bool) {
+        self.plic.set_level(source, level);
+        self.aplic.set_input(source, level);
+    }
+
+    // Nothing can happen before the next timer or device deadline while in
+    // wfi, so time jumps there instead of ticking one step at a time
+    fn fast_forward(&mut self) {
+        let mut deadlines = vec![];
+        if self.csr.mie & csr::MIP_MTIP != 0 {
+            deadlines.push(self.clint.mtimecmp);
+        }
+        if self.csr.mie & csr::MIP_STIP != 0 && self.csr.stimecmp_enabled() {
+            deadlines.push(self.csr.stimecmp);
+        }
+        deadlines.extend(self.device_deadline());
+        if let Some(deadline) = deadlines.into_iter().min() {
+            if deadline > self.clint.mtime {
+                self.clint.mtime = deadline;
+            }
+        }
+    }
+
+    // RV32E only has x0 - x15, naming any of x16 - x31 is an illegal instruction
+    fn check_registers(&self, instruction: &Instruction) -> Result<(), Exception> {
+        let count = self.base.register_count();
+        // the csr*i forms carry a 5 bit immediate where rs1 would be
+        let rs1 = match instruction.opcode {
+            Opcodes::Csrrwi | Opcodes::Csrrsi | Opcodes::Csrrci => 0,
+            _ => instruction.rs1,
+        };
+        if instruction.rd >= count || rs1 >= count || instruction.rs2 >= count {
+            return Err(Exception::IllegalInstruction(instruction.raw));
+        }
+        Ok(())
     }
 
-    fn execute(&mut self, instruction: Instruction) {
+    fn execute(&mut self, instruction: Instruction) -> Result<(), Exception> {
+        let rs1 = self.get_register(instruction.rs1);
+        let rs2 = self.get_register(instruction.rs2);
+        let pc = self.get_register(Registers::Pc as u32);
+
         match instruction.opcode {
             Opcodes::Add => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) + self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1.wrapping_add(rs2));
             }
             Opcodes::Sub => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) - self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1.wrapping_sub(rs2));
             }
             Opcodes::Xor => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) ^ self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1 ^ rs2);
             }
             Opcodes::Or => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) | self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1 | rs2);
             }
             Opcodes::And => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) & self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1 & rs2);
             }
             Opcodes::Sll => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) << self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1 << (rs2 & 0x1F));
             }
             Opcodes::Srl => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) >> self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, rs1 >> (rs2 & 0x1F));
             }
             Opcodes::Sra => {
-                // todo: extend msb
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) >> self.get_register(instruction.rs2),
-                );
+                self.set_register(instruction.rd, ((rs1 as i32) >> (rs2 & 0x1F)) as u32);
             }
             Opcodes::Slt => {
-                self.set_register(
-                    instruction.rd,
-                    if self.get_register(instruction.rs1) < self.get_register(instruction.rs2) {
-                        1
-                    } else {
-                        0
-                    },
-                );
+                self.set_register(instruction.rd, ((rs1 as i32) < (rs2 as i32)) as u32);
             }
             Opcodes::Sltu => {
-                // todo: zero extend
-                self.set_register(
-                    instruction.rd,
-                    if self.get_register(instruction.rs1) < self.get_register(instruction.rs2) {
-                        1
-                    } else {
-                        0
-                    },
-                );
+                self.set_register(instruction.rd, (rs1 < rs2) as u32);
             }
             Opcodes::Addi => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) + instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1.wrapping_add(instruction.imm));
             }
             Opcodes::Xori => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) ^ instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1 ^ instruction.imm);
             }
             Opcodes::Ori => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) | instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1 | instruction.imm);
             }
             Opcodes::Andi => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) & instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1 & instruction.imm);
             }
             Opcodes::Slli => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) << instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1 << instruction.imm);
             }
             Opcodes::Srli => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) >> instruction.imm,
-                );
+                self.set_register(instruction.rd, rs1 >> instruction.imm);
             }
             Opcodes::Srai => {
-                // todo: extend msb
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(instruction.rs1) >> instruction.imm,
-                );
+                self.set_register(instruction.rd, ((rs1 as i32) >> instruction.imm) as u32);
             }
             Opcodes::Slti => {
                 self.set_register(
                     instruction.rd,
-                    if self.get_register(instruction.rs1) < instruction.imm {
-                        1
-                    } else {
-                        0
-                    },
+                    ((rs1 as i32) < (instruction.imm as i32)) as u32,
                 );
             }
             Opcodes::Sltiu => {
-                // todo: extend zero
-                self.set_register(
-                    instruction.rd,
-                    if self.get_register(instruction.rs1) < instruction.imm {
-                        1
-                    } else {
-                        0
-                    },
-                );
+                self.set_register(instruction.rd, (rs1 < instruction.imm) as u32);
             }
             Opcodes::Lb => {
-                self.set_register(
-                    instruction.rd,
-                    into_u32(
-                        self.mem_read(BYTE, self.get_register(instruction.rs1) + instruction.imm),
-                    ),
-                );
+                let value = self.load(BYTE, rs1.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, value as i8 as u32);
             }
             Opcodes::Lh => {
-                self.set_register(
-                    instruction.rd,
-                    into_u32(self.mem_read(
-                        HALF_WORD,
-                        self.get_register(instruction.rs1) + instruction.imm,
-                    )),
-                );
+                let value = self.load(HALF_WORD, rs1.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, value as i16 as u32);
             }
             Opcodes::Lw => {
-                self.set_register(
-                    instruction.rd,
-                    into_u32(self.mem_read(
-                        WORD_SIZE,
-                        self.get_register(instruction.rs1) + instruction.imm,
-                    )),
-                );
+                let value = self.load(WORD_SIZE, rs1.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, value);
             }
             Opcodes::Lbu => {
-                // zero extend
-                self.set_register(
-                    instruction.rd,
-                    into_u32(
-                        self.mem_read(BYTE, self.get_register(instruction.rs1) + instruction.imm),
-                    ),
-                );
+                let value = self.load(BYTE, rs1.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, value);
             }
             Opcodes::Lhu => {
-                // zero extend
-                self.set_register(
-                    instruction.rd,
-                    into_u32(self.mem_read(
-                        HALF_WORD,
-                        self.get_register(instruction.rs1) + instruction.imm,
-                    )),
-                );
+                let value = self.load(HALF_WORD, rs1.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, value);
             }
             Opcodes::Sb => {
-                self.mem_write(
-                    BYTE,
-                    self.get_register(instruction.rs1) + instruction.imm,
-                    into_byte(instruction.rs2).as_slice(),
-                );
+                self.store(BYTE, rs1.wrapping_add(instruction.imm), rs2)?;
             }
             Opcodes::Sh => {
-                self.mem_write(
-                    HALF_WORD,
-                    self.get_register(instruction.rs1) + instruction.imm,
-                    into_byte(instruction.rs2).as_slice(),
-                );
+                self.store(HALF_WORD, rs1.wrapping_add(instruction.imm), rs2)?;
             }
             Opcodes::Sw => {
-                self.mem_write(
-                    WORD_SIZE,
-                    self.get_register(instruction.rs1) + instruction.imm,
-                    into_byte(instruction.rs2).as_slice(),
-                );
+                self.store(WORD_SIZE, rs1.wrapping_add(instruction.imm), rs2)?;
             }
             Opcodes::Beq => {
-                if instruction.rs1 == instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if rs1 == rs2 {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
             Opcodes::Bne => {
-                if instruction.rs1 != instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if rs1 != rs2 {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
             Opcodes::Blt => {
-                if instruction.rs1 < instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if (rs1 as i32) < (rs2 as i32) {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
             Opcodes::Bge => {
-                if instruction.rs1 >= instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if (rs1 as i32) >= (rs2 as i32) {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
             Opcodes::Bltu => {
-                // todo: zero extend
-                if instruction.rs1 < instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if rs1 < rs2 {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
             Opcodes::Bgeu => {
-                // todo: zero extend
-                if instruction.rs1 >= instruction.rs2 {
-                    self.set_register(
-                        Registers::Pc as u32,
-                        self.get_register(Registers::Pc as u32) + instruction.imm,
-                    );
+                if rs1 >= rs2 {
+                    self.jump(pc.wrapping_add(instruction.imm))?;
                 }
             }
 
             Opcodes::Jal => {
-                self.set_register(instruction.rd, self.get_register(Registers::Pc as u32) + 4);
-                self.set_register(
-                    Registers::Pc as u32,
-                    self.get_register(Registers::Pc as u32) + instruction.imm,
-                );
+                self.jump(pc.wrapping_add(instruction.imm))?;
+                self.set_register(instruction.rd, pc.wrapping_add(4));
             }
             Opcodes::Jalr => {
-                self.set_register(instruction.rd, self.get_register(Registers::Pc as u32) + 4);
-                self.set_register(
-                    Registers::Pc as u32,
-                    self.get_register(instruction.rs1) + instruction.imm,
-                );
+                self.jump(rs1.wrapping_add(instruction.imm) & !1)?;
+                self.set_register(instruction.rd, pc.wrapping_add(4));
             }
             Opcodes::Lui => {
-                self.set_register(instruction.rd, instruction.imm >> 12);
+                self.set_register(instruction.rd, instruction.imm);
             }
             Opcodes::Auipc => {
-                self.set_register(
-                    instruction.rd,
-                    self.get_register(Registers::Pc as u32) + (instruction.imm >> 12),
-                );
+                self.set_register(instruction.rd, pc.wrapping_add(instruction.imm));
+            }
+            // transfer control to Os
+            Opcodes::Ecall => {
+                return Err(match self.privilege {
+                    Privilege::User => Exception::EnvironmentCallFromUMode,
+                    Privilege::Supervisor if self.virt => Exception::EnvironmentCallFromVSMode,
+                    Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+                    Privilege::Machine => Exception::EnvironmentCallFromMMode,
+                })
+            }
+            Opcodes::Ebreak => return Err(Exception::Breakpoint(pc)), // transfer control to debugger
+            Opcodes::Fence => {} // order mem/io access, a single hart is always ordered
+            Opcodes::Csrrw
+            | Opcodes::Csrrs
+            | Opcodes::Csrrc
+            | Opcodes::Csrrwi
+            | Opcodes::Csrrsi
+            | Opcodes::Csrrci => self.execute_csr(&instruction)?,
+            Opcodes::Sret => {
+                // TSR traps it in HS mode, VTSR in VS mode
+                if self.virt
+                    && (self.privilege == Privilege::User
+                        || self.csr.hstatus & csr::HSTATUS_VTSR != 0)
+                {
+                    return Err(Exception::VirtualInstruction(instruction.raw));
+                }
+                if self.privilege == Privilege::User
+                    || (self.privilege == Privilege::Supervisor
+                        && !self.virt
+                        && self.csr.mstatus & csr::MSTATUS_TSR != 0)
+                {
+                    return Err(Exception::IllegalInstruction(instruction.raw));
+                }
+                self.sret();
             }
-            Opcodes::Ecall => todo!(),  // transfer control to Os
-            Opcodes::Ebreak => todo!(), // transfer control to debugger
-            Opcodes::Fence => todo!(),  // order mem/io access
-            Opcodes::Default => panic!("Invalid opcode"),
+            Opcodes::Mret => {
+                if self.privilege != Privilege::Machine {
+                    return Err(Exception::IllegalInstruction(instruction.raw));
+                }
+                self.mret();
+            }
+            Opcodes::SfenceVma => {
+                if self.virt {
+                    // VS mode flushes its own translations, which are never
+                    // cached
+                    if self.privilege == Privilege::User
+                        || self.csr.hstatus & csr::HSTATUS_VTVM != 0
+                    {
+                        return Err(Exception::VirtualInstruction(instruction.raw));
+                    }
+                    return Ok(());
+                }
+                if self.privilege == Privilege::User
+                    || (self.privilege == Privilege::Supervisor
+                        && self.csr.mstatus & csr::MSTATUS_TVM != 0)
+                {
+                    return Err(Exception::IllegalInstruction(instruction.raw));
+                }
+                let address = (instruction.rs1 != 0).then_some(rs1 as u64);
+                let asid = (instruction.rs2 != 0).then_some(rs2);
+                self.tlb.flush(address, asid);
+            }
+            Opcodes::Wfi => {
+                // TW traps it immediately outside of M mode, VTW in VS mode
+                if (self.privilege == Privilege::User && !self.virt)
+                    || (self.privilege != Privilege::Machine
+                        && self.csr.mstatus & csr::MSTATUS_TW != 0)
+                {
+                    return Err(Exception::IllegalInstruction(instruction.raw));
+                }
+                if self.virt
+                    && (self.privilege == Privilege::User
+                        || self.csr.hstatus & csr::HSTATUS_VTW != 0)
+                {
+                    return Err(Exception::VirtualInstruction(instruction.raw));
+                }
+                self.waiting = true;
+            }
+            Opcodes::HfenceVvma | Opcodes::HfenceGvma => self.execute_hfence(&instruction)?,
+            Opcodes::HlvB
+            | Opcodes::HlvBu
+            | Opcodes::HlvH
+            | Opcodes::HlvHu
+            | Opcodes::HlvxHu
+            | Opcodes::HlvW
+            | Opcodes::HlvxWu
+            | Opcodes::HsvB
+            | Opcodes::HsvH
+            | Opcodes::HsvW => self.execute_hypervisor_access(&instruction)?,
+            Opcodes::Default => return Err(Exception::IllegalInstruction(instruction.raw)),
         }
+        Ok(())
+    }
+
+    // Zicsr read-modify-write. csrrw with rd = x0 skips the read and the
+    // set/clear forms with rs1 = x0 (or uimm = 0) skip the write.
+    fn execute_csr(&mut self, instruction: &Instruction) -> Result<(), Exception> {
+        let illegal = Exception::IllegalInstruction(instruction.raw);
+        let address = if self.virt {
+            self.virtual_csr(instruction.imm, instruction.raw)?
+        } else {
+            instruction.imm
+        };
+        let operand = match instruction.opcode {
+            Opcodes::Csrrwi | Opcodes::Csrrsi | Opcodes::Csrrci => instruction.rs1,
+            _ => self.get_register(instruction.rs1),
+        };
+
+        let (read, write) = match instruction.opcode {
+            Opcodes::Csrrw | Opcodes::Csrrwi => (instruction.rd != 0, true),
+            _ => (true, instruction.rs1 != 0),
+        };
+
+        let old = if read || write {
+            let value = self.csr.read(address, self.privilege).ok_or(illegal)?;
+            if self.virt && (address == csr::TIME || address == csr::TIMEH) {
+                self.guest_time(address)
+            } else {
+                value
+            }
+        } else {
+            0
+        };
+
+        if write {
+            let new = match instruction.opcode {
+                Opcodes::Csrrw | Opcodes::Csrrwi => operand,
+                Opcodes::Csrrs | Opcodes::Csrrsi => old | operand,
+                _ => old & !operand,
+            };
+            self.csr
+                .write(address, new, self.privilege)
+                .ok_or(illegal)?;
+        }
+
+        if read {
+            self.set_register(instruction.rd, old);
+        }
+        Ok(())
+    }
+
+    // Privilege loads and stores are checked against, with mstatus.MPRV set
+    // M mode data accesses act as if made from MPP
+    fn data_privilege(&self) -> Privilege {
+        if self.privilege == Privilege::Machine && self.csr.mstatus & csr::MSTATUS_MPRV != 0 {
+            let mpp = (self.csr.mstatus & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT;
+            Privilege::from_bits(mpp).unwrap_or(Privilege::Machine)
+        } else {
+            self.privilege
+        }
+    }
+
+    // V for loads and stores, MPRV uses MPV along with MPP
+    fn data_virt(&self) -> bool {
+        if self.privilege == Privilege::Machine && self.csr.mstatus & csr::MSTATUS_MPRV != 0 {
+            self.data_privilege() != Privilege::Machine
+                && self.csr.mstatush & csr::MSTATUSH_MPV != 0
+        } else {
+            self.virt
+        }
+    }
+
+    // Control transfer, targets must be aligned to IALIGN (32 bits)
+    fn jump(&mut self, target: u32) -> Result<(), Exception> {
+        if !target.is_multiple_of(WORD_SIZE as u32) {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+        self.next_pc = target;
+        Ok(())
     }
 
     fn update_pc(&mut self) {
-        self.set_register(
-            Registers::Pc as u32,
-            self.get_register(Registers::Pc as u32) + WORD_SIZE as u32,
-        );
+        self.set_register(Registers::Pc as u32, self.next_pc);
     }
 
     fn get_register(&self, register_address: u32) -> u32 {
@@ -343,20 +693,161 @@ impl Vm {
     }
 
     fn set_register(&mut self, register_address: u32, register_value: u32) {
+        // x0 is hard-wired to zero
+        if register_address == Registers::Zero as u32 {
+            return;
+        }
         self.register[register_address as usize] = register_value;
     }
 
-    fn mem_read(&self, size: usize, memory_address: u32) -> &[u8] {
-        // todo: verify if this should be be or le???
-        &self.memory
-            [((memory_address as usize) + WORD_SIZE - size)..(memory_address as usize + WORD_SIZE)]
+    // physical address check
+    // RAM or ROM, devices can not be fetched from or hold page tables
+    fn in_memory(&self, size: usize, memory_address: u64) -> bool {
+        self.bus.is_memory(memory_address, size)
+    }
+
+    // Guest data load from a virtual address, the value is zero extended
+    fn load(&mut self, size: usize, memory_address: u32) -> Result<u32, Exception> {
+        let (privilege, virt) = (self.data_privilege(), self.data_virt());
+        self.load_as(size, memory_address, privilege, virt, AccessType::Load)
+    }
+
+    // Load as if made from `privilege` and `virt`, `translation` is
+    // Instruction for hlvx which reads memory it could execute
+    fn load_as(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        privilege: Privilege,
+        virt: bool,
+        translation: AccessType,
+    ) -> Result<u32, Exception> {
+        self.check_triggers(AccessType::Load, memory_address, size, None)?;
+        let value = if memory_address.is_multiple_of(size as u32) {
+            self.load_aligned(size, memory_address, privilege, virt, translation)?
+        } else {
+            self.load_misaligned(size, memory_address, privilege, virt, translation)?
+        };
+        // data triggers see the loaded value, rd is left alone when they fire
+        self.check_triggers(AccessType::Load, memory_address, size, Some(value))?;
+        Ok(value)
+    }
+
+    fn load_aligned(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        privilege: Privilege,
+        virt: bool,
+        translation: AccessType,
+    ) -> Result<u32, Exception> {
+        let address = self
+            .translate_as(memory_address, translation, privilege, virt)
+            .map_err(hypervisor::hlvx_fault)?;
+        if !self
+            .csr
+            .pmp
+            .allows(address, size, privilege, AccessType::Load)
+        {
+            return Err(Exception::LoadAccessFault(memory_address));
+        }
+        self.read_physical(size, address, memory_address)
+    }
+
+    // Device register or memory read of a checked physical address
+    fn read_physical(
+        &mut self,
+        size: usize,
+        address: u64,
+        memory_address: u32,
+    ) -> Result<u32, Exception> {
+        let fault = Exception::LoadAccessFault(memory_address);
+        let (kind, base) = match self.bus.region(address, size) {
+            Some(region) => (region.kind, region.base),
+            None => return Err(fault),
+        };
+        match kind {
+            RegionKind::Ram | RegionKind::Rom | RegionKind::Vram => {}
+            RegionKind::Mmio(device) => {
+                return self.mmio_read(device, address - base, size).ok_or(fault)
+            }
+            RegionKind::Hole => return Err(fault),
+        }
+        let mut word = [0; WORD_SIZE];
+        word[WORD_SIZE - size..].copy_from_slice(&self.mem_read(size, address).ok_or(fault)?);
+        Ok(into_u32(&word))
+    }
+
+    // Guest data store of the low `size` bytes of value to a virtual address
+    fn store(&mut self, size: usize, memory_address: u32, value: u32) -> Result<(), Exception> {
+        let (privilege, virt) = (self.data_privilege(), self.data_virt());
+        self.store_as(size, memory_address, value, privilege, virt)
+    }
+
+    fn store_as(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        value: u32,
+        privilege: Privilege,
+        virt: bool,
+    ) -> Result<(), Exception> {
+        self.check_triggers(AccessType::Store, memory_address, size, None)?;
+        self.check_triggers(AccessType::Store, memory_address, size, Some(value))?;
+        if memory_address.is_multiple_of(size as u32) {
+            self.store_aligned(size, memory_address, value, privilege, virt)
+        } else {
+            self.store_misaligned(size, memory_address, value, privilege, virt)
+        }
+    }
+
+    fn store_aligned(
+        &mut self,
+        size: usize,
+        memory_address: u32,
+        value: u32,
+        privilege: Privilege,
+        virt: bool,
+    ) -> Result<(), Exception> {
+        let address = self.translate_as(memory_address, AccessType::Store, privilege, virt)?;
+        if !self
+            .csr
+            .pmp
+            .allows(address, size, privilege, AccessType::Store)
+        {
+            return Err(Exception::StoreAccessFault(memory_address));
+        }
+        let fault = Exception::StoreAccessFault(memory_address);
+        let (kind, base) = match self.bus.region(address, size) {
+            Some(region) => (region.kind, region.base),
+            None => return Err(fault),
+        };
+        match kind {
+            RegionKind::Ram | RegionKind::Vram => {}
+            RegionKind::Mmio(device) => {
+                return self
+                    .mmio_write(device, address - base, size, value)
+                    .ok_or(fault)
+            }
+            RegionKind::Rom | RegionKind::Hole => return Err(fault),
+        }
+        self.mem_write(size, address, &into_byte(value))
+            .ok_or(fault)
     }
 
-    fn mem_write(&mut self, size: usize, memory_address: u32, value: &[u8]) {
-        // todo: verify if this should be be or le???
-        self.memory[((memory_address as usize) + WORD_SIZE - size)
-            ..((memory_address as usize) + WORD_SIZE)]
-            .copy_from_slice(&value);
+    // Memory is little endian, values are passed around most significant byte
+    // first (see into_u32). Both go straight to RAM or ROM at a physical
+    // address, None where there is none, which callers turn into an access
+    // fault.
+    fn mem_read(&self, size: usize, memory_address: u64) -> Option<Vec<u8>> {
+        let bytes = self.bus.read(memory_address, size)?;
+        Some(bytes.iter().rev().copied().collect())
+    }
+
+    // Writes the last `size` bytes of value
+    fn mem_write(&mut self, size: usize, memory_address: u64, value: &[u8]) -> Option<()> {
+        let bytes: Vec<u8> = value[value.len() - size..].iter().rev().copied().collect();
+        self.bus.write(memory_address, &bytes)
     }
 }
 
@@ -364,7 +855,36 @@ impl Vm {
 mod tests {
     use crate::vm::{BYTE, HALF_WORD, WORD_SIZE};
 
-    use super::{instruction::into_u32, Vm};
+    use super::{
+        csr::Privilege,
+        instruction::{into_byte, into_u32},
+        pmp,
+        registers::{BaseIsa, Registers},
+        trap::Exception,
+        Vm,
+    };
+
+    // What firmware does before dropping to S or U mode: a single NAPOT PMP
+    // entry granting RWX on the whole physical address space
+    pub(crate) fn allow_all_memory(vm: &mut Vm) {
+        vm.csr
+            .write(pmp::PMPADDR0, u32::MAX, Privilege::Machine)
+            .unwrap();
+        vm.csr
+            .write(pmp::PMPCFG0, 0x1F, Privilege::Machine)
+            .unwrap();
+    }
+
+    // Vm with the program's words placed at address 0
+    pub(crate) fn vm_with_program(program: &[u32]) -> Vm {
+        let mut vm = Vm::initialize();
+        allow_all_memory(&mut vm);
+        for (i, word) in program.iter().enumerate() {
+            vm.mem_write(WORD_SIZE, (i * WORD_SIZE) as u64, &into_byte(*word))
+                .unwrap();
+        }
+        vm
+    }
 
     #[test]
     fn test_mem_read() {
@@ -373,28 +893,28 @@ mod tests {
         let memory_address = 5;
         let value = [30, 15, 18, 20];
 
-        vm.mem_write(WORD_SIZE, memory_address, &value);
+        vm.mem_write(WORD_SIZE, memory_address, &value).unwrap();
 
         // read full word
-        assert_eq!(vm.mem_read(WORD_SIZE, memory_address), value);
+        assert_eq!(vm.mem_read(WORD_SIZE, memory_address).unwrap(), value);
 
         //read byte
-        assert_eq!(vm.mem_read(1, memory_address), [20]);
+        assert_eq!(vm.mem_read(1, memory_address).unwrap(), [20]);
 
         //read half word
-        assert_eq!(vm.mem_read(2, memory_address), [18, 20]);
+        assert_eq!(vm.mem_read(2, memory_address).unwrap(), [18, 20]);
 
         // store full word
-        vm.mem_write(WORD_SIZE, 5, &[20, 18, 15, 30]);
-        assert_eq!(vm.mem_read(WORD_SIZE, 5), &[20, 18, 15, 30]);
+        vm.mem_write(WORD_SIZE, 5, &[20, 18, 15, 30]).unwrap();
+        assert_eq!(vm.mem_read(WORD_SIZE, 5).unwrap(), &[20, 18, 15, 30]);
 
         // store byte
-        vm.mem_write(BYTE, 5, &[45]);
-        assert_eq!(vm.mem_read(WORD_SIZE, 5), &[20, 18, 15, 45]);
+        vm.mem_write(BYTE, 5, &[45]).unwrap();
+        assert_eq!(vm.mem_read(WORD_SIZE, 5).unwrap(), &[20, 18, 15, 45]);
 
         // store half word
-        vm.mem_write(HALF_WORD, 5, &[20, 60]);
-        assert_eq!(vm.mem_read(WORD_SIZE, 5), &[20, 18, 20, 60]);
+        vm.mem_write(HALF_WORD, 5, &[20, 60]).unwrap();
+        assert_eq!(vm.mem_read(WORD_SIZE, 5).unwrap(), &[20, 18, 20, 60]);
     }
 
     #[test]
@@ -404,6 +924,34 @@ mod tests {
         let mut b = [0; WORD_SIZE];
         b[2..=3].clone_from_slice(&mem[2..=3]);
         let v = into_u32(&mem);
-        dbg!(format_args!("{:?}", b));
+        assert_eq!(b, [0, 0, 0x85, 0x33]);
+        assert_eq!(v, 0x00c58533);
+    }
+
+    #[test]
+    fn test_rv32e_registers() {
+        let mut vm = Vm::with_base(BaseIsa::Rv32e);
+
+        // add x10, x11, x12
+        vm.mem_write(WORD_SIZE, 0, &into_byte(0x00c58533)).unwrap();
+        assert_eq!(vm.run_program(), Ok(()));
+        assert_eq!(vm.get_register(Registers::Pc as u32), 4);
+
+        // add x10, x11, x17
+        vm.mem_write(WORD_SIZE, 4, &into_byte(0x01158533)).unwrap();
+        assert_eq!(
+            vm.run_program(),
+            Err(Exception::IllegalInstruction(0x01158533))
+        );
+
+        // csrrwi x1, mscratch, 31 names no register above x15
+        vm.mem_write(WORD_SIZE, 4, &into_byte(0x340fd0f3)).unwrap();
+        assert_eq!(vm.run_program(), Ok(()));
+        assert_eq!(vm.csr.mscratch, 31);
+
+        // the same instruction is fine on rv32i
+        let mut vm = Vm::initialize();
+        vm.mem_write(WORD_SIZE, 0, &into_byte(0x01158533)).unwrap();
+        assert_eq!(vm.run_program(), Ok(()));
     }
 }
@@ -54,6 +54,36 @@ pub(crate) enum Opcodes {
     Ebreak, // environment break(I)
     Fence,  // memory fence(FENCE)
 
+    // Control and status registers (Zicsr)
+    Csrrw,  // atomic read/write csr
+    Csrrs,  // atomic read and set bits in csr
+    Csrrc,  // atomic read and clear bits in csr
+    Csrrwi, // csrrw with 5 bit zero-extended immediate
+    Csrrsi, // csrrs with immediate
+    Csrrci, // csrrc with immediate
+
+    // Privileged
+    Sret, // return from supervisor mode trap
+    Mret, // return from machine mode trap
+    Wfi,  // wait for interrupt
+
+    // Supervisor memory management
+    SfenceVma, // flush address translation caches
+
+    // Hypervisor
+    HfenceVvma, // flush VS-stage translations
+    HfenceGvma, // flush G-stage translations
+    HlvB,       // load byte as VS/VU mode
+    HlvBu,      // load byte unsigned as VS/VU mode
+    HlvH,       // load half as VS/VU mode
+    HlvHu,      // load half unsigned as VS/VU mode
+    HlvxHu,     // load half from executable memory as VS/VU mode
+    HlvW,       // load word as VS/VU mode
+    HlvxWu,     // load word from executable memory as VS/VU mode
+    HsvB,       // store byte as VS/VU mode
+    HsvH,       // store half as VS/VU mode
+    HsvW,       // store word as VS/VU mode
+
     // Default
     Default,
 }
@@ -0,0 +1,257 @@
+// Platform-level interrupt controller, the SiFive layout used by QEMU virt.
+// Hart 0 has two contexts: 0 drives mip.MEIP and 1 drives mip.SEIP.
+pub(crate) const PLIC_BASE: u64 = 0x0C00_0000;
+pub(crate) const PLIC_SIZE: u64 = 0x40_0000;
+pub(crate) const PLIC_CONTEXTS: usize = 2;
+pub(crate) const MAX_SOURCES: usize = 1023;
+
+// register blocks
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+// priorities and thresholds are 3 bits wide
+const PRIORITY_MASK: u32 = 0x7;
+
+pub(crate) struct Plic {
+    sources: usize, // source ids are 1..=sources, 0 means no interrupt
+    priority: Vec<u32>,
+    level: Vec<bool>,   // interrupt line from the device
+    pending: Vec<bool>, // gateway latched, waiting for a claim
+    claimed: Vec<bool>, // being serviced, no new request until complete
+    enable: [Vec<bool>; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+}
+
+impl Plic {
+    pub(crate) fn new(sources: usize) -> Self {
+        assert!(
+            (1..=MAX_SOURCES).contains(&sources),
+            "the PLIC supports 1 to {} sources",
+            MAX_SOURCES
+        );
+        let slots = sources + 1;
+        Self {
+            sources,
+            priority: vec![0; slots],
+            level: vec![false; slots],
+            pending: vec![false; slots],
+            claimed: vec![false; slots],
+            enable: [vec![false; slots], vec![false; slots]],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    pub(crate) fn sources(&self) -> usize {
+        self.sources
+    }
+
+    // Level triggered gateway, a device drives its line high until serviced
+    pub(crate) fn set_level(&mut self, source: usize, level: bool) {
+        if source == 0 || source > self.sources {
+            return;
+        }
+        self.level[source] = level;
+        if level && !self.claimed[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    // Highest priority pending and enabled source above the context's
+    // threshold, ties going to the lowest id
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for source in 1..=self.sources {
+            if !self.pending[source] || !self.enable[context][source] {
+                continue;
+            }
+            let priority = self.priority[source];
+            if priority <= self.threshold[context] {
+                continue;
+            }
+            if best.is_none_or(|best| priority > self.priority[best]) {
+                best = Some(source);
+            }
+        }
+        best
+    }
+
+    // interrupt output of a context, 0 = mip.MEIP, 1 = mip.SEIP
+    pub(crate) fn interrupt(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    // completions for sources the context has not enabled are ignored
+    fn complete(&mut self, context: usize, source: usize) {
+        if source == 0 || source > self.sources || !self.enable[context][source] {
+            return;
+        }
+        self.claimed[source] = false;
+        // the line is still high, the gateway forwards a new request
+        if self.level[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    // 32 bit registers only, None for anything else. Claims have a side
+    // effect so reads take &mut self.
+    pub(crate) fn read(&mut self, offset: u64, size: usize) -> Option<u32> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = if offset < PENDING {
+            let source = ((offset - PRIORITY) / 4) as usize;
+            *self.priority.get(source)?
+        } else if offset < ENABLE {
+            self.bits(&self.pending, (offset - PENDING) / 4)
+        } else if offset < CONTEXT {
+            let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+            let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+            self.bits(self.enable.get(context)?, word)
+        } else {
+            let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+            if context >= PLIC_CONTEXTS {
+                return None;
+            }
+            match (offset - CONTEXT) % CONTEXT_STRIDE {
+                0 => self.threshold[context],
+                4 => self.claim(context),
+                _ => return None,
+            }
+        };
+        Some(value)
+    }
+
+    pub(crate) fn write(&mut self, offset: u64, size: usize, value: u32) -> Option<()> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        if offset < PENDING {
+            let source = ((offset - PRIORITY) / 4) as usize;
+            // source 0 does not exist, its priority is hardwired to zero
+            if source != 0 {
+                *self.priority.get_mut(source)? = value & PRIORITY_MASK;
+            }
+        } else if offset < ENABLE {
+            // pending bits are read only
+        } else if offset < CONTEXT {
+            let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+            let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+            let enable = self.enable.get_mut(context)?;
+            for bit in 0..32 {
+                let source = word * 32 + bit;
+                if source != 0 && source < enable.len() {
+                    enable[source] = value & (1 << bit) != 0;
+                }
+            }
+        } else {
+            let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+            if context >= PLIC_CONTEXTS {
+                return None;
+            }
+            match (offset - CONTEXT) % CONTEXT_STRIDE {
+                0 => self.threshold[context] = value & PRIORITY_MASK,
+                4 => self.complete(context, value as usize),
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    // 32 sources of a per-source flag array packed into a register
+    fn bits(&self, flags: &[bool], word: u64) -> u32 {
+        (0..32).fold(0, |value, bit| {
+            let source = word as usize * 32 + bit;
+            if flags.get(source).copied().unwrap_or(false) {
+                value | 1 << bit
+            } else {
+                value
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{csr, tests::vm_with_program, WORD_SIZE};
+
+    use super::{Plic, PLIC_BASE};
+
+    #[test]
+    fn test_priority_threshold_and_claim() {
+        let mut plic = Plic::new(8);
+        plic.write(3 * 4, 4, 2).unwrap(); // priority[3] = 2
+        plic.write(5 * 4, 4, 6).unwrap(); // priority[5] = 6
+        plic.write(7 * 4, 4, 6).unwrap(); // priority[7] = 6
+        plic.write(0x2000, 4, 1 << 3 | 1 << 5 | 1 << 7).unwrap(); // context 0 enables
+
+        plic.set_level(3, true);
+        plic.set_level(7, true);
+        plic.set_level(5, true);
+        assert_eq!(plic.read(0x1000, 4), Some(1 << 3 | 1 << 5 | 1 << 7));
+        assert!(plic.interrupt(0));
+        assert!(!plic.interrupt(1));
+
+        // equal priorities go to the lower id
+        assert_eq!(plic.read(0x20_0004, 4), Some(5));
+        assert_eq!(plic.read(0x20_0004, 4), Some(7));
+
+        // threshold masks priority 2
+        plic.write(0x20_0000, 4, 2).unwrap();
+        assert!(!plic.interrupt(0));
+        assert_eq!(plic.read(0x20_0004, 4), Some(0));
+        plic.write(0x20_0000, 4, 0).unwrap();
+
+        // 5 stays claimed until completed, its line is still high
+        plic.set_level(5, true);
+        assert_eq!(plic.read(0x20_0004, 4), Some(3));
+        plic.write(0x20_0004, 4, 5).unwrap();
+        assert_eq!(plic.read(0x20_0004, 4), Some(5));
+
+        // completing with the line low does not re-pend
+        plic.set_level(7, false);
+        plic.write(0x20_0004, 4, 7).unwrap();
+        assert!(!plic.interrupt(0));
+    }
+
+    #[test]
+    fn test_external_interrupts_reach_the_hart() {
+        let mut vm = vm_with_program(&[0x00000013; 4]); // nops
+        let plic = PLIC_BASE as u32;
+        vm.store(WORD_SIZE, plic + 4, 1).unwrap(); // priority[1] = 1
+        vm.store(WORD_SIZE, plic + 0x2080, 1 << 1).unwrap(); // S context enable
+
+        vm.set_interrupt_line(1, true);
+        vm.step();
+        assert_eq!(
+            vm.csr.read(csr::MIP, csr::Privilege::Machine),
+            Some(csr::MIP_SEIP)
+        );
+
+        vm.csr.mie = csr::MIP_SEIP;
+        vm.csr.mstatus |= csr::MSTATUS_MIE;
+        vm.csr.mtvec = 0x100;
+        vm.step();
+        assert_eq!(vm.csr.mcause, 1 << 31 | 9);
+        assert_eq!(vm.load(WORD_SIZE, plic + 0x20_1004), Ok(1));
+
+        vm.set_interrupt_line(1, false);
+        vm.store(WORD_SIZE, plic + 0x20_1004, 1).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.read(csr::MIP, csr::Privilege::Machine), Some(0));
+    }
+}
@@ -0,0 +1,230 @@
+use super::{csr::Privilege, mmu::AccessType};
+
+pub(crate) const PMP_ENTRIES: usize = 64;
+
+// pmpcfg0 - pmpcfg15 and pmpaddr0 - pmpaddr63
+pub(crate) const PMPCFG0: u32 = 0x3A0;
+pub(crate) const PMPCFG15: u32 = 0x3AF;
+pub(crate) const PMPADDR0: u32 = 0x3B0;
+pub(crate) const PMPADDR63: u32 = 0x3EF;
+
+// pmpcfg entry fields
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+const PMP_A: u8 = 0b11 << 3;
+const PMP_L: u8 = 1 << 7;
+
+// address matching modes in pmpcfg.A
+const PMP_A_OFF: u8 = 0;
+const PMP_A_TOR: u8 = 1 << 3;
+const PMP_A_NA4: u8 = 2 << 3;
+#[cfg(test)] // the default of range()
+const PMP_A_NAPOT: u8 = 3 << 3;
+
+// Physical memory protection entries. On RV32 pmpaddr holds bits 33:2 of
+// the physical address and each pmpcfg register packs four 8 bit entries.
+pub(crate) struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u32; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub(crate) fn new() -> Self {
+        Self {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    pub(crate) fn read_cfg(&self, register: usize) -> u32 {
+        (0..4).fold(0, |value, i| {
+            value | (self.cfg[register * 4 + i] as u32) << (8 * i)
+        })
+    }
+
+    pub(crate) fn write_cfg(&mut self, register: usize, value: u32) {
+        for i in 0..4 {
+            let entry = register * 4 + i;
+            if self.locked(entry) {
+                continue;
+            }
+            let mut cfg = (value >> (8 * i)) as u8;
+            // R = 0, W = 1 is reserved
+            if cfg & PMP_R == 0 {
+                cfg &= !PMP_W;
+            }
+            self.cfg[entry] = cfg & (PMP_R | PMP_W | PMP_X | PMP_A | PMP_L);
+        }
+    }
+
+    pub(crate) fn read_addr(&self, entry: usize) -> u32 {
+        self.addr[entry]
+    }
+
+    // Locked entries ignore writes, and so does the address below a locked
+    // TOR entry as it is that entry's lower bound
+    pub(crate) fn write_addr(&mut self, entry: usize, value: u32) {
+        let top_of_locked_tor = entry + 1 < PMP_ENTRIES
+            && self.locked(entry + 1)
+            && self.cfg[entry + 1] & PMP_A == PMP_A_TOR;
+        if !self.locked(entry) && !top_of_locked_tor {
+            self.addr[entry] = value;
+        }
+    }
+
+    fn locked(&self, entry: usize) -> bool {
+        self.cfg[entry] & PMP_L != 0
+    }
+
+    // [start, end) byte range an entry covers, None when it is off
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[entry] as u64;
+        match self.cfg[entry] & PMP_A {
+            PMP_A_OFF => None,
+            PMP_A_TOR => {
+                let start = if entry == 0 {
+                    0
+                } else {
+                    (self.addr[entry - 1] as u64) << 2
+                };
+                Some((start, addr << 2))
+            }
+            PMP_A_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            _ => {
+                // pmpaddr = base | (size / 8 - 1)
+                let size = 8u64 << addr.trailing_ones();
+                let start = (addr << 2) & !(size - 1);
+                Some((start, start + size))
+            }
+        }
+    }
+
+    // The lowest numbered entry matching any byte of the access decides, and
+    // it must cover the whole access. M mode is only held to locked entries
+    // and passes when nothing matches, S and U mode fail.
+    pub(crate) fn allows(
+        &self,
+        address: u64,
+        size: usize,
+        privilege: Privilege,
+        access: AccessType,
+    ) -> bool {
+        let end = address + size as u64;
+        for entry in 0..PMP_ENTRIES {
+            let Some((start, stop)) = self.range(entry) else {
+                continue;
+            };
+            if end <= start || address >= stop {
+                continue;
+            }
+            if address < start || end > stop {
+                return false;
+            }
+            if privilege == Privilege::Machine && !self.locked(entry) {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Instruction => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return self.cfg[entry] & permission != 0;
+        }
+        privilege == Privilege::Machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::Privilege, mmu::AccessType, registers::Registers, trap::Exception, Vm, WORD_SIZE,
+    };
+
+    use super::{Pmp, PMP_A_NA4, PMP_A_NAPOT, PMP_A_TOR, PMP_L, PMP_R, PMP_W, PMP_X};
+
+    #[test]
+    fn test_matching_modes_and_priority() {
+        let mut pmp = Pmp::new();
+        // 0: NA4 read only at 0x1000
+        // 1: TOR [0x1000, 0x2000) read/write
+        // 2: NAPOT 0x8000_0000 + 64 KiB execute
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_addr(2, (0x8000_0000 >> 2) | (0x1_0000 / 8 - 1));
+        pmp.write_cfg(
+            0,
+            (PMP_A_NA4 | PMP_R) as u32
+                | ((PMP_A_TOR | PMP_R | PMP_W) as u32) << 8
+                | ((PMP_A_NAPOT | PMP_X) as u32) << 16,
+        );
+
+        let s = Privilege::Supervisor;
+        assert!(pmp.allows(0x1000, 4, s, AccessType::Load));
+        // entry 0 wins over entry 1
+        assert!(!pmp.allows(0x1000, 4, s, AccessType::Store));
+        assert!(pmp.allows(0x1004, 4, s, AccessType::Store));
+        // partially matching entry 0
+        assert!(!pmp.allows(0x1002, 4, s, AccessType::Load));
+        assert!(pmp.allows(0x8000_fffc, 4, s, AccessType::Instruction));
+        assert!(!pmp.allows(0x8001_0000, 4, s, AccessType::Instruction));
+
+        // no match: only M mode passes
+        assert!(!pmp.allows(0x3000, 4, s, AccessType::Load));
+        assert!(pmp.allows(0x3000, 4, Privilege::Machine, AccessType::Load));
+        assert!(pmp.allows(0x1000, 4, Privilege::Machine, AccessType::Store));
+    }
+
+    #[test]
+    fn test_lock_bit() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(0, ((PMP_A_TOR | PMP_R | PMP_L) as u32) << 8);
+
+        // locked entries bind M mode
+        assert!(!pmp.allows(0x1000, 4, Privilege::Machine, AccessType::Store));
+        assert!(pmp.allows(0x1000, 4, Privilege::Machine, AccessType::Load));
+
+        // neither the entry nor its TOR lower bound can change
+        pmp.write_cfg(0, ((PMP_A_TOR | PMP_R | PMP_W) as u32) << 8);
+        pmp.write_addr(0, 0);
+        pmp.write_addr(1, 0x4000 >> 2);
+        assert_eq!(pmp.read_cfg(0), ((PMP_A_TOR | PMP_R | PMP_L) as u32) << 8);
+        assert_eq!((pmp.read_addr(0), pmp.read_addr(1)), (0x400, 0x800));
+
+        // W without R is reserved
+        pmp.write_cfg(1, (PMP_A_NA4 | PMP_W) as u32);
+        assert_eq!(pmp.read_cfg(1), PMP_A_NA4 as u32);
+    }
+
+    #[test]
+    fn test_hart_accesses_and_page_walks() {
+        let mut vm = Vm::initialize();
+        // U mode may only use [0, 0x1000)
+        vm.csr.pmp.write_addr(0, 0x1000 >> 2);
+        vm.csr
+            .pmp
+            .write_cfg(0, (PMP_A_TOR | PMP_R | PMP_W | PMP_X) as u32);
+        vm.privilege = Privilege::User;
+
+        assert_eq!(vm.load(WORD_SIZE, 0xffc), Ok(0));
+        assert_eq!(
+            vm.load(WORD_SIZE, 0x1000),
+            Err(Exception::LoadAccessFault(0x1000))
+        );
+        assert_eq!(
+            vm.store(WORD_SIZE, 0x2000, 1),
+            Err(Exception::StoreAccessFault(0x2000))
+        );
+
+        // the root page table at 0x10000 is not covered either
+        vm.csr.satp = 1 << 31 | 0x10;
+        assert_eq!(
+            vm.fetch(),
+            Err(Exception::InstructionAccessFault(
+                vm.get_register(Registers::Pc as u32)
+            ))
+        );
+    }
+}
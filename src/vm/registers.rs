@@ -1,3 +1,5 @@
+// x0 - x31 by ABI name, the code only names a few of them
+#[allow(dead_code)]
 pub(crate) enum Registers {
     Zero, // hard-wired zero
     Ra,   // return address
@@ -34,7 +36,8 @@ pub(crate) enum Registers {
     Pc, // program counter
 }
 
-// Floating point registers
+// Floating point registers, for when there is an F extension
+#[allow(dead_code)]
 enum FPRegisters {
     F0, // fp temporaries 0 - 7
     F1,
@@ -69,3 +72,19 @@ enum FPRegisters {
     F30,
     F31,
 }
+
+// Base integer ISA of the hart
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BaseIsa {
+    Rv32i, // 32 general purpose registers
+    Rv32e, // embedded base, only x0 - x15
+}
+
+impl BaseIsa {
+    pub(crate) fn register_count(&self) -> u32 {
+        match self {
+            BaseIsa::Rv32i => 32,
+            BaseIsa::Rv32e => 16,
+        }
+    }
+}
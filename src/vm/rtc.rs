@@ -0,0 +1,196 @@
+// The Goldfish RTC of QEMU virt: nanoseconds since the epoch and one alarm.
+// The clock is the host's, or a virtual one that follows mtime from a fixed
+// start so every run sees the same times.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    clint::TIMEBASE_FREQUENCY,
+    device::{Device, DeviceNode},
+};
+
+pub const RTC_BASE: u64 = 0x10_1000;
+pub const RTC_SIZE: u64 = 0x1000;
+pub const RTC_SOURCE: usize = 11;
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0C;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1C;
+
+const NANOSECONDS_PER_TICK: u64 = 1_000_000_000 / TIMEBASE_FREQUENCY;
+
+enum Clock {
+    Host,
+    Virtual(u64), // time at mtime 0
+}
+
+pub struct GoldfishRtc {
+    clock: Clock,
+    mtime: u64,
+    offset: u64,    // set by the guest, added to the clock
+    time_high: u32, // latched by reading TIME_LOW, or written for TIME_LOW
+    alarm: u64,
+    alarm_high: u32, // written for ALARM_LOW
+    armed: bool,
+    irq_enabled: bool,
+    pending: bool,
+}
+
+impl GoldfishRtc {
+    pub fn host() -> Self {
+        Self::new(Clock::Host)
+    }
+
+    // Starts at `start` nanoseconds since the epoch and advances with mtime
+    pub fn virtual_clock(start: u64) -> Self {
+        Self::new(Clock::Virtual(start))
+    }
+
+    fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            mtime: 0,
+            offset: 0,
+            time_high: 0,
+            alarm: 0,
+            alarm_high: 0,
+            armed: false,
+            irq_enabled: false,
+            pending: false,
+        }
+    }
+
+    fn clock(&self) -> u64 {
+        match self.clock {
+            Clock::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+            Clock::Virtual(start) => start.wrapping_add(self.mtime * NANOSECONDS_PER_TICK),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock().wrapping_add(self.offset)
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if size != 4 {
+            return None;
+        }
+        let value = match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.armed as u32,
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 4 {
+            return None;
+        }
+        let value = value as u32;
+        match offset {
+            // the high half goes first, the low half sets the time
+            TIME_HIGH => self.time_high = value,
+            TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(self.clock());
+            }
+            ALARM_HIGH => self.alarm_high = value,
+            ALARM_LOW => {
+                self.alarm = (self.alarm_high as u64) << 32 | value as u64;
+                self.armed = true;
+            }
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.armed = false,
+            CLEAR_INTERRUPT => self.pending = false,
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn tick(&mut self, time: u64) {
+        self.mtime = time;
+        if self.armed && self.now() >= self.alarm {
+            self.armed = false;
+            self.pending = true;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.pending && self.irq_enabled
+    }
+
+    // only a virtual clock's alarm is tied to mtime
+    fn deadline(&self) -> Option<u64> {
+        match self.clock {
+            Clock::Virtual(_) if self.armed => {
+                let left = self.alarm.saturating_sub(self.now());
+                Some(self.mtime + left.div_ceil(NANOSECONDS_PER_TICK))
+            }
+            _ => None,
+        }
+    }
+
+    fn device_node(&self) -> Option<DeviceNode> {
+        Some(DeviceNode {
+            name: "rtc",
+            compatible: &["google,goldfish-rtc"],
+            properties: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(rtc: &mut GoldfishRtc) -> u64 {
+        let low = rtc.read(TIME_LOW, 4).unwrap();
+        rtc.read(TIME_HIGH, 4).unwrap() << 32 | low
+    }
+
+    #[test]
+    fn test_virtual_clock_and_alarm() {
+        let start = 1_700_000_000 * 1_000_000_000;
+        let mut rtc = GoldfishRtc::virtual_clock(start);
+        rtc.tick(10);
+        assert_eq!(time(&mut rtc), start + 1000);
+
+        // the guest sets the clock, high half first
+        let set = 5 << 32 | 7;
+        rtc.write(TIME_HIGH, 4, set >> 32).unwrap();
+        rtc.write(TIME_LOW, 4, set & 0xFFFF_FFFF).unwrap();
+        rtc.tick(11);
+        assert_eq!(time(&mut rtc), set + 100);
+
+        let alarm = set + 1050;
+        rtc.write(IRQ_ENABLED, 4, 1).unwrap();
+        rtc.write(ALARM_HIGH, 4, alarm >> 32).unwrap();
+        rtc.write(ALARM_LOW, 4, alarm & 0xFFFF_FFFF).unwrap();
+        assert_eq!(rtc.read(ALARM_STATUS, 4), Some(1));
+        assert_eq!(rtc.deadline(), Some(21));
+        rtc.tick(20);
+        assert!(!rtc.interrupt());
+        rtc.tick(21);
+        assert!(rtc.interrupt());
+        assert_eq!(rtc.read(ALARM_STATUS, 4), Some(0));
+        rtc.write(CLEAR_INTERRUPT, 4, 1).unwrap();
+        assert!(!rtc.interrupt());
+    }
+}
@@ -0,0 +1,611 @@
+use super::{
+    csr::{self, Privilege},
+    registers::Registers,
+    Vm,
+};
+
+// Synchronous exceptions raised while fetching, decoding or executing an
+// instruction. The payload is the value reported to the guest in `xtval`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Exception {
+    InstructionAddressMisaligned(u32), // jump target
+    InstructionAccessFault(u32),       // fetch address
+    IllegalInstruction(u32),           // faulting instruction bits
+    Breakpoint(u32),                   // pc of the ebreak
+    LoadAddressMisaligned(u32),        // effective address
+    LoadAccessFault(u32),
+    StoreAddressMisaligned(u32),
+    StoreAccessFault(u32),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromVSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault(u32), // virtual address
+    LoadPageFault(u32),
+    StorePageFault(u32),
+    // guest virtual address and the guest physical address shifted right by
+    // 2, as reported in htval / mtval2
+    InstructionGuestPageFault(u32, u32),
+    LoadGuestPageFault(u32, u32),
+    VirtualInstruction(u32), // faulting instruction bits
+    StoreGuestPageFault(u32, u32),
+}
+
+impl Exception {
+    // exception code written to mcause
+    pub(crate) fn cause(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromVSMode => 10,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+            Exception::InstructionGuestPageFault(..) => 20,
+            Exception::LoadGuestPageFault(..) => 21,
+            Exception::VirtualInstruction(_) => 22,
+            Exception::StoreGuestPageFault(..) => 23,
+        }
+    }
+
+    pub(crate) fn tval(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned(val)
+            | Exception::InstructionAccessFault(val)
+            | Exception::IllegalInstruction(val)
+            | Exception::Breakpoint(val)
+            | Exception::LoadAddressMisaligned(val)
+            | Exception::LoadAccessFault(val)
+            | Exception::StoreAddressMisaligned(val)
+            | Exception::StoreAccessFault(val)
+            | Exception::InstructionPageFault(val)
+            | Exception::LoadPageFault(val)
+            | Exception::StorePageFault(val)
+            | Exception::InstructionGuestPageFault(val, _)
+            | Exception::LoadGuestPageFault(val, _)
+            | Exception::VirtualInstruction(val)
+            | Exception::StoreGuestPageFault(val, _) => *val,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromVSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+
+    // value for htval / mtval2
+    pub(crate) fn htval(&self) -> u32 {
+        match self {
+            Exception::InstructionGuestPageFault(_, gpa)
+            | Exception::LoadGuestPageFault(_, gpa)
+            | Exception::StoreGuestPageFault(_, gpa) => *gpa,
+            _ => 0,
+        }
+    }
+
+    // xtval holds an address, for accesses made with V = 1 that is a guest
+    // virtual address and sets xstatus.GVA
+    fn has_address(&self) -> bool {
+        !matches!(
+            self,
+            Exception::IllegalInstruction(_)
+                | Exception::VirtualInstruction(_)
+                | Exception::EnvironmentCallFromUMode
+                | Exception::EnvironmentCallFromSMode
+                | Exception::EnvironmentCallFromVSMode
+                | Exception::EnvironmentCallFromMMode
+        )
+    }
+}
+
+// Interrupts, the discriminant is the cause code and the bit in mip/mie
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Interrupt {
+    SupervisorSoftware = 1,
+    VirtualSupervisorSoftware = 2,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    VirtualSupervisorTimer = 6,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    VirtualSupervisorExternal = 10,
+    MachineExternal = 11,
+    SupervisorGuestExternal = 12,
+}
+
+// highest priority first
+pub(crate) const INTERRUPT_PRIORITY: [Interrupt; 10] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoftware,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoftware,
+    Interrupt::SupervisorTimer,
+    Interrupt::SupervisorGuestExternal,
+    Interrupt::VirtualSupervisorExternal,
+    Interrupt::VirtualSupervisorSoftware,
+    Interrupt::VirtualSupervisorTimer,
+];
+
+// Mode a trap is handled in
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TrapTarget {
+    Machine,
+    Supervisor,        // HS mode when the hypervisor extension is in use
+    VirtualSupervisor, // VS mode, the guest's own handler
+}
+
+impl Vm {
+    // Deliver an exception to the guest, in S mode if it is delegated and in
+    // VS mode if the hypervisor delegates it further
+    pub(crate) fn take_trap(&mut self, exception: Exception) {
+        let cause = exception.cause();
+        let target = self.trap_target(
+            self.csr.medeleg & (1 << cause) != 0,
+            self.csr.hedeleg & (1 << cause) != 0,
+        );
+        let gva = self.guest_access && exception.has_address();
+        self.trap(
+            false,
+            cause,
+            exception.tval(),
+            exception.htval(),
+            gva,
+            target,
+        );
+    }
+
+    pub(crate) fn take_interrupt(&mut self, interrupt: Interrupt) {
+        let bit = 1 << interrupt as u32;
+        let target = self.trap_target(self.csr.mideleg() & bit != 0, self.csr.hideleg & bit != 0);
+        // VS mode sees its interrupts at the S mode codes, one lower
+        let cause = match target {
+            TrapTarget::VirtualSupervisor => interrupt as u32 - 1,
+            _ => interrupt as u32,
+        };
+        self.trap(true, cause, 0, 0, false, target);
+    }
+
+    // Traps from M mode always stay in M mode, delegated ones are taken in S
+    // mode, or in VS mode when they happen with V = 1 and HS delegates too
+    fn trap_target(&self, delegated: bool, virtual_delegated: bool) -> TrapTarget {
+        if !delegated || self.privilege == Privilege::Machine {
+            TrapTarget::Machine
+        } else if self.virt && virtual_delegated {
+            TrapTarget::VirtualSupervisor
+        } else {
+            TrapTarget::Supervisor
+        }
+    }
+
+    // Highest priority interrupt that is pending, enabled and not masked by
+    // the global enable of the mode it traps to
+    pub(crate) fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csr.pending() & self.csr.mie;
+        if pending == 0 {
+            return None;
+        }
+
+        // interrupts for a more privileged mode are always enabled, for the
+        // current mode only when xstatus.xIE is set. HS interrupts are always
+        // enabled while a guest runs.
+        let m_enabled =
+            self.privilege < Privilege::Machine || self.csr.mstatus & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.virt
+            || self.privilege < Privilege::Supervisor
+            || (self.privilege == Privilege::Supervisor
+                && self.csr.mstatus & csr::MSTATUS_SIE != 0);
+        let vs_enabled = self.virt
+            && (self.privilege == Privilege::User || self.csr.vsstatus & csr::MSTATUS_SIE != 0);
+
+        let mideleg = self.csr.mideleg();
+        let m_pending = if m_enabled { pending & !mideleg } else { 0 };
+        let s_pending = if s_enabled {
+            pending & mideleg & !self.csr.hideleg
+        } else {
+            0
+        };
+        let vs_pending = if vs_enabled {
+            pending & self.csr.hideleg
+        } else {
+            0
+        };
+
+        INTERRUPT_PRIORITY
+            .into_iter()
+            .find(|interrupt| (m_pending | s_pending | vs_pending) & (1 << *interrupt as u32) != 0)
+    }
+
+    // `htval` and `gva` only matter for traps out of V = 1, they end up in
+    // htval / mtval2 and xstatus.GVA
+    fn trap(
+        &mut self,
+        interrupt: bool,
+        cause: u32,
+        tval: u32,
+        htval: u32,
+        gva: bool,
+        target: TrapTarget,
+    ) {
+        let pc = self.get_register(Registers::Pc as u32);
+        let mcause = if interrupt { cause | 1 << 31 } else { cause };
+
+        match target {
+            TrapTarget::VirtualSupervisor => {
+                self.csr.vsepc = pc;
+                self.csr.vscause = mcause;
+                self.csr.vstval = tval;
+                self.csr.vsstatus = enter_supervisor(self.csr.vsstatus, self.privilege);
+
+                self.privilege = Privilege::Supervisor;
+                self.set_register(
+                    Registers::Pc as u32,
+                    trap_vector(self.csr.vstvec, interrupt, cause),
+                );
+            }
+            TrapTarget::Supervisor => {
+                self.csr.sepc = pc;
+                self.csr.scause = mcause;
+                self.csr.stval = tval;
+                self.csr.htval = htval;
+                self.csr.htinst = 0;
+                self.csr.mstatus = enter_supervisor(self.csr.mstatus, self.privilege);
+
+                let mut hstatus = self.csr.hstatus & !(csr::HSTATUS_SPV | csr::HSTATUS_GVA);
+                if self.virt {
+                    hstatus |= csr::HSTATUS_SPV;
+                    hstatus &= !csr::HSTATUS_SPVP;
+                    if self.privilege == Privilege::Supervisor {
+                        hstatus |= csr::HSTATUS_SPVP;
+                    }
+                }
+                if gva {
+                    hstatus |= csr::HSTATUS_GVA;
+                }
+                self.csr.hstatus = hstatus;
+
+                self.privilege = Privilege::Supervisor;
+                self.virt = false;
+                self.set_register(
+                    Registers::Pc as u32,
+                    trap_vector(self.csr.stvec, interrupt, cause),
+                );
+            }
+            TrapTarget::Machine => {
+                self.csr.mepc = pc;
+                self.csr.mcause = mcause;
+                self.csr.mtval = tval;
+                self.csr.mtval2 = htval;
+                self.csr.mtinst = 0;
+
+                let mie = self.csr.mstatus & csr::MSTATUS_MIE != 0;
+                self.csr.mstatus &= !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP);
+                if mie {
+                    self.csr.mstatus |= csr::MSTATUS_MPIE;
+                }
+                self.csr.mstatus |= (self.privilege as u32) << csr::MSTATUS_MPP_SHIFT;
+
+                self.csr.mstatush &= !(csr::MSTATUSH_MPV | csr::MSTATUSH_GVA);
+                if self.virt {
+                    self.csr.mstatush |= csr::MSTATUSH_MPV;
+                }
+                if gva {
+                    self.csr.mstatush |= csr::MSTATUSH_GVA;
+                }
+
+                self.csr.triggers.enter_machine_trap();
+                self.privilege = Privilege::Machine;
+                self.virt = false;
+                self.set_register(
+                    Registers::Pc as u32,
+                    trap_vector(self.csr.mtvec, interrupt, cause),
+                );
+            }
+        }
+    }
+
+    // Return from a machine mode trap handler to the mode saved in MPP
+    pub(crate) fn mret(&mut self) {
+        let mpie = self.csr.mstatus & csr::MSTATUS_MPIE != 0;
+        self.csr.mstatus &= !csr::MSTATUS_MIE;
+        if mpie {
+            self.csr.mstatus |= csr::MSTATUS_MIE;
+        }
+        self.csr.mstatus |= csr::MSTATUS_MPIE;
+
+        let mpp = (self.csr.mstatus & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT;
+        self.privilege = Privilege::from_bits(mpp).unwrap_or(Privilege::Machine);
+        // MPP is reset to the least privileged mode, leaving M mode also drops MPRV
+        self.csr.mstatus &= !csr::MSTATUS_MPP;
+        if self.privilege != Privilege::Machine {
+            self.csr.mstatus &= !csr::MSTATUS_MPRV;
+        }
+        // MPV picks between HS/U and VS/VU
+        self.virt =
+            self.privilege != Privilege::Machine && self.csr.mstatush & csr::MSTATUSH_MPV != 0;
+        self.csr.mstatush &= !csr::MSTATUSH_MPV;
+        self.csr.triggers.leave_machine_trap();
+
+        self.next_pc = self.csr.mepc;
+    }
+
+    // Return from a supervisor mode trap handler. In VS mode it uses the
+    // guest's registers and stays virtualized, in HS mode hstatus.SPV picks
+    // whether it enters the guest.
+    pub(crate) fn sret(&mut self) {
+        if self.virt {
+            self.privilege = leave_supervisor(&mut self.csr.vsstatus);
+            self.next_pc = self.csr.vsepc;
+            return;
+        }
+
+        self.privilege = leave_supervisor(&mut self.csr.mstatus);
+        self.csr.mstatus &= !csr::MSTATUS_MPRV;
+        self.virt = self.csr.hstatus & csr::HSTATUS_SPV != 0;
+        self.csr.hstatus &= !csr::HSTATUS_SPV;
+
+        self.next_pc = self.csr.sepc;
+    }
+}
+
+// sstatus (or vsstatus) on a trap into S mode from `privilege`
+fn enter_supervisor(status: u32, privilege: Privilege) -> u32 {
+    let sie = status & csr::MSTATUS_SIE != 0;
+    let mut status = status & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP);
+    if sie {
+        status |= csr::MSTATUS_SPIE;
+    }
+    if privilege == Privilege::Supervisor {
+        status |= csr::MSTATUS_SPP;
+    }
+    status
+}
+
+// sret's update of sstatus (or vsstatus), returns the mode saved in SPP
+fn leave_supervisor(status: &mut u32) -> Privilege {
+    let spie = *status & csr::MSTATUS_SPIE != 0;
+    *status &= !csr::MSTATUS_SIE;
+    if spie {
+        *status |= csr::MSTATUS_SIE;
+    }
+    *status |= csr::MSTATUS_SPIE;
+
+    let privilege = if *status & csr::MSTATUS_SPP != 0 {
+        Privilege::Supervisor
+    } else {
+        Privilege::User
+    };
+    *status &= !csr::MSTATUS_SPP;
+    privilege
+}
+
+// Handler address for a trap. In vectored mode (xtvec.MODE = 1) interrupts
+// jump to BASE + 4 * cause, exceptions always go to BASE.
+pub(crate) fn trap_vector(tvec: u32, interrupt: bool, cause: u32) -> u32 {
+    let base = tvec & !0x3;
+    if interrupt && tvec & 0x3 == 1 {
+        base.wrapping_add(4 * cause)
+    } else {
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        registers::Registers,
+        tests::vm_with_program,
+        WORD_SIZE,
+    };
+
+    use super::{trap_vector, Interrupt};
+
+    #[test]
+    fn test_ecall_and_mret() {
+        let mut program = vec![0; 0x120 / WORD_SIZE];
+        program[..5].copy_from_slice(&[
+            0x10000293, // addi x5, x0, 0x100
+            0x30529073, // csrw mtvec, x5
+            0x30046073, // csrsi mstatus, MIE
+            0x00000073, // ecall
+            0x00100413, // addi x8, x0, 1
+        ]);
+        program[0x100 / WORD_SIZE..].copy_from_slice(&[
+            0x34202373, // csrr x6, mcause
+            0x341023f3, // csrr x7, mepc
+            0x00438393, // addi x7, x7, 4
+            0x34139073, // csrw mepc, x7
+            0x30200073, // mret
+            0, 0, 0,
+        ]);
+        let mut vm = vm_with_program(&program);
+
+        for _ in 0..4 {
+            vm.step();
+        }
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x100);
+        assert_eq!(vm.csr.mepc, 0xc);
+        assert_eq!(vm.csr.mstatus & csr::MSTATUS_MIE, 0);
+        assert_ne!(vm.csr.mstatus & csr::MSTATUS_MPIE, 0);
+
+        for _ in 0..5 {
+            vm.step();
+        }
+        assert_eq!(vm.get_register(Registers::T1 as u32), 11);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x10);
+        assert_ne!(vm.csr.mstatus & csr::MSTATUS_MIE, 0);
+
+        vm.step();
+        assert_eq!(vm.get_register(Registers::S0 as u32), 1);
+    }
+
+    #[test]
+    fn test_faults_set_mcause_and_mtval() {
+        let mut vm = vm_with_program(&[
+            0x0060006f, // jal x0, 6
+            0x7c0022f3, // csrr x5, 0x7c0 (not implemented)
+            0xf1429073, // csrw mhartid, x5
+            0xffffffff,
+        ]);
+
+        vm.step();
+        assert_eq!((vm.csr.mcause, vm.csr.mtval, vm.csr.mepc), (0, 6, 0));
+
+        vm.set_register(Registers::Pc as u32, 4);
+        vm.step();
+        assert_eq!((vm.csr.mcause, vm.csr.mtval), (2, 0x7c0022f3));
+
+        vm.set_register(Registers::Pc as u32, 8);
+        vm.step();
+        assert_eq!((vm.csr.mcause, vm.csr.mtval), (2, 0xf1429073));
+
+        vm.set_register(Registers::Pc as u32, 12);
+        vm.step();
+        assert_eq!(
+            (vm.csr.mcause, vm.csr.mtval, vm.csr.mepc),
+            (2, 0xffffffff, 12)
+        );
+    }
+
+    #[test]
+    fn test_trap_vector() {
+        assert_eq!(trap_vector(0x8000_0001, false, 11), 0x8000_0000);
+        assert_eq!(trap_vector(0x8000_0001, true, 7), 0x8000_001c);
+        assert_eq!(trap_vector(0x8000_0000, true, 7), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_delegation_to_supervisor() {
+        let mut program = vec![0; 0x210 / WORD_SIZE];
+        program[0] = 0x30200073; // mret
+        program[0x40 / WORD_SIZE] = 0x00100073; // ebreak
+        program[0x200 / WORD_SIZE] = 0x00000073; // ecall
+        let mut vm = vm_with_program(&program);
+        vm.csr.mtvec = 0x100;
+        vm.csr.stvec = 0x200;
+        vm.csr.mepc = 0x40;
+        vm.csr.medeleg = 1 << 3;
+        vm.csr
+            .write(
+                csr::MSTATUS,
+                1 << csr::MSTATUS_MPP_SHIFT,
+                Privilege::Machine,
+            )
+            .unwrap();
+
+        // mret drops to S mode
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::Supervisor);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x40);
+
+        // the breakpoint is delegated
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::Supervisor);
+        assert_eq!((vm.csr.scause, vm.csr.sepc), (3, 0x40));
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x200);
+
+        // ecall from S mode is not
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::Machine);
+        assert_eq!((vm.csr.mcause, vm.csr.mepc), (9, 0x200));
+        assert_eq!(
+            vm.csr.mstatus & csr::MSTATUS_MPP,
+            1 << csr::MSTATUS_MPP_SHIFT
+        );
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x100);
+
+        // S mode cannot touch M mode csrs
+        assert_eq!(vm.csr.read(csr::MSTATUS, Privilege::Supervisor), None);
+        assert!(vm.csr.read(csr::SSTATUS, Privilege::Supervisor).is_some());
+    }
+
+    #[test]
+    fn test_delegated_interrupt() {
+        let mut vm = vm_with_program(&[0; 4]);
+        vm.csr.stvec = 0x201; // vectored
+        vm.csr.mideleg = csr::MIP_SSIP;
+        vm.csr.mie = csr::MIP_SSIP;
+        vm.csr.mip = csr::MIP_SSIP;
+
+        // delegated interrupts are never taken in M mode
+        assert_eq!(vm.pending_interrupt(), None);
+
+        vm.privilege = Privilege::Supervisor;
+        assert_eq!(vm.pending_interrupt(), None);
+        vm.csr.mstatus |= csr::MSTATUS_SIE;
+        assert_eq!(vm.pending_interrupt(), Some(Interrupt::SupervisorSoftware));
+
+        vm.step();
+        assert_eq!(vm.csr.scause, 1 << 31 | 1);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x204);
+        assert_eq!(vm.csr.mstatus & csr::MSTATUS_SIE, 0);
+
+        // M mode interrupts preempt S mode even with MIE clear
+        vm.csr.mie |= csr::MIP_MTIP;
+        vm.csr.mip |= csr::MIP_MTIP;
+        assert_eq!(vm.pending_interrupt(), Some(Interrupt::MachineTimer));
+    }
+
+    #[test]
+    fn test_user_mode_restrictions() {
+        let mut vm = vm_with_program(&[
+            0x100022f3, // csrr x5, sstatus
+            0x10200073, // sret
+            0x10500073, // wfi
+            0x00000073, // ecall
+        ]);
+        vm.csr.mtvec = 0x100;
+
+        let causes = [2, 2, 2, 8];
+        for (i, cause) in causes.iter().enumerate() {
+            vm.privilege = Privilege::User;
+            vm.set_register(Registers::Pc as u32, (i * WORD_SIZE) as u32);
+            vm.step();
+            assert_eq!(vm.privilege, Privilege::Machine);
+            assert_eq!(vm.csr.mcause, *cause);
+            assert_eq!(vm.csr.mstatus & csr::MSTATUS_MPP, 0);
+        }
+
+        // wfi in S mode only traps with mstatus.TW
+        vm.privilege = Privilege::Supervisor;
+        vm.set_register(Registers::Pc as u32, 8);
+        vm.step();
+        assert_eq!(vm.get_register(Registers::Pc as u32), 12);
+        assert!(vm.waiting);
+
+        vm.waiting = false;
+        vm.csr.mstatus |= csr::MSTATUS_TW;
+        vm.set_register(Registers::Pc as u32, 8);
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::Machine);
+        assert_eq!(vm.csr.mcause, 2);
+    }
+
+    #[test]
+    fn test_sret_and_mprv() {
+        let mut vm = vm_with_program(&[0x10200073]); // sret
+        vm.privilege = Privilege::Supervisor;
+        vm.csr.sepc = 0x40;
+
+        // SPP = U returns to user mode
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::User);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x40);
+
+        vm.privilege = Privilege::Machine;
+        vm.csr.mstatus &= !csr::MSTATUS_MPP;
+        vm.csr.mstatus |= csr::MSTATUS_MPRV | 1 << csr::MSTATUS_MPP_SHIFT;
+        assert_eq!(vm.data_privilege(), Privilege::Supervisor);
+        vm.csr.mstatus &= !csr::MSTATUS_MPRV;
+        assert_eq!(vm.data_privilege(), Privilege::Machine);
+    }
+}
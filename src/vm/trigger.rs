@@ -0,0 +1,515 @@
+// Sdtrig: hardware breakpoints and watchpoints. Every trigger is either
+// disabled or an mcontrol6 address/data match on execute, load or store that
+// raises a breakpoint exception or halts the hart in debug mode.
+use super::{csr::Privilege, mmu::AccessType, registers::Registers, trap::Exception, Vm};
+
+pub(crate) const TRIGGER_COUNT: usize = 4;
+
+// trigger csrs
+pub(crate) const TSELECT: u32 = 0x7A0;
+pub(crate) const TDATA1: u32 = 0x7A1;
+pub(crate) const TDATA2: u32 = 0x7A2;
+pub(crate) const TDATA3: u32 = 0x7A3;
+pub(crate) const TINFO: u32 = 0x7A4;
+pub(crate) const TCONTROL: u32 = 0x7A5;
+
+// tdata1: type in 31:28, dmode (only debug mode may write the trigger) in 27
+const TDATA1_TYPE_SHIFT: u32 = 28;
+const TDATA1_DMODE: u32 = 1 << 27;
+const TYPE_MCONTROL6: u32 = 6;
+const TYPE_DISABLED: u32 = 15;
+
+// mcontrol6 fields
+const MCONTROL6_LOAD: u32 = 1 << 0;
+const MCONTROL6_STORE: u32 = 1 << 1;
+const MCONTROL6_EXECUTE: u32 = 1 << 2;
+const MCONTROL6_U: u32 = 1 << 3;
+const MCONTROL6_S: u32 = 1 << 4;
+const MCONTROL6_M: u32 = 1 << 6;
+const MCONTROL6_MATCH_SHIFT: u32 = 7;
+const MCONTROL6_MATCH: u32 = 0xF << MCONTROL6_MATCH_SHIFT;
+const MCONTROL6_CHAIN: u32 = 1 << 11;
+const MCONTROL6_ACTION_SHIFT: u32 = 12;
+const MCONTROL6_ACTION: u32 = 0xF << MCONTROL6_ACTION_SHIFT;
+const MCONTROL6_SIZE_SHIFT: u32 = 16;
+const MCONTROL6_SIZE: u32 = 0x7 << MCONTROL6_SIZE_SHIFT;
+const MCONTROL6_SELECT: u32 = 1 << 21;
+const MCONTROL6_HIT0: u32 = 1 << 22;
+const MCONTROL6_VU: u32 = 1 << 23;
+const MCONTROL6_VS: u32 = 1 << 24;
+const MCONTROL6_WRITABLE: u32 = MCONTROL6_LOAD
+    | MCONTROL6_STORE
+    | MCONTROL6_EXECUTE
+    | MCONTROL6_U
+    | MCONTROL6_S
+    | MCONTROL6_M
+    | MCONTROL6_MATCH
+    | MCONTROL6_CHAIN
+    | MCONTROL6_ACTION
+    | MCONTROL6_SIZE
+    | MCONTROL6_SELECT
+    | MCONTROL6_HIT0
+    | MCONTROL6_VU
+    | MCONTROL6_VS;
+
+// tinfo: version 1 of the spec, mcontrol6 and disabled triggers
+const TINFO_VALUE: u32 = 1 << 24 | 1 << TYPE_MCONTROL6 | 1 << TYPE_DISABLED;
+
+// tcontrol: breakpoint exceptions in M mode are off inside M mode trap
+// handlers, so a trigger cannot fire again in its own handler
+const TCONTROL_MTE: u32 = 1 << 3;
+const TCONTROL_MPTE: u32 = 1 << 7;
+
+// What a firing trigger does, mcontrol6.action
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum TriggerAction {
+    Breakpoint, // breakpoint exception
+    DebugMode,  // halt in debug mode
+}
+
+// Where the hart was when a trigger halted it, what dpc and dcsr.prv/v hold
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct DebugHalt {
+    pub(crate) dpc: u32,
+    pub(crate) privilege: Privilege,
+    pub(crate) virt: bool,
+    pub(crate) trigger: usize,
+}
+
+// One memory access or instruction fetch as the triggers see it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct TriggerEvent {
+    pub(crate) access: AccessType,
+    pub(crate) address: u32,
+    pub(crate) size: usize,
+    // instruction bits for execute, the value for loads and stores. None
+    // while the access has not happened yet, data triggers cannot fire then.
+    pub(crate) data: Option<u32>,
+    pub(crate) privilege: Privilege,
+    pub(crate) virt: bool,
+}
+
+pub(crate) struct Triggers {
+    tselect: usize,
+    tdata1: [u32; TRIGGER_COUNT],
+    tdata2: [u32; TRIGGER_COUNT],
+    tcontrol: u32,
+}
+
+impl Triggers {
+    pub(crate) fn new() -> Self {
+        Self {
+            tselect: 0,
+            tdata1: [TYPE_DISABLED << TDATA1_TYPE_SHIFT; TRIGGER_COUNT],
+            tdata2: [0; TRIGGER_COUNT],
+            tcontrol: 0,
+        }
+    }
+
+    pub(crate) fn read(&self, address: u32) -> Option<u32> {
+        let value = match address {
+            TSELECT => self.tselect as u32,
+            TDATA1 => self.tdata1[self.tselect],
+            TDATA2 => self.tdata2[self.tselect],
+            TDATA3 => 0, // textra32, no context matching
+            TINFO => TINFO_VALUE,
+            TCONTROL => self.tcontrol,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Triggers with dmode set belong to the debugger, only debug mode can
+    // change them
+    pub(crate) fn write(&mut self, address: u32, value: u32, debug_mode: bool) -> Option<()> {
+        let locked = self.tdata1[self.tselect] & TDATA1_DMODE != 0 && !debug_mode;
+        match address {
+            // selecting a trigger that does not exist keeps the current one
+            TSELECT => {
+                if (value as usize) < TRIGGER_COUNT {
+                    self.tselect = value as usize;
+                }
+            }
+            TDATA1 if !locked => self.tdata1[self.tselect] = write_tdata1(value, debug_mode),
+            TDATA2 if !locked => self.tdata2[self.tselect] = value,
+            TDATA1 | TDATA2 | TDATA3 | TINFO => {}
+            TCONTROL => self.tcontrol = value & (TCONTROL_MTE | TCONTROL_MPTE),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // tdata1 and tdata2 of trigger `index` as the debugger writes them
+    pub(crate) fn set(&mut self, index: usize, tdata1: u32, tdata2: u32) -> Option<()> {
+        if index >= TRIGGER_COUNT {
+            return None;
+        }
+        self.tdata1[index] = write_tdata1(tdata1, true);
+        self.tdata2[index] = tdata2;
+        Some(())
+    }
+
+    // tcontrol on a trap into M mode and on mret
+    pub(crate) fn enter_machine_trap(&mut self) {
+        let mte = self.tcontrol & TCONTROL_MTE != 0;
+        self.tcontrol = if mte { TCONTROL_MPTE } else { 0 };
+    }
+
+    pub(crate) fn leave_machine_trap(&mut self) {
+        if self.tcontrol & TCONTROL_MPTE != 0 {
+            self.tcontrol |= TCONTROL_MTE;
+        } else {
+            self.tcontrol &= !TCONTROL_MTE;
+        }
+    }
+
+    // First trigger (or chain of triggers) matching `event`, its hit bit is
+    // set. Chained triggers only fire when the whole chain matches.
+    pub(crate) fn check(&mut self, event: &TriggerEvent) -> Option<(usize, TriggerAction)> {
+        let mut chain_ok = true;
+        for index in 0..TRIGGER_COUNT {
+            let tdata1 = self.tdata1[index];
+            let chained = tdata1 & MCONTROL6_CHAIN != 0 && index + 1 < TRIGGER_COUNT;
+            chain_ok &= self.matches(index, event);
+            if chained {
+                continue;
+            }
+            if chain_ok {
+                let action = match (tdata1 & MCONTROL6_ACTION) >> MCONTROL6_ACTION_SHIFT {
+                    1 => TriggerAction::DebugMode,
+                    _ => TriggerAction::Breakpoint,
+                };
+                // breakpoints in M mode are held off by tcontrol.MTE
+                if action == TriggerAction::Breakpoint
+                    && event.privilege == Privilege::Machine
+                    && self.tcontrol & TCONTROL_MTE == 0
+                {
+                    chain_ok = true;
+                    continue;
+                }
+                self.tdata1[index] |= MCONTROL6_HIT0;
+                return Some((index, action));
+            }
+            chain_ok = true;
+        }
+        None
+    }
+
+    fn matches(&self, index: usize, event: &TriggerEvent) -> bool {
+        let tdata1 = self.tdata1[index];
+        if tdata1 >> TDATA1_TYPE_SHIFT != TYPE_MCONTROL6 {
+            return false;
+        }
+
+        let access = match event.access {
+            AccessType::Instruction => MCONTROL6_EXECUTE,
+            AccessType::Load => MCONTROL6_LOAD,
+            AccessType::Store => MCONTROL6_STORE,
+        };
+        let mode = match (event.privilege, event.virt) {
+            (Privilege::Machine, _) => MCONTROL6_M,
+            (Privilege::Supervisor, false) => MCONTROL6_S,
+            (Privilege::User, false) => MCONTROL6_U,
+            (Privilege::Supervisor, true) => MCONTROL6_VS,
+            (Privilege::User, true) => MCONTROL6_VU,
+        };
+        if tdata1 & access == 0 || tdata1 & mode == 0 {
+            return false;
+        }
+
+        // size 0 is any access, otherwise 1, 2 or 4 bytes
+        let size = (tdata1 & MCONTROL6_SIZE) >> MCONTROL6_SIZE_SHIFT;
+        if size != 0 && 1 << (size - 1) != event.size {
+            return false;
+        }
+
+        let kind = (tdata1 & MCONTROL6_MATCH) >> MCONTROL6_MATCH_SHIFT;
+        let tdata2 = self.tdata2[index];
+        if tdata1 & MCONTROL6_SELECT != 0 {
+            event.data.is_some_and(|data| compare(kind, tdata2, data))
+        } else {
+            // an address trigger matches on any byte of the access, before and
+            // after it so it can chain to a data trigger
+            (0..event.size as u32)
+                .any(|byte| compare(kind, tdata2, event.address.wrapping_add(byte)))
+        }
+    }
+}
+
+// mcontrol6.match against tdata2, bit 3 negates the base match
+fn compare(kind: u32, tdata2: u32, value: u32) -> bool {
+    let matched = match kind & 0x7 {
+        0 => value == tdata2,
+        // napot: the trailing ones of tdata2 (and the zero above them) are
+        // don't care bits
+        1 => {
+            let mask = !(tdata2 ^ (tdata2.wrapping_add(1)));
+            value & mask == tdata2 & mask
+        }
+        2 => value >= tdata2,
+        3 => value < tdata2,
+        // the low or high half of tdata2 masks the other half
+        4 => value as u16 & (tdata2 >> 16) as u16 == tdata2 as u16 & (tdata2 >> 16) as u16,
+        5 => (value >> 16) as u16 & (tdata2 >> 16) as u16 == tdata2 as u16 & (tdata2 >> 16) as u16,
+        _ => false,
+    };
+    if kind & 0x8 != 0 {
+        !matched
+    } else {
+        matched
+    }
+}
+
+// WARL tdata1: anything but mcontrol6 disables the trigger, dmode needs
+// debug mode and entering debug mode needs dmode
+fn write_tdata1(value: u32, debug_mode: bool) -> u32 {
+    if value >> TDATA1_TYPE_SHIFT != TYPE_MCONTROL6 {
+        return TYPE_DISABLED << TDATA1_TYPE_SHIFT;
+    }
+    let dmode = value & TDATA1_DMODE != 0 && debug_mode;
+    let mut value = TYPE_MCONTROL6 << TDATA1_TYPE_SHIFT | value & MCONTROL6_WRITABLE;
+    let kind = (value & MCONTROL6_MATCH) >> MCONTROL6_MATCH_SHIFT;
+    if !matches!(kind, 0..=5 | 8 | 9 | 12 | 13) {
+        value &= !MCONTROL6_MATCH;
+    }
+    let action = (value & MCONTROL6_ACTION) >> MCONTROL6_ACTION_SHIFT;
+    if action > 1 || (action == 1 && !dmode) {
+        value &= !MCONTROL6_ACTION;
+    }
+    if (value & MCONTROL6_SIZE) >> MCONTROL6_SIZE_SHIFT > 3 {
+        value &= !MCONTROL6_SIZE;
+    }
+    if dmode {
+        value |= TDATA1_DMODE;
+    }
+    value
+}
+
+impl Vm {
+    // Runs the triggers on a fetch, load or store in the current mode. A hit
+    // is a breakpoint exception with the address in xtval, or a request for
+    // debug mode that step() honours instead of taking the trap.
+    pub(crate) fn check_triggers(
+        &mut self,
+        access: AccessType,
+        address: u32,
+        size: usize,
+        data: Option<u32>,
+    ) -> Result<(), Exception> {
+        let event = TriggerEvent {
+            access,
+            address,
+            size,
+            data,
+            privilege: self.privilege,
+            virt: self.virt,
+        };
+        match self.csr.triggers.check(&event) {
+            Some((trigger, action)) => {
+                if action == TriggerAction::DebugMode {
+                    self.debug_request = Some(trigger);
+                }
+                Err(Exception::Breakpoint(address))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Halts before the instruction that hit the trigger, debug mode runs
+    // with M mode privileges
+    pub(crate) fn enter_debug_mode(&mut self, trigger: usize) {
+        self.halted = Some(DebugHalt {
+            dpc: self.get_register(Registers::Pc as u32),
+            privilege: self.privilege,
+            virt: self.virt,
+            trigger,
+        });
+        self.privilege = Privilege::Machine;
+        self.virt = false;
+    }
+
+    // Programs a trigger from the debugger's side, which unlike the hart
+    // can pick the debug mode action. None if there is no such trigger.
+    pub fn set_debug_trigger(&mut self, index: usize, tdata1: u32, tdata2: u32) -> Option<()> {
+        self.csr.triggers.set(index, tdata1, tdata2)
+    }
+
+    // dpc while the hart waits in debug mode, run() and step() do nothing
+    // until it resumes
+    pub fn halted(&self) -> Option<u32> {
+        self.halted.map(|halt| halt.dpc)
+    }
+
+    // dret on behalf of the debugger: continue at dpc in the halted mode
+    pub fn resume(&mut self) {
+        if let Some(halt) = self.halted.take() {
+            self.set_register(Registers::Pc as u32, halt.dpc);
+            self.privilege = halt.privilege;
+            self.virt = halt.virt;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        csr::{self, Privilege},
+        instruction::into_byte,
+        registers::Registers,
+        tests::vm_with_program,
+        WORD_SIZE,
+    };
+
+    use super::*;
+
+    const NOP: u32 = 0x00000013;
+
+    fn mcontrol6(access: u32, match_kind: u32) -> u32 {
+        TYPE_MCONTROL6 << TDATA1_TYPE_SHIFT
+            | access
+            | MCONTROL6_M
+            | MCONTROL6_U
+            | match_kind << MCONTROL6_MATCH_SHIFT
+    }
+
+    fn set_trigger(vm: &mut Vm, index: u32, tdata1: u32, tdata2: u32) {
+        vm.csr.write(TSELECT, index, Privilege::Machine).unwrap();
+        vm.csr.write(TDATA1, tdata1, Privilege::Machine).unwrap();
+        vm.csr.write(TDATA2, tdata2, Privilege::Machine).unwrap();
+    }
+
+    #[test]
+    fn test_execute_breakpoint() {
+        let mut vm = vm_with_program(&[NOP; 4]);
+        assert_eq!(vm.csr.read(TINFO, Privilege::Machine), Some(TINFO_VALUE));
+        set_trigger(&mut vm, 0, mcontrol6(MCONTROL6_EXECUTE, 0), 0x4);
+        vm.csr.mtvec = 0x40;
+
+        // M mode breakpoints wait for tcontrol.MTE
+        vm.step();
+        vm.step();
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x8);
+
+        vm.set_register(Registers::Pc as u32, 0);
+        vm.csr
+            .write(TCONTROL, TCONTROL_MTE, Privilege::Machine)
+            .unwrap();
+        vm.step();
+        vm.step();
+        assert_eq!(vm.csr.mcause, 3);
+        assert_eq!(vm.csr.mepc, 0x4);
+        assert_eq!(vm.csr.mtval, 0x4);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x40);
+        assert_ne!(
+            vm.csr.read(TDATA1, Privilege::Machine).unwrap() & MCONTROL6_HIT0,
+            0
+        );
+        // the handler runs with MTE clear, mret brings it back
+        assert_eq!(
+            vm.csr.read(TCONTROL, Privilege::Machine),
+            Some(TCONTROL_MPTE)
+        );
+        vm.mret();
+        assert_eq!(
+            vm.csr.read(TCONTROL, Privilege::Machine),
+            Some(TCONTROL_MTE | TCONTROL_MPTE)
+        );
+    }
+
+    #[test]
+    fn test_chained_watchpoints() {
+        let mut vm = vm_with_program(&[
+            0x10002283, // lw x5, 0x100(x0)
+            0x10602223, // sw x6, 0x104(x0)
+        ]);
+        vm.csr
+            .write(TCONTROL, TCONTROL_MTE, Privilege::Machine)
+            .unwrap();
+        vm.csr.mtvec = 0x40;
+        // a load of 0x1234 from 0x100
+        set_trigger(
+            &mut vm,
+            0,
+            mcontrol6(MCONTROL6_LOAD, 0) | MCONTROL6_CHAIN,
+            0x100,
+        );
+        set_trigger(
+            &mut vm,
+            1,
+            mcontrol6(MCONTROL6_LOAD, 0) | MCONTROL6_SELECT,
+            0x1234,
+        );
+        // a word store anywhere in 0x100..0x108
+        set_trigger(
+            &mut vm,
+            2,
+            mcontrol6(MCONTROL6_STORE, 1) | 3 << MCONTROL6_SIZE_SHIFT,
+            0x103,
+        );
+
+        vm.mem_write(WORD_SIZE, 0x100, &into_byte(0x5555)).unwrap();
+        vm.step();
+        assert_eq!(vm.get_register(5), 0x5555);
+
+        vm.set_register(Registers::Pc as u32, 0);
+        vm.mem_write(WORD_SIZE, 0x100, &into_byte(0x1234)).unwrap();
+        vm.step();
+        assert_eq!(vm.csr.mcause, 3);
+        assert_eq!(vm.csr.mtval, 0x100);
+        assert_eq!(vm.get_register(5), 0x5555);
+
+        // back out of the handler
+        vm.mret();
+        vm.set_register(Registers::Pc as u32, 0x4);
+        vm.set_register(6, 0xAB);
+        vm.step();
+        assert_eq!(vm.csr.mepc, 0x4);
+        assert_eq!(vm.csr.mtval, 0x104);
+        assert_eq!(vm.load(WORD_SIZE, 0x104), Ok(0));
+    }
+
+    #[test]
+    fn test_debug_mode_trigger() {
+        let mut vm = vm_with_program(&[NOP; 4]);
+        let tdata1 = mcontrol6(MCONTROL6_EXECUTE, 0) | TDATA1_DMODE | 1 << MCONTROL6_ACTION_SHIFT;
+        // only the debugger can hand a trigger to debug mode
+        vm.csr.write(TDATA1, tdata1, Privilege::Machine).unwrap();
+        assert_eq!(
+            vm.csr.read(TDATA1, Privilege::Machine).unwrap() & TDATA1_DMODE,
+            0
+        );
+        vm.set_debug_trigger(0, tdata1, 0x4).unwrap();
+        assert_eq!(vm.set_debug_trigger(TRIGGER_COUNT, tdata1, 0x4), None);
+        vm.privilege = Privilege::User;
+
+        // run() comes back once the hart halts instead of spinning
+        assert_eq!(vm.run(u64::MAX), None);
+        assert_eq!(vm.halted(), Some(0x4));
+        assert_eq!(
+            vm.halted,
+            Some(DebugHalt {
+                dpc: 0x4,
+                privilege: Privilege::User,
+                virt: false,
+                trigger: 0,
+            })
+        );
+        assert_eq!(vm.csr.mcause, 0);
+        vm.step();
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x4);
+
+        // locked against the hart, the debugger clears it and resumes
+        vm.csr.write(TDATA1, 0, Privilege::Machine).unwrap();
+        assert_eq!(
+            vm.csr.read(TDATA1, Privilege::Machine),
+            Some(tdata1 | MCONTROL6_HIT0)
+        );
+        vm.set_debug_trigger(0, 0, 0).unwrap();
+        vm.resume();
+        assert_eq!(vm.halted(), None);
+        vm.step();
+        assert_eq!(vm.privilege, Privilege::User);
+        assert_eq!(vm.get_register(Registers::Pc as u32), 0x8);
+        assert_eq!(vm.csr.read(csr::MCAUSE, Privilege::Machine), Some(0));
+    }
+}
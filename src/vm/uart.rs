@@ -0,0 +1,594 @@
+// NS16550A UART, byte wide registers without a shift like QEMU virt's.
+// Transmission is instant, received bytes wait in a 16 byte FIFO that the
+// backend refills as the guest drains it.
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    process::{self, Command, Stdio},
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use super::device::{Device, DeviceNode};
+
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_SOURCE: usize = 10;
+// the usual 1.8432 MHz crystal times two, what QEMU tells guests
+const UART_CLOCK: u32 = 3_686_400;
+
+const FIFO_SIZE: usize = 16;
+// ticks without a new byte before a FIFO below its trigger level interrupts
+const RX_TIMEOUT: u64 = 64;
+
+// register offsets, DLL and DLM replace RBR/THR and IER while LCR.DLAB is set
+const RBR_THR: u64 = 0;
+const IER: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDA: u8 = 1 << 0; // received data available
+const IER_THRE: u8 = 1 << 1; // transmit holding register empty
+const IER_MASK: u8 = 0x0F;
+
+// IIR: bit 0 clear when an interrupt is pending, 7:6 set with the FIFO on
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0C;
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_TRIGGER_SHIFT: u8 = 6;
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// MSR with CTS, DSR and DCD asserted, the other end is always there
+const MSR_CONNECTED: u8 = 0xB0;
+
+// Where the UART's bytes come from and go to
+pub trait Serial {
+    fn read_byte(&mut self) -> Option<u8>; // never blocks
+    fn write_byte(&mut self, byte: u8);
+}
+
+pub struct Uart {
+    serial: Box<dyn Serial>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    thre_pending: bool, // THR emptied since IIR last reported it
+    idle: u64,          // ticks since the last received byte
+}
+
+impl Uart {
+    pub fn new(serial: Box<dyn Serial>) -> Self {
+        Self {
+            serial,
+            rx: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            thre_pending: false,
+            idle: 0,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    fn capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        [1, 4, 8, 14][(self.fcr >> FCR_TRIGGER_SHIFT) as usize]
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.capacity() {
+            self.rx.push_back(byte);
+            self.idle = 0;
+        }
+    }
+
+    // Highest priority pending interrupt: received data, then THR empty
+    fn iir(&self) -> u8 {
+        if self.ier & IER_RDA != 0 {
+            if self.rx.len() >= self.trigger_level() {
+                return IIR_RDA;
+            }
+            if !self.rx.is_empty() && self.idle >= RX_TIMEOUT {
+                return IIR_TIMEOUT;
+            }
+        }
+        if self.ier & IER_THRE != 0 && self.thre_pending {
+            return IIR_THRE;
+        }
+        IIR_NONE
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => {
+                self.idle = 0;
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                // reading IIR acknowledges a THR empty interrupt
+                if iir == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                if self.fifo_enabled() {
+                    iir | IIR_FIFO
+                } else {
+                    iir
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.rx.is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.divisor = self.divisor & 0xFF00 | value as u16,
+            RBR_THR => {
+                if self.mcr & MCR_LOOP != 0 {
+                    self.receive(value);
+                } else {
+                    self.serial.write_byte(value);
+                }
+                self.thre_pending = true;
+            }
+            IER if dlab => self.divisor = self.divisor & 0xFF | (value as u16) << 8,
+            IER => {
+                // enabling the THR empty interrupt raises it right away
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ self.fcr) & FCR_ENABLE != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value & (FCR_ENABLE | 0x3 << FCR_TRIGGER_SHIFT);
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+        Some(self.read_register(offset) as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+        self.write_register(offset, value as u8);
+        Some(())
+    }
+
+    fn tick(&mut self, _time: u64) {
+        self.idle = self.idle.saturating_add(1);
+        // in loopback the line is disconnected from the backend
+        while self.mcr & MCR_LOOP == 0 && self.rx.len() < self.capacity() {
+            match self.serial.read_byte() {
+                Some(byte) => self.receive(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.iir() != IIR_NONE
+    }
+
+    fn device_node(&self) -> Option<DeviceNode> {
+        Some(DeviceNode {
+            name: "serial",
+            compatible: &["ns16550a"],
+            properties: vec![("clock-frequency", UART_CLOCK)],
+        })
+    }
+}
+
+// Bytes from a blocking reader, collected by a thread so the UART can poll
+fn spawn_reader(mut reader: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 256];
+        while let Ok(count @ 1..) = reader.read(&mut buffer) {
+            for byte in &buffer[..count] {
+                if sender.send(*byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// Host stdin and stdout. A terminal is put in raw mode so every key reaches
+// the guest, Ctrl-A x quits.
+pub struct StdioSerial {
+    input: Receiver<u8>,
+    saved: Option<String>, // stty settings to restore
+    escape: bool,          // Ctrl-A seen
+}
+
+impl StdioSerial {
+    pub fn new() -> Self {
+        let saved = if io::stdin().is_terminal() {
+            stty(&["-g"]).inspect(|_| {
+                stty(&["raw", "-echo"]);
+            })
+        } else {
+            None
+        };
+        Self {
+            input: spawn_reader(io::stdin()),
+            saved,
+            escape: false,
+        }
+    }
+
+    fn restore(&self) {
+        if let Some(saved) = &self.saved {
+            stty(&[saved]);
+        }
+    }
+}
+
+impl Default for StdioSerial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial for StdioSerial {
+    fn read_byte(&mut self) -> Option<u8> {
+        let byte = self.input.try_recv().ok()?;
+        if self.saved.is_none() {
+            return Some(byte);
+        }
+        if self.escape {
+            self.escape = false;
+            match byte {
+                b'x' => {
+                    self.restore();
+                    process::exit(0);
+                }
+                0x01 => return Some(byte), // Ctrl-A twice sends one
+                _ => {}
+            }
+        } else if byte == 0x01 {
+            self.escape = true;
+            return None;
+        }
+        Some(byte)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        // the guest may wait for nothing but its own output
+        let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+}
+
+impl Drop for StdioSerial {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+// stty on the controlling terminal, its output for -g
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(File::open("/dev/tty").ok()?)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Input read from one file (a named pipe works too), output appended to another
+pub struct FileSerial {
+    input: Receiver<u8>,
+    output: File,
+}
+
+impl FileSerial {
+    pub fn new(input: &str, output: &str) -> io::Result<Self> {
+        let input = File::open(input)?;
+        let output = File::options().create(true).append(true).open(output)?;
+        Ok(Self {
+            input: spawn_reader(input),
+            output,
+        })
+    }
+}
+
+impl Serial for FileSerial {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let _ = self.output.write_all(&[byte]);
+    }
+}
+
+// In memory, clones share the buffers so a test can keep one to feed input
+// and check output
+#[derive(Clone, Default)]
+pub struct BufferSerial {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl BufferSerial {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.take()
+    }
+}
+
+impl Serial for BufferSerial {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+}
+
+// A host pseudo-terminal, attach with screen or minicom to the slave path
+#[cfg(target_os = "linux")]
+pub struct PtySerial {
+    input: Receiver<u8>,
+    master: File,
+    path: String,
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use std::os::raw::{c_char, c_int};
+
+    pub(super) const O_RDWR: c_int = 0o2;
+    pub(super) const O_NOCTTY: c_int = 0o400;
+
+    extern "C" {
+        pub(super) fn posix_openpt(flags: c_int) -> c_int;
+        pub(super) fn grantpt(fd: c_int) -> c_int;
+        pub(super) fn unlockpt(fd: c_int) -> c_int;
+        pub(super) fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PtySerial {
+    pub fn new() -> io::Result<Self> {
+        use std::{ffi::CStr, os::fd::FromRawFd};
+
+        // SAFETY: plain libc calls, the descriptor is owned by `master` as
+        // soon as it is open and ptsname_r gets the length of its buffer
+        let (master, path) = unsafe {
+            let fd = pty::posix_openpt(pty::O_RDWR | pty::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if pty::grantpt(fd) != 0 || pty::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0; 64];
+            let err = pty::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            (master, path)
+        };
+        Ok(Self {
+            input: spawn_reader(master.try_clone()?),
+            master,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Serial for PtySerial {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    // nobody may be attached yet, the pty buffers what fits and drops the rest
+    fn write_byte(&mut self, byte: u8) {
+        let _ = self.master.write_all(&[byte]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        bus::Bus,
+        csr::{self, Privilege},
+        registers::BaseIsa,
+        tests::allow_all_memory,
+        Vm, BYTE,
+    };
+
+    use super::*;
+
+    fn vm_with_uart() -> (Vm, BufferSerial) {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x1000).unwrap());
+        allow_all_memory(&mut vm);
+        let serial = BufferSerial::new();
+        let uart = Uart::new(Box::new(serial.clone()));
+        vm.attach(
+            "uart",
+            UART_BASE,
+            UART_SIZE,
+            Box::new(uart),
+            Some(UART_SOURCE),
+        )
+        .unwrap();
+        (vm, serial)
+    }
+
+    fn uart_read(vm: &mut Vm, register: u64) -> u32 {
+        vm.load(BYTE, (UART_BASE + register) as u32).unwrap()
+    }
+
+    fn uart_write(vm: &mut Vm, register: u64, value: u32) {
+        vm.store(BYTE, (UART_BASE + register) as u32, value)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_transmit_and_registers() {
+        let (mut vm, serial) = vm_with_uart();
+        for byte in b"ok\r\n" {
+            uart_write(&mut vm, RBR_THR, *byte as u32);
+        }
+        assert_eq!(serial.take_output(), b"ok\r\n");
+        assert_eq!(uart_read(&mut vm, LSR), (LSR_THRE | LSR_TEMT) as u32);
+        assert_eq!(uart_read(&mut vm, IIR_FCR), IIR_NONE as u32);
+
+        // the divisor latch shares the data registers
+        uart_write(&mut vm, LCR, LCR_DLAB as u32);
+        uart_write(&mut vm, RBR_THR, 0x01);
+        uart_write(&mut vm, IER, 0x02);
+        assert_eq!(uart_read(&mut vm, IER), 0x02);
+        uart_write(&mut vm, LCR, 0x03);
+        assert_eq!(uart_read(&mut vm, IER), 0);
+        assert!(vm.load(4, UART_BASE as u32).is_err());
+
+        // a THR empty interrupt is acknowledged by reading IIR
+        uart_write(&mut vm, IER, IER_THRE as u32);
+        assert_eq!(uart_read(&mut vm, IIR_FCR), IIR_THRE as u32);
+        assert_eq!(uart_read(&mut vm, IIR_FCR), IIR_NONE as u32);
+
+        // loopback
+        uart_write(&mut vm, IER, 0);
+        uart_write(&mut vm, MCR, MCR_LOOP as u32);
+        uart_write(&mut vm, RBR_THR, b'z' as u32);
+        assert_eq!(serial.take_output(), b"");
+        assert_eq!(uart_read(&mut vm, LSR) & LSR_DR as u32, LSR_DR as u32);
+        assert_eq!(uart_read(&mut vm, RBR_THR), b'z' as u32);
+    }
+
+    #[test]
+    fn test_receive_interrupts() {
+        let (mut vm, serial) = vm_with_uart();
+        vm.store(4, 0xC00_0000 + UART_SOURCE as u32 * 4, 1).unwrap();
+        vm.store(4, 0xC00_2000, 1 << UART_SOURCE).unwrap(); // M context enable
+        uart_write(
+            &mut vm,
+            IIR_FCR,
+            (FCR_ENABLE | 1 << FCR_TRIGGER_SHIFT) as u32,
+        ); // 4 bytes
+        uart_write(&mut vm, IER, IER_RDA as u32);
+
+        serial.push_input(b"hello, world, from the uart");
+        vm.tick_devices();
+        assert_eq!(uart_read(&mut vm, IIR_FCR), (IIR_RDA | IIR_FIFO) as u32);
+        vm.update_interrupts();
+        assert_ne!(
+            vm.csr.read(csr::MIP, Privilege::Machine).unwrap() & csr::MIP_MEIP,
+            0
+        );
+
+        // the FIFO holds 16 bytes, the backend keeps the rest until it drains
+        let mut received = vec![];
+        while uart_read(&mut vm, LSR) & LSR_DR as u32 != 0 {
+            received.push(uart_read(&mut vm, RBR_THR) as u8);
+        }
+        assert_eq!(received, b"hello, world, fr");
+        vm.tick_devices();
+        while uart_read(&mut vm, LSR) & LSR_DR as u32 != 0 {
+            received.push(uart_read(&mut vm, RBR_THR) as u8);
+        }
+        assert_eq!(received, b"hello, world, from the uart");
+
+        // below the trigger level the FIFO only interrupts after a timeout
+        serial.push_input(b"!");
+        vm.tick_devices();
+        assert_eq!(uart_read(&mut vm, IIR_FCR), (IIR_NONE | IIR_FIFO) as u32);
+        for _ in 0..RX_TIMEOUT {
+            vm.tick_devices();
+        }
+        assert_eq!(uart_read(&mut vm, IIR_FCR), (IIR_TIMEOUT | IIR_FIFO) as u32);
+        assert_eq!(uart_read(&mut vm, RBR_THR), b'!' as u32);
+        assert_eq!(uart_read(&mut vm, IIR_FCR), (IIR_NONE | IIR_FIFO) as u32);
+    }
+}
@@ -0,0 +1,320 @@
+// virtio-blk over a host image file. The image can be shared read only,
+// written in place, or covered by an in-memory copy-on-write overlay that
+// is dropped when the VM exits.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use super::{
+    queue::{BadChain, Chain, GuestMemory, Virtqueue},
+    VirtioDevice,
+};
+
+const DEVICE_ID: u32 = 2;
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// request status, the last byte of every request
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DiskMode {
+    ReadOnly,
+    ReadWrite,
+    Overlay, // guest writes go to memory, the image is never modified
+}
+
+pub struct VirtioBlock {
+    image: File,
+    sectors: u64,
+    mode: DiskMode,
+    overlay: HashMap<u64, Vec<u8>>, // written sectors in Overlay mode
+    id: Vec<u8>,                    // serial number for GET_ID
+}
+
+impl VirtioBlock {
+    pub fn open(path: &str, mode: DiskMode) -> io::Result<Self> {
+        let image = File::options()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        // a partial sector at the end of the image is not part of the disk
+        let sectors = image.metadata()?.len() / SECTOR_SIZE as u64;
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut id = name.into_bytes();
+        id.truncate(ID_SIZE);
+        Ok(Self {
+            image,
+            sectors,
+            mode,
+            overlay: HashMap::new(),
+            id,
+        })
+    }
+
+    fn read_sectors(&mut self, sector: u64, data: &mut [u8]) -> io::Result<()> {
+        for (index, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + index as u64;
+            match self.overlay.get(&sector) {
+                Some(saved) => chunk.copy_from_slice(saved),
+                None => {
+                    self.image
+                        .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                    self.image.read_exact(chunk)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        match self.mode {
+            DiskMode::ReadOnly => Err(io::ErrorKind::PermissionDenied.into()),
+            DiskMode::ReadWrite => {
+                self.image
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.image.write_all(data)
+            }
+            DiskMode::Overlay => {
+                for (index, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+                    self.overlay.insert(sector + index as u64, chunk.to_vec());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Whole sectors inside the disk
+    fn in_range(&self, sector: u64, length: usize) -> bool {
+        length.is_multiple_of(SECTOR_SIZE)
+            && sector
+                .checked_add((length / SECTOR_SIZE) as u64)
+                .is_some_and(|end| end <= self.sectors)
+    }
+
+    // Carries out one request, returns how many bytes went into the chain
+    fn request(&mut self, chain: &Chain, memory: &mut GuestMemory) -> Result<u32, BadChain> {
+        let readable = chain.read_all(memory).ok_or(BadChain)?;
+        let writable = chain.writable_len();
+        if readable.len() < HEADER_SIZE || writable == 0 {
+            return Err(BadChain);
+        }
+        let kind = u32::from_le_bytes(readable[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(readable[8..16].try_into().unwrap());
+        let data_length = writable - 1; // the status byte comes last
+
+        let (status, written) = match kind {
+            VIRTIO_BLK_T_IN if self.in_range(sector, data_length) => {
+                let mut data = vec![0; data_length];
+                match self.read_sectors(sector, &mut data) {
+                    Ok(()) => {
+                        chain.write_at(memory, 0, &data).ok_or(BadChain)?;
+                        (VIRTIO_BLK_S_OK, data_length)
+                    }
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_OUT if self.in_range(sector, readable.len() - HEADER_SIZE) => {
+                match self.write_sectors(sector, &readable[HEADER_SIZE..]) {
+                    Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => (VIRTIO_BLK_S_IOERR, 0),
+            VIRTIO_BLK_T_FLUSH => match self.mode {
+                DiskMode::ReadWrite if self.image.sync_data().is_err() => (VIRTIO_BLK_S_IOERR, 0),
+                _ => (VIRTIO_BLK_S_OK, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                // NUL padded unless it takes all 20 bytes
+                let mut id = self.id.clone();
+                id.resize(ID_SIZE.min(data_length), 0);
+                chain.write_at(memory, 0, &id).ok_or(BadChain)?;
+                (VIRTIO_BLK_S_OK, id.len())
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        chain
+            .write_at(memory, data_length, &[status])
+            .ok_or(BadChain)?;
+        Ok(written as u32 + 1)
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        let read_only = match self.mode {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_RO,
+            _ => 0,
+        };
+        read_only | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH
+    }
+
+    // capacity in sectors, then blk_size at offset 20
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.sectors.to_le_bytes().to_vec();
+        config.resize(20, 0);
+        config.extend((SECTOR_SIZE as u32).to_le_bytes());
+        config
+    }
+
+    fn notify(
+        &mut self,
+        _queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(memory)? {
+            let written = self.request(&chain, memory)?;
+            queues[0].push(memory, chain, written).ok_or(BadChain)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use crate::vm::{
+        virtio::{
+            tests::{read_memory, vm_with_virtio, write_memory, Driver, BUFFERS},
+            VIRTIO_F_VERSION_1,
+        },
+        Vm,
+    };
+
+    use super::*;
+
+    const STATUS: u64 = BUFFERS + 0x1000;
+
+    // An image whose sector n is filled with byte n
+    fn image(name: &str) -> String {
+        let path = env::temp_dir().join(format!("riscv_vm-{}-{}.img", process::id(), name));
+        let data: Vec<u8> = (0..8u8).flat_map(|sector| [sector; SECTOR_SIZE]).collect();
+        fs::write(&path, data).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn header(kind: u32, sector: u64) -> Vec<u8> {
+        let mut header = kind.to_le_bytes().to_vec();
+        header.extend(0u32.to_le_bytes());
+        header.extend(sector.to_le_bytes());
+        header
+    }
+
+    // Runs one request with `data` to write or `read` bytes to read, returns
+    // the status and the bytes read
+    fn request(
+        vm: &mut Vm,
+        driver: &mut Driver,
+        kind: u32,
+        sector: u64,
+        data: &[u8],
+        read: u32,
+    ) -> (u8, Vec<u8>) {
+        let mut request = header(kind, sector);
+        request.extend(data);
+        write_memory(vm, BUFFERS, &request);
+        let mut buffers = vec![(BUFFERS, request.len() as u32, false)];
+        if read > 0 {
+            buffers.push((BUFFERS + 0x800, read, true));
+        }
+        buffers.push((STATUS, 1, true));
+        driver.submit(vm, 0, &buffers);
+        let (_, length) = driver.used(vm, 0).unwrap();
+        assert!(length <= read + 1);
+        (
+            read_memory(vm, STATUS, 1)[0],
+            read_memory(vm, BUFFERS + 0x800, read as usize),
+        )
+    }
+
+    #[test]
+    fn test_read_write_and_flush() {
+        let path = image("rw");
+        let mut vm = vm_with_virtio(Box::new(
+            VirtioBlock::open(&path, DiskMode::ReadWrite).unwrap(),
+        ));
+        assert_eq!(vm.load(4, 0x1000_1100), Ok(8)); // capacity
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH);
+
+        let (status, data) = request(&mut vm, &mut driver, VIRTIO_BLK_T_IN, 2, &[], 1024);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(data[..512], [2; 512]);
+        assert_eq!(data[512..], [3; 512]);
+
+        let (status, _) = request(&mut vm, &mut driver, VIRTIO_BLK_T_OUT, 7, &[0xAA; 512], 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let (status, _) = request(&mut vm, &mut driver, VIRTIO_BLK_T_FLUSH, 0, &[], 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(fs::read(&path).unwrap()[7 * 512..], [0xAA; 512]);
+
+        // past the end of the disk
+        let (status, _) = request(&mut vm, &mut driver, VIRTIO_BLK_T_IN, 7, &[], 1024);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let (status, data) = request(&mut vm, &mut driver, VIRTIO_BLK_T_GET_ID, 0, &[], 20);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let mut id = format!("riscv_vm-{}-rw.img", process::id()).into_bytes();
+        id.resize(ID_SIZE, 0);
+        assert_eq!(data, id);
+        let (status, _) = request(&mut vm, &mut driver, 99, 0, &[], 0);
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_and_overlay() {
+        let path = image("overlay");
+        let mut vm = vm_with_virtio(Box::new(
+            VirtioBlock::open(&path, DiskMode::ReadOnly).unwrap(),
+        ));
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO);
+        let (status, _) = request(&mut vm, &mut driver, VIRTIO_BLK_T_OUT, 1, &[0xAA; 512], 0);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let mut vm = vm_with_virtio(Box::new(
+            VirtioBlock::open(&path, DiskMode::Overlay).unwrap(),
+        ));
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1);
+        let (status, _) = request(&mut vm, &mut driver, VIRTIO_BLK_T_OUT, 1, &[0xAA; 512], 0);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let (_, data) = request(&mut vm, &mut driver, VIRTIO_BLK_T_IN, 0, &[], 1536);
+        assert_eq!(data[..512], [0; 512]);
+        assert_eq!(data[512..1024], [0xAA; 512]);
+        assert_eq!(data[1024..], [2; 512]);
+        // the image itself is untouched
+        assert_eq!(fs::read(&path).unwrap()[512..1024], [1; 512]);
+        fs::remove_file(path).unwrap();
+    }
+}
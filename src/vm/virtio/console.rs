@@ -0,0 +1,319 @@
+// virtio-console with multiple ports. Every port is backed by a Serial like
+// the UART; port 0 is the console, the others show up in the guest as
+// /dev/vportNpM with their names.
+use std::collections::VecDeque;
+
+use super::{
+    super::uart::Serial,
+    queue::{BadChain, GuestMemory, Virtqueue},
+    VirtioDevice,
+};
+
+const DEVICE_ID: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// queues 2 and 3 carry control messages, the ports have a receive and a
+// transmit queue each around them
+const CONTROL_RECEIVE: usize = 2;
+const CONTROL_TRANSMIT: usize = 3;
+
+// control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+const CONTROL_SIZE: usize = 8; // id u32, event u16, value u16
+const EMERG_WR: u64 = 8; // config offset
+
+// input read ahead of the guest per port, the backend is not read past it
+const INPUT_LIMIT: usize = 4096;
+
+struct Port {
+    name: String,
+    serial: Box<dyn Serial>,
+    input: VecDeque<u8>,
+    open: bool, // the guest has the port open
+}
+
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    multiport: bool,
+    control: VecDeque<Vec<u8>>, // messages waiting for a control receive buffer
+}
+
+fn receive_queue(port: usize) -> usize {
+    match port {
+        0 => 0,
+        _ => 2 * port + 2,
+    }
+}
+
+// port of a receive or transmit queue
+fn queue_port(queue: usize) -> usize {
+    match queue {
+        0 | 1 => 0,
+        _ => (queue - 2) / 2,
+    }
+}
+
+fn control(id: usize, event: u16, value: u16) -> Vec<u8> {
+    let mut message = (id as u32).to_le_bytes().to_vec();
+    message.extend(event.to_le_bytes());
+    message.extend(value.to_le_bytes());
+    message
+}
+
+impl VirtioConsole {
+    // A device with `console` as port 0
+    pub fn new(console: Box<dyn Serial>) -> Self {
+        let mut device = Self {
+            ports: vec![],
+            multiport: false,
+            control: VecDeque::new(),
+        };
+        device.add_port("", console);
+        device
+    }
+
+    // Adds a named port, before the device is attached
+    pub fn add_port(&mut self, name: &str, serial: Box<dyn Serial>) {
+        self.ports.push(Port {
+            name: name.to_string(),
+            serial,
+            input: VecDeque::new(),
+            open: false,
+        });
+    }
+
+    fn control_message(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        let value = u16::from_le_bytes([message[6], message[7]]);
+        match event {
+            DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.control.push_back(control(id, DEVICE_ADD, 0));
+                }
+            }
+            PORT_READY if value == 1 && id < self.ports.len() => {
+                if id == 0 {
+                    self.control.push_back(control(id, CONSOLE_PORT, 1));
+                }
+                if !self.ports[id].name.is_empty() {
+                    let mut message = control(id, PORT_NAME, 0);
+                    message.extend(self.ports[id].name.as_bytes());
+                    self.control.push_back(message);
+                }
+                // the host end is always connected
+                self.control.push_back(control(id, PORT_OPEN, 1));
+            }
+            PORT_OPEN if id < self.ports.len() => self.ports[id].open = value == 1,
+            _ => {}
+        }
+    }
+
+    // Hands waiting control messages and input to the receive buffers the
+    // driver has offered
+    fn deliver(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        while !self.control.is_empty() && self.multiport {
+            let Some(chain) = queues[CONTROL_RECEIVE].pop(memory)? else {
+                break;
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write_at(memory, 0, &message).ok_or(BadChain)?;
+            queues[CONTROL_RECEIVE]
+                .push(memory, chain, written as u32)
+                .ok_or(BadChain)?;
+            used = true;
+        }
+        for (id, port) in self.ports.iter_mut().enumerate() {
+            // without multiport only the console exists, and is always open
+            let open = match self.multiport {
+                true => port.open,
+                false => id == 0,
+            };
+            let queue = &mut queues[receive_queue(id)];
+            while open && !port.input.is_empty() {
+                let Some(chain) = queue.pop(memory)? else {
+                    break;
+                };
+                let count = chain.writable_len().min(port.input.len());
+                let bytes: Vec<u8> = port.input.drain(..count).collect();
+                let written = chain.write_at(memory, 0, &bytes).ok_or(BadChain)?;
+                queue.push(memory, chain, written as u32).ok_or(BadChain)?;
+                used = true;
+            }
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        2 * self.ports.len() + 2
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    // cols and rows (no VIRTIO_CONSOLE_F_SIZE), max_nr_ports, emerg_wr
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend((self.ports.len() as u32).to_le_bytes());
+        config.extend([0; 4]);
+        config
+    }
+
+    fn write_config(&mut self, offset: u64, bytes: &[u8]) {
+        if offset == EMERG_WR {
+            self.ports[0].serial.write_byte(bytes[0]);
+        }
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        if queue == CONTROL_TRANSMIT || queue % 2 == 1 {
+            while let Some(chain) = queues[queue].pop(memory)? {
+                let bytes = chain.read_all(memory).ok_or(BadChain)?;
+                match queue {
+                    CONTROL_TRANSMIT => self.control_message(&bytes),
+                    _ => {
+                        let serial = &mut self.ports[queue_port(queue)].serial;
+                        bytes.into_iter().for_each(|byte| serial.write_byte(byte));
+                    }
+                }
+                queues[queue].push(memory, chain, 0).ok_or(BadChain)?;
+                used = true;
+            }
+        }
+        Ok(self.deliver(queues, memory)? || used)
+    }
+
+    fn poll(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        for port in &mut self.ports {
+            while port.input.len() < INPUT_LIMIT {
+                match port.serial.read_byte() {
+                    Some(byte) => port.input.push_back(byte),
+                    None => break,
+                }
+            }
+        }
+        self.deliver(queues, memory)
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        for port in &mut self.ports {
+            port.open = false;
+            port.input.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{
+        uart::BufferSerial,
+        virtio::{
+            tests::{read_memory, vm_with_virtio, write_memory, Driver, BUFFERS},
+            VIRTIO_F_VERSION_1,
+        },
+        Vm,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_multiport_console() {
+        let (console, log) = (BufferSerial::new(), BufferSerial::new());
+        let mut device = VirtioConsole::new(Box::new(console.clone()));
+        device.add_port("log", Box::new(log.clone()));
+        let mut vm = vm_with_virtio(Box::new(device));
+        assert_eq!(vm.load(4, 0x1000_1104), Ok(2)); // max_nr_ports
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_MULTIPORT);
+
+        // receive buffers for control messages, then the handshake
+        for i in 0..6 {
+            driver.offer(
+                &mut vm,
+                CONTROL_RECEIVE,
+                &[(BUFFERS + 0x100 * i, 0x40, true)],
+            );
+        }
+        let mut send = |vm: &mut Vm, message: Vec<u8>| {
+            write_memory(vm, BUFFERS + 0x1000, &message);
+            driver.submit(vm, CONTROL_TRANSMIT, &[(BUFFERS + 0x1000, 8, false)]);
+        };
+        send(&mut vm, control(0, DEVICE_READY, 1));
+        send(&mut vm, control(1, PORT_READY, 1));
+        send(&mut vm, control(1, PORT_OPEN, 1));
+        let messages: Vec<Vec<u8>> = (0..4)
+            .map(|i| {
+                let (_, length) = driver.used(&mut vm, CONTROL_RECEIVE).unwrap();
+                read_memory(&vm, BUFFERS + 0x100 * i, length as usize)
+            })
+            .collect();
+        let mut name = control(1, PORT_NAME, 0);
+        name.extend(b"log");
+        assert_eq!(
+            messages,
+            [
+                control(0, DEVICE_ADD, 0),
+                control(1, DEVICE_ADD, 0),
+                name,
+                control(1, PORT_OPEN, 1),
+            ]
+        );
+
+        // transmit on port 1, receive on port 1 but not on the closed console
+        write_memory(&mut vm, BUFFERS + 0x2000, b"to the log");
+        driver.submit(&mut vm, 5, &[(BUFFERS + 0x2000, 10, false)]);
+        assert_eq!(log.take_output(), b"to the log");
+        assert_eq!(console.take_output(), b"");
+
+        log.push_input(b"input");
+        console.push_input(b"ignored");
+        driver.submit(&mut vm, 0, &[(BUFFERS + 0x3000, 0x10, true)]);
+        driver.submit(&mut vm, 4, &[(BUFFERS + 0x4000, 0x10, true)]);
+        vm.tick_virtio();
+        assert_eq!(driver.used(&mut vm, 0), None);
+        assert_eq!(driver.used(&mut vm, 4), Some((0, 5)));
+        assert_eq!(read_memory(&vm, BUFFERS + 0x4000, 5), b"input");
+
+        // emergency writes go to the console
+        vm.store(1, 0x1000_1108, b'!' as u32).unwrap();
+        assert_eq!(console.take_output(), b"!");
+    }
+}
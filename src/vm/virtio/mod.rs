@@ -0,0 +1,628 @@
+// virtio 1.x over MMIO (the version 2 register layout). The transport does
+// feature negotiation, device status and queue setup, the device behind it
+// only sees notifications and the chains on its queues.
+use super::{
+    bus::{MapError, Mmio, Region, RegionKind},
+    Vm,
+};
+
+pub(crate) mod block;
+
+pub(crate) mod console;
+
+pub(crate) mod net;
+
+#[cfg(unix)]
+pub(crate) mod p9;
+
+pub(crate) mod queue;
+
+pub(crate) mod rng;
+
+use queue::{BadChain, GuestMemory, Virtqueue, QUEUE_SIZE};
+
+// QEMU virt: eight transports from 0x1000_1000 on interrupt sources 1 - 8
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_SLOTS: usize = 8;
+const VIRTIO_FIRST_SOURCE: usize = 1;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x4D56_5352; // "RSVM"
+
+// registers
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID_REG: u64 = 0x00C;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0A0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0A4;
+const CONFIG_GENERATION: u64 = 0x0FC;
+const CONFIG: u64 = 0x100;
+
+// device status
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_NEEDS_RESET: u32 = 0x40;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// A device type on the transport
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+    fn queue_count(&self) -> usize;
+
+    // device specific feature bits, the transport adds VIRTIO_F_VERSION_1
+    fn features(&self) -> u64 {
+        0
+    }
+
+    // the device configuration space, little endian
+    fn config(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn write_config(&mut self, _offset: u64, _bytes: &[u8]) {}
+
+    // The driver offered buffers on `queue`. True when chains were used and
+    // the driver should be interrupted.
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain>;
+
+    // Called every step while the driver is running, for devices with input
+    // of their own
+    fn poll(
+        &mut self,
+        _queues: &mut [Virtqueue],
+        _memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        Ok(false)
+    }
+
+    // the driver accepted `features` and set DRIVER_OK
+    fn activate(&mut self, _features: u64) {}
+
+    fn reset(&mut self) {}
+}
+
+pub(crate) struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    pub(crate) source: usize,
+    queues: Vec<Virtqueue>,
+    queue_sel: usize,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+}
+
+impl VirtioMmio {
+    fn new(device: Box<dyn VirtioDevice>, source: usize) -> Self {
+        let queues = (0..device.queue_count())
+            .map(|_| Virtqueue::default())
+            .collect();
+        Self {
+            device,
+            source,
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.queues.iter_mut().for_each(Virtqueue::reset);
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.device.reset();
+    }
+
+    fn running(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0 && self.status & STATUS_NEEDS_RESET == 0
+    }
+
+    // What the device did with its queues, a broken chain needs a reset
+    fn completed(&mut self, result: Result<bool, BadChain>) {
+        match result {
+            Ok(true) => self.interrupt_status |= INTERRUPT_USED_BUFFER,
+            Ok(false) => {}
+            Err(BadChain) => {
+                self.status |= STATUS_NEEDS_RESET;
+                self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            }
+        }
+    }
+
+    pub(crate) fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    pub(crate) fn read(&mut self, offset: u64, size: usize) -> Option<u32> {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let bytes = config.get(start..start + size)?;
+            return Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| value << 8 | *byte as u32),
+            );
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let queue = self.queues.get(self.queue_sel);
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE as u32),
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        Some(value)
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        offset: u64,
+        size: usize,
+        value: u32,
+        memory: &mut GuestMemory,
+    ) -> Option<()> {
+        if offset >= CONFIG {
+            let bytes = value.to_le_bytes();
+            self.device.write_config(offset - CONFIG, &bytes[..size]);
+            return Some(());
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        // queue layout is fixed once the queue is ready
+        let queue = self
+            .queues
+            .get_mut(self.queue_sel)
+            .filter(|queue| !queue.ready);
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            DRIVER_FEATURES if self.status & STATUS_FEATURES_OK == 0 => {
+                match self.driver_features_sel {
+                    0 => self.driver_features = self.driver_features & !0xFFFF_FFFF | value as u64,
+                    1 => {
+                        self.driver_features =
+                            self.driver_features & 0xFFFF_FFFF | (value as u64) << 32
+                    }
+                    _ => {}
+                }
+            }
+            QUEUE_SEL => self.queue_sel = value as usize,
+            QUEUE_NUM => {
+                if let Some(queue) = queue {
+                    queue.size = value.min(QUEUE_SIZE as u32) as u16;
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queues.get_mut(self.queue_sel) {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+            | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = queue {
+                    let field = match offset & !0x4 {
+                        QUEUE_DESC_LOW => &mut queue.desc,
+                        QUEUE_DRIVER_LOW => &mut queue.avail,
+                        _ => &mut queue.used,
+                    };
+                    *field = if offset & 0x4 == 0 {
+                        *field & !0xFFFF_FFFF | value as u64
+                    } else {
+                        *field & 0xFFFF_FFFF | (value as u64) << 32
+                    };
+                }
+            }
+            QUEUE_NOTIFY => {
+                let index = value as usize;
+                if self.running() && index < self.queues.len() {
+                    let result = self.device.notify(index, &mut self.queues, memory);
+                    self.completed(result);
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => self.write_status(value),
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn write_status(&mut self, value: u32) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+        let mut value = value;
+        // features are only accepted if they are ones we offered, version 1
+        // included
+        if value & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+            let offered = self.features();
+            let features = self.driver_features;
+            if features & !offered != 0 || features & VIRTIO_F_VERSION_1 == 0 {
+                value &= !STATUS_FEATURES_OK;
+            }
+        }
+        if value & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0 {
+            self.device.activate(self.driver_features);
+        }
+        self.status = value | self.status & STATUS_NEEDS_RESET;
+    }
+
+    fn poll(&mut self, memory: &mut GuestMemory) {
+        if self.running() {
+            let result = self.device.poll(&mut self.queues, memory);
+            self.completed(result);
+        }
+    }
+}
+
+impl Vm {
+    // Maps a virtio-mmio transport for `device` at base..base + VIRTIO_SIZE
+    pub fn attach_virtio(
+        &mut self,
+        name: &str,
+        base: u64,
+        device: Box<dyn VirtioDevice>,
+        source: usize,
+    ) -> Result<(), MapError> {
+        if source == 0 || source > self.plic.sources() {
+            return Err(MapError::Source(name.to_string()));
+        }
+        let index = self.virtio.len();
+        let region = Region::new(
+            name,
+            base,
+            VIRTIO_SIZE,
+            RegionKind::Mmio(Mmio::Virtio(index)),
+        );
+        self.bus.add(region)?;
+        self.virtio.push(VirtioMmio::new(device, source));
+        Ok(())
+    }
+
+    // The next free QEMU virt virtio slot
+    pub fn add_virtio(
+        &mut self,
+        name: &str,
+        device: Box<dyn VirtioDevice>,
+    ) -> Result<(), MapError> {
+        let slot = self.virtio.len();
+        if slot >= VIRTIO_SLOTS {
+            return Err(MapError::VirtioSlots(name.to_string()));
+        }
+        let base = VIRTIO_BASE + slot as u64 * VIRTIO_SIZE;
+        self.attach_virtio(name, base, device, VIRTIO_FIRST_SOURCE + slot)
+    }
+
+    pub(crate) fn virtio_read(&mut self, index: usize, offset: u64, size: usize) -> Option<u32> {
+        self.virtio[index].read(offset, size)
+    }
+
+    pub(crate) fn virtio_write(
+        &mut self,
+        index: usize,
+        offset: u64,
+        size: usize,
+        value: u32,
+    ) -> Option<()> {
+        let mut memory = GuestMemory::new(&mut self.bus);
+        self.virtio[index].write(offset, size, value, &mut memory)
+    }
+
+    pub(crate) fn tick_virtio(&mut self) {
+        for index in 0..self.virtio.len() {
+            let transport = &mut self.virtio[index];
+            transport.poll(&mut GuestMemory::new(&mut self.bus));
+            let (source, level) = (transport.source, transport.interrupt());
+            self.set_interrupt_line(source, level);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::vm::{
+        bus::{Bus, DRAM_BASE},
+        registers::BaseIsa,
+        tests::allow_all_memory,
+        Vm, WORD_SIZE,
+    };
+
+    use super::*;
+
+    const STATUS_ACKNOWLEDGE: u32 = 1;
+    const STATUS_DRIVER: u32 = 2;
+
+    // Where a test driver keeps its queues, buffers go after them
+    const QUEUE_AREA: u64 = DRAM_BASE;
+    const QUEUE_STRIDE: u64 = 0x3000;
+    pub(crate) const BUFFERS: u64 = DRAM_BASE + 0x2_0000;
+    const TEST_QUEUE_SIZE: u16 = 16;
+
+    pub(crate) fn vm_with_virtio(device: Box<dyn VirtioDevice>) -> Vm {
+        let mut vm = Vm::with_bus(BaseIsa::Rv32i, Bus::virt(0x10_0000).unwrap());
+        allow_all_memory(&mut vm);
+        vm.add_virtio("virtio", device).unwrap();
+        vm
+    }
+
+    fn reg(offset: u64) -> u32 {
+        (VIRTIO_BASE + offset) as u32
+    }
+
+    pub(crate) fn mmio_write(vm: &mut Vm, offset: u64, value: u32) {
+        vm.store(WORD_SIZE, reg(offset), value).unwrap();
+    }
+
+    pub(crate) fn mmio_read(vm: &mut Vm, offset: u64) -> u32 {
+        vm.load(WORD_SIZE, reg(offset)).unwrap()
+    }
+
+    pub(crate) fn write_memory(vm: &mut Vm, address: u64, bytes: &[u8]) {
+        vm.bus.write(address, bytes).unwrap();
+    }
+
+    pub(crate) fn read_memory(vm: &Vm, address: u64, length: usize) -> Vec<u8> {
+        vm.bus.read(address, length).unwrap().to_vec()
+    }
+
+    // Driver side of the queues, the way a guest driver sets them up
+    pub(crate) struct Driver {
+        next_desc: Vec<u16>,
+        avail_idx: Vec<u16>,
+        used_idx: Vec<u16>,
+    }
+
+    impl Driver {
+        // Initialization as in section 3.1.1 of the spec, accepting `features`
+        pub(crate) fn new(vm: &mut Vm, features: u64) -> Self {
+            mmio_write(vm, STATUS, 0);
+            mmio_write(vm, STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+            for select in 0..2 {
+                mmio_write(vm, DRIVER_FEATURES_SEL, select);
+                mmio_write(vm, DRIVER_FEATURES, (features >> (32 * select)) as u32);
+            }
+            mmio_write(
+                vm,
+                STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK,
+            );
+            assert_ne!(mmio_read(vm, STATUS) & STATUS_FEATURES_OK, 0);
+
+            let queues = vm.virtio[0].queues.len();
+            for queue in 0..queues {
+                let base = QUEUE_AREA + queue as u64 * QUEUE_STRIDE;
+                mmio_write(vm, QUEUE_SEL, queue as u32);
+                mmio_write(vm, QUEUE_NUM, TEST_QUEUE_SIZE as u32);
+                mmio_write(vm, QUEUE_DESC_LOW, base as u32);
+                mmio_write(vm, QUEUE_DRIVER_LOW, (base + 0x1000) as u32);
+                mmio_write(vm, QUEUE_DEVICE_LOW, (base + 0x2000) as u32);
+                mmio_write(vm, QUEUE_READY, 1);
+            }
+            mmio_write(
+                vm,
+                STATUS,
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK,
+            );
+            Self {
+                next_desc: vec![0; queues],
+                avail_idx: vec![0; queues],
+                used_idx: vec![0; queues],
+            }
+        }
+
+        // Offers a chain of (address, length, device writable) buffers without
+        // notifying, returns its head
+        pub(crate) fn offer(
+            &mut self,
+            vm: &mut Vm,
+            queue: usize,
+            buffers: &[(u64, u32, bool)],
+        ) -> u16 {
+            let base = QUEUE_AREA + queue as u64 * QUEUE_STRIDE;
+            let head = self.next_desc[queue];
+            for (i, (address, length, writable)) in buffers.iter().enumerate() {
+                let index = (head + i as u16) % TEST_QUEUE_SIZE;
+                let mut flags = if *writable { 2 } else { 0 };
+                if i + 1 < buffers.len() {
+                    flags |= 1;
+                }
+                let mut desc = address.to_le_bytes().to_vec();
+                desc.extend(length.to_le_bytes());
+                desc.extend((flags as u16).to_le_bytes());
+                desc.extend(((index + 1) % TEST_QUEUE_SIZE).to_le_bytes());
+                write_memory(vm, base + index as u64 * 16, &desc);
+            }
+            self.next_desc[queue] = (head + buffers.len() as u16) % TEST_QUEUE_SIZE;
+
+            let avail = base + 0x1000;
+            let slot = self.avail_idx[queue] % TEST_QUEUE_SIZE;
+            write_memory(vm, avail + 4 + slot as u64 * 2, &head.to_le_bytes());
+            self.avail_idx[queue] = self.avail_idx[queue].wrapping_add(1);
+            write_memory(vm, avail + 2, &self.avail_idx[queue].to_le_bytes());
+            head
+        }
+
+        pub(crate) fn submit(
+            &mut self,
+            vm: &mut Vm,
+            queue: usize,
+            buffers: &[(u64, u32, bool)],
+        ) -> u16 {
+            let head = self.offer(vm, queue, buffers);
+            mmio_write(vm, QUEUE_NOTIFY, queue as u32);
+            head
+        }
+
+        // Next used (head, length) of the queue
+        pub(crate) fn used(&mut self, vm: &mut Vm, queue: usize) -> Option<(u16, u32)> {
+            let used = QUEUE_AREA + queue as u64 * QUEUE_STRIDE + 0x2000;
+            let idx = u16::from_le_bytes(read_memory(vm, used + 2, 2).try_into().unwrap());
+            if idx == self.used_idx[queue] {
+                return None;
+            }
+            let slot = self.used_idx[queue] % TEST_QUEUE_SIZE;
+            let entry = read_memory(vm, used + 4 + slot as u64 * 8, 8);
+            self.used_idx[queue] = self.used_idx[queue].wrapping_add(1);
+            let head = u32::from_le_bytes(entry[..4].try_into().unwrap()) as u16;
+            let length = u32::from_le_bytes(entry[4..].try_into().unwrap());
+            Some((head, length))
+        }
+
+        pub(crate) fn acknowledge(&mut self, vm: &mut Vm) -> u32 {
+            let status = mmio_read(vm, INTERRUPT_STATUS);
+            mmio_write(vm, INTERRUPT_ACK, status);
+            status
+        }
+    }
+
+    // Echoes what it reads on queue 0 into the writable part of the chain
+    struct Echo;
+
+    impl VirtioDevice for Echo {
+        fn device_id(&self) -> u32 {
+            0x7E
+        }
+
+        fn queue_count(&self) -> usize {
+            1
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![0x11, 0x22, 0x33, 0x44]
+        }
+
+        fn notify(
+            &mut self,
+            _queue: usize,
+            queues: &mut [Virtqueue],
+            memory: &mut GuestMemory,
+        ) -> Result<bool, BadChain> {
+            let mut used = false;
+            while let Some(chain) = queues[0].pop(memory)? {
+                let bytes = chain.read_all(memory).ok_or(BadChain)?;
+                let written = chain.write_at(memory, 0, &bytes).ok_or(BadChain)?;
+                queues[0]
+                    .push(memory, chain, written as u32)
+                    .ok_or(BadChain)?;
+                used = true;
+            }
+            Ok(used)
+        }
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut vm = vm_with_virtio(Box::new(Echo));
+        assert_eq!(mmio_read(&mut vm, MAGIC_VALUE), MAGIC);
+        assert_eq!(mmio_read(&mut vm, VERSION_REG), 2);
+        assert_eq!(mmio_read(&mut vm, DEVICE_ID), 0x7E);
+        mmio_write(&mut vm, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(mmio_read(&mut vm, DEVICE_FEATURES), 1);
+        assert_eq!(vm.load(2, reg(CONFIG + 2)), Ok(0x4433));
+        assert_eq!(mmio_read(&mut vm, QUEUE_NUM_MAX), QUEUE_SIZE as u32);
+
+        // features without VERSION_1 are refused
+        mmio_write(&mut vm, DRIVER_FEATURES, 1 << 3);
+        mmio_write(&mut vm, STATUS, STATUS_FEATURES_OK);
+        assert_eq!(mmio_read(&mut vm, STATUS), 0);
+
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1 | 1 << 3);
+        write_memory(&mut vm, BUFFERS, b"ping");
+        let head = driver.submit(
+            &mut vm,
+            0,
+            &[
+                (BUFFERS, 2, false),
+                (BUFFERS + 2, 2, false),
+                (BUFFERS + 0x100, 8, true),
+            ],
+        );
+        assert_eq!(driver.used(&mut vm, 0), Some((head, 4)));
+        assert_eq!(read_memory(&vm, BUFFERS + 0x100, 4), b"ping");
+
+        // the interrupt line stays up until acknowledged
+        vm.tick_virtio();
+        vm.update_interrupts();
+        assert!(vm.plic.read(0x1000, 4).unwrap() & 1 << VIRTIO_FIRST_SOURCE != 0);
+        assert_eq!(driver.acknowledge(&mut vm), INTERRUPT_USED_BUFFER);
+        assert!(!vm.virtio[0].interrupt());
+
+        // a chain pointing outside RAM breaks the device until reset
+        driver.submit(&mut vm, 0, &[(0x4000_0000, 4, false)]);
+        assert_ne!(mmio_read(&mut vm, STATUS) & STATUS_NEEDS_RESET, 0);
+        assert_eq!(driver.acknowledge(&mut vm), INTERRUPT_CONFIG_CHANGE);
+        mmio_write(&mut vm, STATUS, 0);
+        assert_eq!(mmio_read(&mut vm, STATUS), 0);
+        assert_eq!(mmio_read(&mut vm, QUEUE_READY), 0);
+
+        // slot 0 is taken, the other seven fill up
+        for _ in 1..VIRTIO_SLOTS {
+            vm.add_virtio("echo", Box::new(Echo)).unwrap();
+        }
+        assert_eq!(
+            vm.add_virtio("echo", Box::new(Echo)),
+            Err(MapError::VirtioSlots("echo".to_string()))
+        );
+    }
+}
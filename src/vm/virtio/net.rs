@@ -0,0 +1,397 @@
+// virtio-net with in-process backends: a link to another VM in the same
+// process, or pcap files to replay frames from and capture frames to. No
+// tap devices or privileges needed.
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    queue::{BadChain, GuestMemory, Virtqueue},
+    VirtioDevice,
+};
+
+const DEVICE_ID: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RECEIVE: usize = 0;
+const TRANSMIT: usize = 1;
+
+// virtio_net_hdr, every frame is preceded by one in both directions
+const HEADER_SIZE: usize = 12;
+
+// QEMU's default, 52:54:00 is its locally administered prefix
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+// Where the frames go to and come from
+pub trait NetBackend {
+    fn receive(&mut self) -> Option<Vec<u8>>; // never blocks
+    fn send(&mut self, frame: &[u8]);
+}
+
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    pending: Option<Vec<u8>>, // received, waiting for a buffer
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            pending: None,
+        }
+    }
+
+    fn transmit(
+        &mut self,
+        queue: &mut Virtqueue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory)? {
+            let bytes = chain.read_all(memory).ok_or(BadChain)?;
+            if bytes.len() > HEADER_SIZE {
+                self.backend.send(&bytes[HEADER_SIZE..]);
+            }
+            queue.push(memory, chain, 0).ok_or(BadChain)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn receive(
+        &mut self,
+        queue: &mut Virtqueue,
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.backend.receive();
+            }
+            let Some(frame) = &self.pending else {
+                return Ok(used);
+            };
+            let Some(chain) = queue.pop(memory)? else {
+                return Ok(used);
+            };
+            // no checksum offload or GSO, num_buffers is always 1
+            let mut bytes = vec![0; HEADER_SIZE];
+            bytes[10] = 1;
+            bytes.extend(frame);
+            self.pending = None;
+            // a frame too large for the buffer is dropped
+            let written = match bytes.len() <= chain.writable_len() {
+                true => chain.write_at(memory, 0, &bytes).ok_or(BadChain)?,
+                false => 0,
+            };
+            queue.push(memory, chain, written as u32).ok_or(BadChain)?;
+            used = true;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    // mac, then status
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend(VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let (receive, transmit) = queues.split_at_mut(TRANSMIT);
+        match queue {
+            TRANSMIT => self.transmit(&mut transmit[0], memory),
+            _ => self.receive(&mut receive[RECEIVE], memory),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        self.receive(&mut queues[RECEIVE], memory)
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+// frames in flight on a link, more are dropped like on a congested wire
+const LINK_FRAMES: usize = 256;
+
+type Wire = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+// One end of a point to point link between two VMs of this process
+pub struct LinkEndpoint {
+    inbox: Wire,
+    peer: Wire,
+}
+
+impl LinkEndpoint {
+    pub fn pair() -> (Self, Self) {
+        let (a, b) = (Wire::default(), Wire::default());
+        (
+            Self {
+                inbox: a.clone(),
+                peer: b.clone(),
+            },
+            Self { inbox: b, peer: a },
+        )
+    }
+}
+
+impl NetBackend for LinkEndpoint {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.inbox.borrow_mut().pop_front()
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let mut peer = self.peer.borrow_mut();
+        if peer.len() < LINK_FRAMES {
+            peer.push_back(frame.to_vec());
+        }
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xA1B2_3C4D;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 65535;
+
+// Replays the frames of one pcap file to the guest in order and captures
+// what the guest sends to another, either may be left out
+pub struct PcapBackend {
+    replay: Option<(BufReader<File>, bool)>, // file, big endian
+    capture: Option<BufWriter<File>>,
+}
+
+impl PcapBackend {
+    pub fn new(replay: Option<&str>, capture: Option<&str>) -> io::Result<Self> {
+        let replay = match replay {
+            Some(path) => {
+                let mut file = BufReader::new(File::open(path)?);
+                let mut header = [0; 24];
+                file.read_exact(&mut header)?;
+                let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                let big_endian = match magic {
+                    PCAP_MAGIC | PCAP_MAGIC_NANOSECONDS => false,
+                    _ if [PCAP_MAGIC, PCAP_MAGIC_NANOSECONDS].contains(&magic.swap_bytes()) => true,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "not a pcap file",
+                        ))
+                    }
+                };
+                let network = u32::from_le_bytes(header[20..24].try_into().unwrap());
+                let network = if big_endian {
+                    network.swap_bytes()
+                } else {
+                    network
+                };
+                if network != LINKTYPE_ETHERNET {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "not an Ethernet capture",
+                    ));
+                }
+                Some((file, big_endian))
+            }
+            None => None,
+        };
+        let capture = match capture {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                let mut header = PCAP_MAGIC.to_le_bytes().to_vec();
+                header.extend(2u16.to_le_bytes()); // version 2.4
+                header.extend(4u16.to_le_bytes());
+                header.extend([0; 8]); // thiszone, sigfigs
+                header.extend(SNAPLEN.to_le_bytes());
+                header.extend(LINKTYPE_ETHERNET.to_le_bytes());
+                file.write_all(&header)?;
+                file.flush()?;
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Self { replay, capture })
+    }
+
+    fn next_frame(&mut self) -> io::Result<Vec<u8>> {
+        let (file, big_endian) = self.replay.as_mut().ok_or(io::ErrorKind::NotFound)?;
+        let mut record = [0; 16];
+        file.read_exact(&mut record)?;
+        let length = u32::from_le_bytes(record[8..12].try_into().unwrap());
+        let length = if *big_endian {
+            length.swap_bytes()
+        } else {
+            length
+        };
+        let mut frame = vec![0; length.min(SNAPLEN) as usize];
+        file.read_exact(&mut frame)?;
+        // the rest of an oversized record is dropped, the next header follows it
+        let rest = (length - frame.len() as u32) as u64;
+        if io::copy(&mut file.by_ref().take(rest), &mut io::sink())? != rest {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(frame)
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        // the replay ends at the end of the file or at a broken record
+        match self.next_frame() {
+            Ok(frame) => Some(frame),
+            Err(_) => {
+                self.replay = None;
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let Some(file) = &mut self.capture else {
+            return;
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = (time.as_secs() as u32).to_le_bytes().to_vec();
+        record.extend(time.subsec_micros().to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend((frame.len() as u32).to_le_bytes());
+        record.extend(frame);
+        // a capture that can not be written stops
+        if file.write_all(&record).and_then(|_| file.flush()).is_err() {
+            self.capture = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use crate::vm::{
+        virtio::{
+            tests::{read_memory, vm_with_virtio, write_memory, Driver, BUFFERS},
+            VIRTIO_F_VERSION_1,
+        },
+        Vm,
+    };
+
+    use super::*;
+
+    const FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS;
+
+    fn send(vm: &mut Vm, driver: &mut Driver, frame: &[u8]) {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes.extend(frame);
+        write_memory(vm, BUFFERS, &bytes);
+        driver.submit(vm, TRANSMIT, &[(BUFFERS, bytes.len() as u32, false)]);
+        assert!(driver.used(vm, TRANSMIT).is_some());
+    }
+
+    // Offers a receive buffer and returns the frame that lands in it, if any
+    fn receive(vm: &mut Vm, driver: &mut Driver, size: u32) -> Option<Vec<u8>> {
+        driver.submit(vm, RECEIVE, &[(BUFFERS + 0x1000, size, true)]);
+        vm.tick_virtio();
+        let (_, length) = driver.used(vm, RECEIVE)?;
+        let bytes = read_memory(vm, BUFFERS + 0x1000, length as usize);
+        Some(bytes.get(HEADER_SIZE..).unwrap_or_default().to_vec())
+    }
+
+    #[test]
+    fn test_link() {
+        let (a, b) = LinkEndpoint::pair();
+        let mut vm_a = vm_with_virtio(Box::new(VirtioNet::new(DEFAULT_MAC, Box::new(a))));
+        let mut vm_b = vm_with_virtio(Box::new(VirtioNet::new(DEFAULT_MAC, Box::new(b))));
+        assert_eq!(vm_a.load(2, 0x1000_1104), Ok(0x5634)); // mac[4..6]
+        let mut driver_a = Driver::new(&mut vm_a, FEATURES);
+        let mut driver_b = Driver::new(&mut vm_b, FEATURES);
+
+        let frame: Vec<u8> = (0..60).collect();
+        send(&mut vm_a, &mut driver_a, &frame);
+        send(&mut vm_a, &mut driver_a, &[0xEE; 200]);
+        assert_eq!(receive(&mut vm_b, &mut driver_b, 0x800), Some(frame));
+        // too large for the buffer, dropped
+        assert_eq!(receive(&mut vm_b, &mut driver_b, 100), Some(vec![]));
+        assert_eq!(receive(&mut vm_b, &mut driver_b, 0x800), None);
+        assert_eq!(receive(&mut vm_a, &mut driver_a, 0x800), None);
+    }
+
+    #[test]
+    fn test_pcap_capture_and_replay() {
+        let path = env::temp_dir().join(format!("riscv_vm-{}.pcap", process::id()));
+        let path = path.to_str().unwrap();
+        let backend = PcapBackend::new(None, Some(path)).unwrap();
+        let mut vm = vm_with_virtio(Box::new(VirtioNet::new(DEFAULT_MAC, Box::new(backend))));
+        let mut driver = Driver::new(&mut vm, FEATURES);
+        send(&mut vm, &mut driver, b"first frame");
+        send(&mut vm, &mut driver, b"second frame");
+        assert_eq!(fs::read(path).unwrap().len(), 24 + 2 * 16 + 23);
+
+        let backend = PcapBackend::new(Some(path), None).unwrap();
+        let mut vm = vm_with_virtio(Box::new(VirtioNet::new(DEFAULT_MAC, Box::new(backend))));
+        let mut driver = Driver::new(&mut vm, FEATURES);
+        for frame in [&b"first frame"[..], b"second frame"] {
+            assert_eq!(receive(&mut vm, &mut driver, 0x800), Some(frame.to_vec()));
+        }
+        assert_eq!(receive(&mut vm, &mut driver, 0x800), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pcap_oversized_record() {
+        let path = env::temp_dir().join(format!("riscv_vm-{}-oversized.pcap", process::id()));
+        let mut file = PCAP_MAGIC.to_le_bytes().to_vec();
+        file.extend([2, 0, 4, 0]);
+        file.extend([0; 8]);
+        file.extend(SNAPLEN.to_le_bytes());
+        file.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        for frame in [vec![0xAB; SNAPLEN as usize + 10], b"next".to_vec()] {
+            file.extend([0; 8]);
+            file.extend((frame.len() as u32).to_le_bytes());
+            file.extend((frame.len() as u32).to_le_bytes());
+            file.extend(frame);
+        }
+        fs::write(&path, file).unwrap();
+
+        let mut backend = PcapBackend::new(path.to_str(), None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(backend.receive().unwrap().len(), SNAPLEN as usize);
+        assert_eq!(backend.receive(), Some(b"next".to_vec()));
+        assert_eq!(backend.receive(), None);
+    }
+}
@@ -0,0 +1,966 @@
+// virtio-9p, a host directory shared with the guest over 9P2000.L. Mount in
+// Linux with `mount -t 9p -o trans=virtio,version=9p2000.L TAG /mnt`.
+//
+// Every path the guest names is resolved on the host and has to stay inside
+// the shared directory: ".." stops at the root and symlinks are followed only
+// as far as they point inside it.
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata, OpenOptions, Permissions},
+    io,
+    os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    queue::{BadChain, GuestMemory, Virtqueue},
+    VirtioDevice,
+};
+
+const DEVICE_ID: u32 = 9;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const VERSION: &str = "9P2000.L";
+const MAX_MESSAGE: u32 = 128 << 10; // msize we offer
+const MIN_MESSAGE: u32 = 4096; // smallest msize we accept, as Linux does
+const HEADER_SIZE: usize = 7; // size u32, type u8, tag u16
+
+// T-messages, the R-message is always one more
+const TLERROR: u8 = 6;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TRENAME: u8 = 20;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// Linux errno values, what a 9P2000.L client expects in Rlerror
+#[cfg(test)] // a missing host file reports its own errno
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const ENOTDIR: u32 = 20;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const EOPNOTSUPP: u32 = 95;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0;
+
+// open flags
+const O_ACCMODE: u32 = 3;
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const GETATTR_BASIC: u64 = 0x7FF;
+
+// setattr valid bits
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ShareMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+// what a fid points at, its path relative to the shared directory
+struct Fid {
+    path: PathBuf,
+    open: bool,
+    file: Option<File>,                 // open regular file
+    entries: Option<Vec<(String, u8)>>, // open directory: names and qid types
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            open: false,
+            file: None,
+            entries: None,
+        }
+    }
+}
+
+pub struct Virtio9p {
+    root: PathBuf, // canonical
+    tag: String,
+    mode: ShareMode,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+fn errno(err: io::Error) -> u32 {
+    err.raw_os_error().map_or(EIO, |errno| errno as u32)
+}
+
+fn qid_type(metadata: &Metadata) -> u8 {
+    match metadata.file_type() {
+        kind if kind.is_dir() => QTDIR,
+        kind if kind.is_symlink() => QTSYMLINK,
+        _ => QTFILE,
+    }
+}
+
+fn qid(metadata: &Metadata) -> Vec<u8> {
+    let mut qid = vec![qid_type(metadata)];
+    qid.extend(0u32.to_le_bytes()); // version, no caching in the guest
+    qid.extend(metadata.ino().to_le_bytes());
+    qid
+}
+
+// A single path component, the guest can not smuggle a path in a name
+fn component(name: &str) -> Result<&str, u32> {
+    match name {
+        "" | "." | ".." => Err(EINVAL),
+        _ if name.contains('/') || name.contains('\0') => Err(EINVAL),
+        _ => Ok(name),
+    }
+}
+
+// Fields of a T-message in order
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], u32> {
+        if self.bytes.len() < count {
+            return Err(EINVAL);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, u32> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, u32> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, u32> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, u32> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+fn put_string(reply: &mut Vec<u8>, string: &str) {
+    reply.extend((string.len() as u16).to_le_bytes());
+    reply.extend(string.as_bytes());
+}
+
+impl Virtio9p {
+    pub fn new(root: &str, tag: &str, mode: ShareMode) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        Ok(Self {
+            root,
+            tag: tag.to_string(),
+            mode,
+            msize: MAX_MESSAGE,
+            fids: HashMap::new(),
+        })
+    }
+
+    // The host path of `path`, which has to exist and resolve inside the
+    // shared directory
+    fn host(&self, path: &Path) -> Result<PathBuf, u32> {
+        let host = fs::canonicalize(self.root.join(path)).map_err(errno)?;
+        match host.starts_with(&self.root) {
+            true => Ok(host),
+            false => Err(EACCES),
+        }
+    }
+
+    // The host path of `name` in the directory `path`, not following it if
+    // it is a symlink, or checking it if the caller will
+    fn entry(&self, path: &Path, name: &str, follow: bool) -> Result<PathBuf, u32> {
+        let name = component(name)?;
+        let entry = self.host(path)?.join(name);
+        if follow && fs::symlink_metadata(&entry).is_ok() {
+            return self.host(&path.join(name));
+        }
+        Ok(entry)
+    }
+
+    fn writable(&self) -> Result<(), u32> {
+        match self.mode {
+            ShareMode::ReadWrite => Ok(()),
+            ShareMode::ReadOnly => Err(EROFS),
+        }
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn path(&self, fid: u32) -> Result<PathBuf, u32> {
+        self.fids.get(&fid).map(|fid| fid.path.clone()).ok_or(EBADF)
+    }
+
+    // Handles one T-message and returns the R-message
+    fn request(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut reader = Reader { bytes: message };
+        let (Ok(_), Ok(kind), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else {
+            return vec![];
+        };
+        let mut body = vec![];
+        let result = match kind {
+            TVERSION => self.version(&mut reader, &mut body),
+            TATTACH => self.attach(&mut reader, &mut body),
+            TWALK => self.walk(&mut reader, &mut body),
+            TGETATTR => self.getattr(&mut reader, &mut body),
+            TSETATTR => self.setattr(&mut reader),
+            TLOPEN => self.lopen(&mut reader, &mut body),
+            TLCREATE => self.lcreate(&mut reader, &mut body),
+            TREAD => self.read(&mut reader, &mut body),
+            TWRITE => self.write(&mut reader, &mut body),
+            TREADDIR => self.readdir(&mut reader, &mut body),
+            TSTATFS => self.statfs(&mut reader, &mut body),
+            TMKDIR => self.mkdir(&mut reader, &mut body),
+            TRENAME => self.rename(&mut reader),
+            TRENAMEAT => self.renameat(&mut reader),
+            TUNLINKAT => self.unlinkat(&mut reader),
+            TREMOVE => self.remove(&mut reader),
+            TFSYNC => self.fsync(&mut reader),
+            TCLUNK => reader.u32().map(|fid| {
+                self.fids.remove(&fid);
+            }),
+            // requests are answered as they come, there is nothing to flush
+            TFLUSH => Ok(()),
+            _ => Err(EOPNOTSUPP),
+        };
+        let (kind, body) = match result {
+            Ok(()) => (kind + 1, body),
+            Err(errno) => (TLERROR + 1, errno.to_le_bytes().to_vec()),
+        };
+        let mut reply = ((HEADER_SIZE + body.len()) as u32).to_le_bytes().to_vec();
+        reply.push(kind);
+        reply.extend(tag.to_le_bytes());
+        reply.extend(body);
+        reply
+    }
+
+    fn version(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let msize = reader.u32()?;
+        let version = reader.string()?;
+        if msize < MIN_MESSAGE {
+            return Err(EINVAL);
+        }
+        // a new session, every fid is gone
+        self.fids.clear();
+        self.msize = msize.min(MAX_MESSAGE);
+        reply.extend(self.msize.to_le_bytes());
+        put_string(
+            reply,
+            if version == VERSION {
+                VERSION
+            } else {
+                "unknown"
+            },
+        );
+        Ok(())
+    }
+
+    fn attach(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let _afid = reader.u32()?;
+        let _uname = reader.string()?;
+        let _aname = reader.string()?;
+        let metadata = fs::metadata(&self.root).map_err(errno)?;
+        self.fids.insert(fid, Fid::new(PathBuf::new()));
+        reply.extend(qid(&metadata));
+        Ok(())
+    }
+
+    fn walk(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let new_fid = reader.u32()?;
+        let count = reader.u16()?;
+        let names = (0..count)
+            .map(|_| reader.string())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut path = self.path(fid)?;
+        let mut qids = vec![];
+        for name in &names {
+            let mut next = path.clone();
+            match name.as_str() {
+                // the root is its own parent
+                ".." => {
+                    next.pop();
+                }
+                name => next.push(component(name)?),
+            }
+            let metadata = self
+                .host(&next)
+                .and_then(|host| fs::metadata(host).map_err(errno));
+            match metadata {
+                Ok(metadata) => qids.push(qid(&metadata)),
+                // the first name has to be found, later ones end the walk
+                Err(errno) if qids.is_empty() => return Err(errno),
+                Err(_) => break,
+            }
+            path = next;
+        }
+        if qids.len() == names.len() {
+            self.fids.insert(new_fid, Fid::new(path));
+        }
+        reply.extend((qids.len() as u16).to_le_bytes());
+        qids.iter().for_each(|qid| reply.extend(qid));
+        Ok(())
+    }
+
+    fn getattr(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let _mask = reader.u64()?;
+        let metadata = fs::metadata(self.host(&self.path(fid)?)?).map_err(errno)?;
+        reply.extend(GETATTR_BASIC.to_le_bytes());
+        reply.extend(qid(&metadata));
+        reply.extend(metadata.mode().to_le_bytes());
+        reply.extend(metadata.uid().to_le_bytes());
+        reply.extend(metadata.gid().to_le_bytes());
+        for value in [
+            metadata.nlink(),
+            metadata.rdev(),
+            metadata.size(),
+            metadata.blksize(),
+            metadata.blocks(),
+            metadata.atime() as u64,
+            metadata.atime_nsec() as u64,
+            metadata.mtime() as u64,
+            metadata.mtime_nsec() as u64,
+            metadata.ctime() as u64,
+            metadata.ctime_nsec() as u64,
+            0, // btime, gen and data_version are not kept
+            0,
+            0,
+            0,
+        ] {
+            reply.extend(value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    // Mode, size and mtime; ownership and the other times stay the host's
+    fn setattr(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let valid = reader.u32()?;
+        let mode = reader.u32()?;
+        let _uid = reader.u32()?;
+        let _gid = reader.u32()?;
+        let size = reader.u64()?;
+        let _atime = (reader.u64()?, reader.u64()?);
+        let mtime = Duration::new(reader.u64()?, reader.u64()? as u32);
+        self.writable()?;
+        let host = self.host(&self.path(fid)?)?;
+        if valid & SETATTR_MODE != 0 {
+            fs::set_permissions(&host, Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        }
+        if valid & (SETATTR_SIZE | SETATTR_MTIME) != 0 {
+            let file = OpenOptions::new().write(true).open(&host).map_err(errno)?;
+            if valid & SETATTR_SIZE != 0 {
+                file.set_len(size).map_err(errno)?;
+            }
+            if valid & SETATTR_MTIME != 0 {
+                let mtime = match valid & SETATTR_MTIME_SET {
+                    0 => SystemTime::now(),
+                    _ => UNIX_EPOCH + mtime,
+                };
+                file.set_modified(mtime).map_err(errno)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_options(&self, flags: u32) -> Result<OpenOptions, u32> {
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_RDONLY => options.read(true),
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => return Err(EINVAL),
+        };
+        if flags & (O_ACCMODE | O_TRUNC | O_APPEND) != O_RDONLY {
+            self.writable()?;
+        }
+        options.truncate(flags & O_TRUNC != 0);
+        options.append(flags & O_APPEND != 0);
+        Ok(options)
+    }
+
+    fn iounit(&self) -> u32 {
+        self.msize.saturating_sub((HEADER_SIZE + 4) as u32)
+    }
+
+    fn lopen(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let flags = reader.u32()?;
+        let host = self.host(&self.path(fid)?)?;
+        let metadata = fs::metadata(&host).map_err(errno)?;
+        let file = match metadata.is_dir() {
+            true => None,
+            false => Some(self.open_options(flags)?.open(&host).map_err(errno)?),
+        };
+        let fid = self.fid(fid)?;
+        if fid.open {
+            return Err(EBADF);
+        }
+        (fid.open, fid.file) = (true, file);
+        reply.extend(qid(&metadata));
+        reply.extend(self.iounit().to_le_bytes());
+        Ok(())
+    }
+
+    fn lcreate(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let name = reader.string()?;
+        let flags = reader.u32()?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+        self.writable()?;
+        let path = self.path(fid)?;
+        let host = self.entry(&path, &name, true)?;
+        let mut options = self.open_options(flags)?;
+        options.write(true).mode(mode & 0o7777);
+        match flags & O_EXCL {
+            0 => options.create(true),
+            _ => options.create_new(true),
+        };
+        let file = options.open(&host).map_err(errno)?;
+        let metadata = file.metadata().map_err(errno)?;
+        let fid = self.fid(fid)?;
+        *fid = Fid::new(path.join(&name));
+        (fid.open, fid.file) = (true, Some(file));
+        reply.extend(qid(&metadata));
+        reply.extend(self.iounit().to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?.min(self.iounit());
+        let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+        let mut data = vec![0; count as usize];
+        let length = file.read_at(&mut data, offset).map_err(errno)?;
+        reply.extend((length as u32).to_le_bytes());
+        reply.extend(&data[..length]);
+        Ok(())
+    }
+
+    fn write(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?;
+        let data = reader.take(count as usize)?;
+        let file = self.fids.get(&fid).and_then(|fid| fid.file.as_ref());
+        let length = file.ok_or(EBADF)?.write_at(data, offset).map_err(errno)?;
+        reply.extend((length as u32).to_le_bytes());
+        Ok(())
+    }
+
+    // Entries are numbered in the order of a listing taken at the first
+    // read, the offset of each is the number of the next
+    fn readdir(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?.min(self.iounit()) as usize;
+        let path = self.path(fid)?;
+        let host = self.host(&path)?;
+        let open = self.fid(fid)?;
+        if !open.open || open.file.is_some() {
+            return Err(ENOTDIR);
+        }
+        if open.entries.is_none() || offset == 0 {
+            let mut entries = vec![(".".to_string(), QTDIR), ("..".to_string(), QTDIR)];
+            let mut names = vec![];
+            for entry in fs::read_dir(&host).map_err(errno)? {
+                let entry = entry.map_err(errno)?;
+                let metadata = entry.metadata().map_err(errno)?;
+                names.push((
+                    entry.file_name().to_string_lossy().into_owned(),
+                    qid_type(&metadata),
+                ));
+            }
+            names.sort();
+            entries.extend(names);
+            self.fid(fid)?.entries = Some(entries);
+        }
+
+        let mut data = vec![];
+        let entries = self.fids[&fid].entries.as_ref().unwrap();
+        for (index, (name, kind)) in entries.iter().enumerate().skip(offset as usize) {
+            let metadata = match name.as_str() {
+                "." => fs::symlink_metadata(&host),
+                ".." => fs::symlink_metadata(self.host(path.parent().unwrap_or(&path))?),
+                _ => fs::symlink_metadata(host.join(name)),
+            };
+            // gone since the listing was taken
+            let Ok(metadata) = metadata else {
+                continue;
+            };
+            let mut entry = qid(&metadata);
+            entry.extend((index as u64 + 1).to_le_bytes());
+            entry.push(match *kind {
+                QTDIR => 4,      // DT_DIR
+                QTSYMLINK => 10, // DT_LNK
+                _ => 8,          // DT_REG
+            });
+            put_string(&mut entry, name);
+            if data.len() + entry.len() > count {
+                break;
+            }
+            data.extend(entry);
+        }
+        reply.extend((data.len() as u32).to_le_bytes());
+        reply.extend(data);
+        Ok(())
+    }
+
+    // Only the names are real, std has no statvfs
+    fn statfs(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        self.path(fid)?;
+        reply.extend(V9FS_MAGIC.to_le_bytes());
+        reply.extend(4096u32.to_le_bytes()); // bsize
+        reply.extend([0; 48]); // blocks, bfree, bavail, files, ffree, fsid
+        reply.extend(255u32.to_le_bytes()); // namelen
+        Ok(())
+    }
+
+    fn mkdir(&mut self, reader: &mut Reader, reply: &mut Vec<u8>) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let name = reader.string()?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+        self.writable()?;
+        let host = self.entry(&self.path(fid)?, &name, false)?;
+        fs::create_dir(&host).map_err(errno)?;
+        fs::set_permissions(&host, Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        reply.extend(qid(&fs::metadata(&host).map_err(errno)?));
+        Ok(())
+    }
+
+    fn rename(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let directory = reader.u32()?;
+        let name = reader.string()?;
+        self.writable()?;
+        let path = self.path(fid)?;
+        let (Some(parent), Some(old)) = (path.parent(), path.file_name()) else {
+            return Err(EACCES); // the root itself
+        };
+        let from = self.entry(parent, &old.to_string_lossy(), false)?;
+        let directory = self.path(directory)?;
+        fs::rename(from, self.entry(&directory, &name, false)?).map_err(errno)?;
+        self.fid(fid)?.path = directory.join(name);
+        Ok(())
+    }
+
+    fn renameat(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let old_directory = reader.u32()?;
+        let old_name = reader.string()?;
+        let new_directory = reader.u32()?;
+        let new_name = reader.string()?;
+        self.writable()?;
+        let from = self.entry(&self.path(old_directory)?, &old_name, false)?;
+        let to = self.entry(&self.path(new_directory)?, &new_name, false)?;
+        fs::rename(from, to).map_err(errno)
+    }
+
+    fn unlink(&self, host: &Path, directory: bool) -> Result<(), u32> {
+        match directory {
+            true => fs::remove_dir(host).map_err(errno),
+            false => fs::remove_file(host).map_err(errno),
+        }
+    }
+
+    fn unlinkat(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let directory = reader.u32()?;
+        let name = reader.string()?;
+        let flags = reader.u32()?;
+        self.writable()?;
+        let host = self.entry(&self.path(directory)?, &name, false)?;
+        self.unlink(&host, flags & AT_REMOVEDIR != 0)
+    }
+
+    // Removes the file and clunks the fid, even if the remove fails
+    fn remove(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let path = self.path(fid)?;
+        self.fids.remove(&fid);
+        self.writable()?;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(EACCES);
+        };
+        let host = self.entry(parent, &name.to_string_lossy(), false)?;
+        let metadata = fs::symlink_metadata(&host).map_err(errno)?;
+        self.unlink(&host, metadata.is_dir())
+    }
+
+    fn fsync(&mut self, reader: &mut Reader) -> Result<(), u32> {
+        let fid = reader.u32()?;
+        let _datasync = reader.u32()?;
+        match &self.fid(fid)?.file {
+            Some(file) => file.sync_all().map_err(errno),
+            None => Ok(()),
+        }
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    // tag_len, then the tag
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend(self.tag.as_bytes());
+        config
+    }
+
+    fn notify(
+        &mut self,
+        _queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(memory)? {
+            let message = chain.read_all(memory).ok_or(BadChain)?;
+            let reply = self.request(&message);
+            let written = chain.write_at(memory, 0, &reply).ok_or(BadChain)?;
+            queues[0]
+                .push(memory, chain, written as u32)
+                .ok_or(BadChain)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = MAX_MESSAGE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::fs::symlink, process};
+
+    use crate::vm::{
+        virtio::{
+            tests::{read_memory, vm_with_virtio, write_memory, Driver, BUFFERS},
+            VIRTIO_F_VERSION_1,
+        },
+        Vm,
+    };
+
+    use super::*;
+
+    fn string(string: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        put_string(&mut bytes, string);
+        bytes
+    }
+
+    // Sends a T-message through the queue, returns the R-message type and body
+    fn call(vm: &mut Vm, driver: &mut Driver, kind: u8, fields: &[&[u8]]) -> (u8, Vec<u8>) {
+        let body = fields.concat();
+        let mut message = ((HEADER_SIZE + body.len()) as u32).to_le_bytes().to_vec();
+        message.push(kind);
+        message.extend(1u16.to_le_bytes());
+        message.extend(body);
+        write_memory(vm, BUFFERS, &message);
+        driver.submit(
+            vm,
+            0,
+            &[
+                (BUFFERS, message.len() as u32, false),
+                (BUFFERS + 0x1000, 0x1000, true),
+            ],
+        );
+        let (_, length) = driver.used(vm, 0).unwrap();
+        let reply = read_memory(vm, BUFFERS + 0x1000, length as usize);
+        assert_eq!(reply[..4], length.to_le_bytes());
+        (reply[4], reply[HEADER_SIZE..].to_vec())
+    }
+
+    fn error(errno: u32) -> (u8, Vec<u8>) {
+        (TLERROR + 1, errno.to_le_bytes().to_vec())
+    }
+
+    // A shared directory with a file, a subdirectory and a symlink that
+    // points out of it
+    fn share(name: &str, mode: ShareMode) -> (Vm, Driver, PathBuf) {
+        let root = env::temp_dir().join(format!("riscv_vm-{}-{}", process::id(), name));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("hello.txt"), "hello from the host").unwrap();
+        symlink("/etc", root.join("escape")).unwrap();
+        let device = Virtio9p::new(root.to_str().unwrap(), "share", mode).unwrap();
+        let mut vm = vm_with_virtio(Box::new(device));
+        let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1 | VIRTIO_9P_MOUNT_TAG);
+
+        let (kind, body) = call(
+            &mut vm,
+            &mut driver,
+            TVERSION,
+            &[&8192u32.to_le_bytes(), &string(VERSION)],
+        );
+        assert_eq!(
+            (kind, body),
+            (
+                TVERSION + 1,
+                [&8192u32.to_le_bytes()[..], &string(VERSION)].concat()
+            )
+        );
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TATTACH,
+            &[
+                &0u32.to_le_bytes(),
+                &(!0u32).to_le_bytes(),
+                &string("root"),
+                &string(""),
+                &0u32.to_le_bytes(),
+            ],
+        );
+        assert_eq!(kind, TATTACH + 1);
+        (vm, driver, root)
+    }
+
+    fn walk(vm: &mut Vm, driver: &mut Driver, new_fid: u32, names: &[&str]) -> (u8, Vec<u8>) {
+        let mut fields = vec![0u32.to_le_bytes().to_vec(), new_fid.to_le_bytes().to_vec()];
+        fields.push((names.len() as u16).to_le_bytes().to_vec());
+        fields.extend(names.iter().map(|name| string(name)));
+        let fields: Vec<&[u8]> = fields.iter().map(Vec::as_slice).collect();
+        call(vm, driver, TWALK, &fields)
+    }
+
+    #[test]
+    fn test_read_only_share() {
+        let (mut vm, mut driver, root) = share("ro", ShareMode::ReadOnly);
+        assert_eq!(vm.load(2, 0x1000_1100), Ok(5)); // tag_len
+
+        // ".." stops at the root, symlinks out of the share are refused
+        let (kind, body) = walk(&mut vm, &mut driver, 1, &["sub", "..", "..", "hello.txt"]);
+        assert_eq!(
+            (kind, body[..2].to_vec()),
+            (TWALK + 1, 4u16.to_le_bytes().to_vec())
+        );
+        assert_eq!(walk(&mut vm, &mut driver, 2, &["escape"]), error(EACCES));
+        assert_eq!(walk(&mut vm, &mut driver, 2, &["sub/../.."]), error(EINVAL));
+        assert_eq!(walk(&mut vm, &mut driver, 2, &["missing"]), error(ENOENT));
+
+        let fid = 1u32.to_le_bytes();
+        assert_eq!(
+            call(&mut vm, &mut driver, TLOPEN, &[&fid, &O_RDWR.to_le_bytes()]),
+            error(EROFS)
+        );
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TLOPEN,
+            &[&fid, &O_RDONLY.to_le_bytes()],
+        );
+        assert_eq!(kind, TLOPEN + 1);
+        let (kind, body) = call(
+            &mut vm,
+            &mut driver,
+            TREAD,
+            &[&fid, &6u64.to_le_bytes(), &100u32.to_le_bytes()],
+        );
+        assert_eq!(kind, TREAD + 1);
+        assert_eq!(body[4..], *b"from the host");
+
+        // the root lists ".", "..", escape, hello.txt and sub
+        walk(&mut vm, &mut driver, 3, &[]);
+        let dir = 3u32.to_le_bytes();
+        call(
+            &mut vm,
+            &mut driver,
+            TLOPEN,
+            &[&dir, &O_RDONLY.to_le_bytes()],
+        );
+        let (kind, body) = call(
+            &mut vm,
+            &mut driver,
+            TREADDIR,
+            &[&dir, &3u64.to_le_bytes(), &0x800u32.to_le_bytes()],
+        );
+        assert_eq!(kind, TREADDIR + 1);
+        assert_eq!(body[4 + 13..4 + 13 + 8], 4u64.to_le_bytes());
+        assert_eq!(body[4 + 22..4 + 24 + 9], string("hello.txt")[..]);
+        assert_eq!(body[4 + 24 + 9 + 21], 4); // sub is a directory
+
+        let name = string("new");
+        assert_eq!(
+            call(
+                &mut vm,
+                &mut driver,
+                TMKDIR,
+                &[&dir, &name, &0o755u32.to_le_bytes(), &0u32.to_le_bytes()]
+            ),
+            error(EROFS)
+        );
+
+        // an msize with no room for a reply is refused, the session goes on
+        assert_eq!(
+            call(
+                &mut vm,
+                &mut driver,
+                TVERSION,
+                &[&8u32.to_le_bytes(), &string(VERSION)]
+            ),
+            error(EINVAL)
+        );
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TREAD,
+            &[&fid, &0u64.to_le_bytes(), &5u32.to_le_bytes()],
+        );
+        assert_eq!(kind, TREAD + 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_share() {
+        let (mut vm, mut driver, root) = share("rw", ShareMode::ReadWrite);
+        walk(&mut vm, &mut driver, 1, &["sub"]);
+        let fid = 1u32.to_le_bytes();
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TLCREATE,
+            &[
+                &fid,
+                &string("out.txt"),
+                &O_RDWR.to_le_bytes(),
+                &0o644u32.to_le_bytes(),
+                &0u32.to_le_bytes(),
+            ],
+        );
+        assert_eq!(kind, TLCREATE + 1);
+        let data = b"results";
+        let (kind, body) = call(
+            &mut vm,
+            &mut driver,
+            TWRITE,
+            &[
+                &fid,
+                &0u64.to_le_bytes(),
+                &(data.len() as u32).to_le_bytes(),
+                data,
+            ],
+        );
+        assert_eq!((kind, body), (TWRITE + 1, 7u32.to_le_bytes().to_vec()));
+        assert_eq!(fs::read(root.join("sub/out.txt")).unwrap(), data);
+
+        // names are single components, and creating through the escaping
+        // symlink is refused
+        walk(&mut vm, &mut driver, 2, &[]);
+        let mode = [0o755u32.to_le_bytes(), 0u32.to_le_bytes()].concat();
+        let dir = 2u32.to_le_bytes();
+        let name = string("../outside");
+        assert_eq!(
+            call(&mut vm, &mut driver, TMKDIR, &[&dir, &name, &mode]),
+            error(EINVAL)
+        );
+        walk(&mut vm, &mut driver, 3, &[]);
+        let flags = [
+            O_RDWR.to_le_bytes(),
+            0o644u32.to_le_bytes(),
+            0u32.to_le_bytes(),
+        ]
+        .concat();
+        let name = string("escape");
+        assert_eq!(
+            call(
+                &mut vm,
+                &mut driver,
+                TLCREATE,
+                &[&3u32.to_le_bytes(), &name, &flags]
+            ),
+            error(EACCES)
+        );
+
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TRENAMEAT,
+            &[
+                &2u32.to_le_bytes(),
+                &string("hello.txt"),
+                &2u32.to_le_bytes(),
+                &string("moved.txt"),
+            ],
+        );
+        assert_eq!(kind, TRENAMEAT + 1);
+        assert!(root.join("moved.txt").exists());
+        let (kind, _) = call(
+            &mut vm,
+            &mut driver,
+            TUNLINKAT,
+            &[
+                &2u32.to_le_bytes(),
+                &string("moved.txt"),
+                &0u32.to_le_bytes(),
+            ],
+        );
+        assert_eq!(kind, TUNLINKAT + 1);
+        assert!(!root.join("moved.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
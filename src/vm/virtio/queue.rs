@@ -0,0 +1,210 @@
+// Split virtqueues: the driver lists buffers in the descriptor table and
+// offers chains of them in the available ring, the device hands them back
+// through the used ring.
+use super::super::bus::Bus;
+
+pub const QUEUE_SIZE: u16 = 256; // QueueNumMax of every queue
+
+const DESC_SIZE: u64 = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+
+// A chain the device can not follow, the queue is broken until reset
+#[derive(Debug, PartialEq)]
+pub struct BadChain;
+
+// Guest RAM as the devices see it, DMA never reaches ROM or MMIO
+pub struct GuestMemory<'a> {
+    bus: &'a mut Bus,
+}
+
+impl<'a> GuestMemory<'a> {
+    pub(crate) fn new(bus: &'a mut Bus) -> Self {
+        Self { bus }
+    }
+
+    pub fn read(&self, address: u64, length: usize) -> Option<Vec<u8>> {
+        if !self.bus.is_ram(address, length) {
+            return None;
+        }
+        self.bus.read(address, length).map(|bytes| bytes.to_vec())
+    }
+
+    pub fn write(&mut self, address: u64, bytes: &[u8]) -> Option<()> {
+        if !self.bus.is_ram(address, bytes.len()) {
+            return None;
+        }
+        self.bus.write(address, bytes)
+    }
+
+    fn read_u16(&self, address: u64) -> Option<u16> {
+        let bytes = self.read(address, 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&self, address: u64) -> Option<u32> {
+        let bytes = self.read(address, 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn read_u64(&self, address: u64) -> Option<u64> {
+        let bytes = self.read(address, 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+// One guest buffer of a chain
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Buffer {
+    address: u64,
+    length: u32,
+}
+
+// A descriptor chain taken from the available ring: the buffers the device
+// reads from, then the ones it writes to
+#[derive(Debug, PartialEq)]
+pub struct Chain {
+    head: u16,
+    readable: Vec<Buffer>,
+    writable: Vec<Buffer>,
+}
+
+impl Chain {
+    pub fn readable_len(&self) -> usize {
+        self.readable
+            .iter()
+            .map(|buffer| buffer.length as usize)
+            .sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable
+            .iter()
+            .map(|buffer| buffer.length as usize)
+            .sum()
+    }
+
+    // Everything the driver gave the device to read
+    pub fn read_all(&self, memory: &GuestMemory) -> Option<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.readable_len());
+        for buffer in &self.readable {
+            bytes.extend(memory.read(buffer.address, buffer.length as usize)?);
+        }
+        Some(bytes)
+    }
+
+    // Fills the writable buffers from `offset` on, as far as they reach
+    pub fn write_at(&self, memory: &mut GuestMemory, offset: usize, bytes: &[u8]) -> Option<usize> {
+        let mut skip = offset;
+        let mut written = 0;
+        for buffer in &self.writable {
+            let length = buffer.length as usize;
+            if skip >= length {
+                skip -= length;
+                continue;
+            }
+            let count = (length - skip).min(bytes.len() - written);
+            memory.write(
+                buffer.address + skip as u64,
+                &bytes[written..written + count],
+            )?;
+            written += count;
+            skip = 0;
+            if written == bytes.len() {
+                break;
+            }
+        }
+        Some(written)
+    }
+}
+
+#[derive(Default)]
+pub struct Virtqueue {
+    pub(crate) size: u16,
+    pub(crate) ready: bool,
+    pub(crate) desc: u64,
+    pub(crate) avail: u64,
+    pub(crate) used: u64,
+    last_avail: u16, // next available ring entry to take
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    // Next chain the driver offered, None when the ring is empty or the queue
+    // is not set up. A malformed chain is an error, the device stops using
+    // the queue.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<Chain>, BadChain> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        let avail_idx = memory.read_u16(self.avail + 2).ok_or(BadChain)?;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        let slot = self.avail + 4 + (self.last_avail % self.size) as u64 * 2;
+        let head = memory.read_u16(slot).ok_or(BadChain)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        self.chain(memory, head).map(Some).ok_or(BadChain)
+    }
+
+    fn chain(&self, memory: &GuestMemory, head: u16) -> Option<Chain> {
+        let mut chain = Chain {
+            head,
+            readable: vec![],
+            writable: vec![],
+        };
+        let (mut table, mut table_size) = (self.desc, self.size as u32);
+        let mut index = head as u32;
+        let mut indirect = false;
+        // a loop in the chain can not be longer than the table
+        for _ in 0..=QUEUE_SIZE as usize * 2 {
+            if index >= table_size {
+                return None;
+            }
+            let desc = table + index as u64 * DESC_SIZE;
+            let address = memory.read_u64(desc)?;
+            let length = memory.read_u32(desc + 8)?;
+            let flags = memory.read_u16(desc + 12)?;
+            let next = memory.read_u16(desc + 14)?;
+
+            if flags & DESC_F_INDIRECT != 0 {
+                // one level only, replacing the rest of the chain
+                if indirect || !(length as u64).is_multiple_of(DESC_SIZE) {
+                    return None;
+                }
+                indirect = true;
+                (table, table_size) = (address, length / DESC_SIZE as u32);
+                index = 0;
+                continue;
+            }
+            let buffer = Buffer { address, length };
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buffer);
+            } else {
+                return None; // readable after writable
+            }
+            if flags & DESC_F_NEXT == 0 {
+                return Some(chain);
+            }
+            index = next as u32;
+        }
+        None
+    }
+
+    // Hands a chain back with the number of bytes written into it
+    pub fn push(&mut self, memory: &mut GuestMemory, chain: Chain, written: u32) -> Option<()> {
+        let slot = self.used + 4 + (self.used_idx % self.size) as u64 * 8;
+        let mut entry = (chain.head as u32).to_le_bytes().to_vec();
+        entry.extend(written.to_le_bytes());
+        memory.write(slot, &entry)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        memory.write(self.used + 2, &self.used_idx.to_le_bytes())
+    }
+}
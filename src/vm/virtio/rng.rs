@@ -0,0 +1,115 @@
+// virtio-rng, entropy for the guest from the host or from a seeded
+// generator that makes every run see the same bytes.
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use super::{
+    queue::{BadChain, GuestMemory, Virtqueue},
+    VirtioDevice,
+};
+
+const DEVICE_ID: u32 = 4;
+
+// bytes handed out per request at most
+const REQUEST_LIMIT: usize = 4096;
+
+enum Source {
+    Seeded(u64), // splitmix64 state
+    Host(File),
+}
+
+pub struct VirtioRng {
+    source: Source,
+}
+
+impl VirtioRng {
+    // The same seed gives the same bytes on every run
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            source: Source::Seeded(seed),
+        }
+    }
+
+    pub fn host() -> io::Result<Self> {
+        Ok(Self {
+            source: Source::Host(File::open("/dev/urandom")?),
+        })
+    }
+
+    fn fill(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        match &mut self.source {
+            Source::Seeded(state) => {
+                for chunk in bytes.chunks_mut(8) {
+                    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = *state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+                Ok(())
+            }
+            Source::Host(file) => file.read_exact(bytes),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn notify(
+        &mut self,
+        _queue: usize,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<bool, BadChain> {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(memory)? {
+            let mut bytes = vec![0; chain.writable_len().min(REQUEST_LIMIT)];
+            // a failing host source hands back nothing rather than zeroes
+            let written = match self.fill(&mut bytes) {
+                Ok(()) => chain.write_at(memory, 0, &bytes).ok_or(BadChain)?,
+                Err(_) => 0,
+            };
+            queues[0]
+                .push(memory, chain, written as u32)
+                .ok_or(BadChain)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::virtio::{
+        tests::{read_memory, vm_with_virtio, Driver, BUFFERS},
+        VIRTIO_F_VERSION_1,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_seeded_entropy() {
+        let mut runs = vec![];
+        for seed in [1, 1, 2] {
+            let mut vm = vm_with_virtio(Box::new(VirtioRng::seeded(seed)));
+            assert_eq!(vm.load(4, 0x1000_1008), Ok(DEVICE_ID));
+            let mut driver = Driver::new(&mut vm, VIRTIO_F_VERSION_1);
+            driver.submit(&mut vm, 0, &[(BUFFERS, 12, true), (BUFFERS + 12, 20, true)]);
+            assert_eq!(driver.used(&mut vm, 0), Some((0, 32)));
+            runs.push(read_memory(&vm, BUFFERS, 32));
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert_ne!(runs[0], runs[2]);
+        assert_ne!(runs[0], [0; 32]);
+    }
+}